@@ -0,0 +1,552 @@
+//! Grammar compiler used by zfuzz. A json grammar is converted into a table
+//! of fragments and optimized, after which it can either be emitted as a Rust
+//! module (see `src/main.rs`) or be interpreted by zfuzz at runtime.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+
+/// Representation of a grammar file in a Rust structure. This allows us to
+/// use Serde to serialize and deserialize the json grammar files
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Grammar(pub BTreeMap<String, Vec<Variant>>);
+
+/// One of the alternatives a non-terminal can expand to
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Variant {
+    /// Symbols that are expanded in order, picked with a weight of 1
+    Plain(Vec<Symbol>),
+
+    /// Symbols that are expanded in order, picked with a probability
+    /// proportional to `weight`
+    Weighted {
+        weight:    usize,
+        expansion: Vec<Symbol>,
+    },
+}
+
+/// A single symbol of a variant
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum Symbol {
+    /// Name of a non-terminal, or the literal bytes of a terminal if no
+    /// non-terminal of that name exists
+    Name(String),
+
+    /// Terminal that may contain `\xNN` escapes, for bytes that can't be
+    /// represented in a json string
+    Bytes {
+        bytes: String,
+    },
+
+    /// Terminal made up of a single byte out of a set of characters and
+    /// ranges, eg. `a-zA-Z_`. `\xNN` escapes are supported, and `\-` is a
+    /// literal dash
+    Class {
+        class: String,
+    },
+
+    /// `repeat` expanded between `min` and `max` times
+    Repeat {
+        repeat: Box<Symbol>,
+        #[serde(default)]
+        min:    usize,
+        max:    usize,
+    },
+}
+
+/// A strongly typed wrapper around a `usize` which selects different fragment
+/// identifiers
+#[derive(Clone, Copy, Debug)]
+pub struct FragmentId(pub usize);
+
+/// A fragment which is specified by the grammar file
+#[derive(Clone, Debug)]
+pub enum Fragment {
+    /// A non-terminal fragment which refers to a list of `FragmentId`s to
+    /// randomly select from for expansion, each with the weight it is
+    /// selected with
+    NonTerminal(Vec<(FragmentId, usize)>),
+
+    /// A fragment that is expanded between `min` and `max` times in a row
+    Repeat(FragmentId, usize, usize),
+
+    /// A list of `FragmentId`s that should be expanded in order
+    Expression(Vec<FragmentId>),
+
+    /// A terminal fragment which simply should expand directly to the
+    /// contained vector of bytes
+    Terminal(Vec<u8>),
+
+    /// A fragment which does nothing. This is used during optimization passes
+    /// to remove fragments with no effect.
+    Nop,
+}
+
+/// A grammar representation in Rust that is designed to be easy to work with
+/// in-memory and optimized for code generation.
+#[derive(Debug, Default)]
+pub struct GrammarRust {
+    /// All types
+    pub fragments: Vec<Fragment>,
+
+    /// Cached fragment identifier for the start node
+    pub start: Option<FragmentId>,
+
+    /// Mapping of non-terminal names to fragment identifers
+    pub name_to_fragment: BTreeMap<String, FragmentId>,
+}
+
+impl GrammarRust {
+    /// Create a new Rust version of a `Grammar` which was loaded via a
+    /// grammar json specification.
+    pub fn new(grammar: &Grammar) -> Result<Self, String> {
+        // Create a new grammar structure
+        let mut ret = GrammarRust::default();
+
+        // Parse the input grammar to resolve all fragment names
+        for (non_term, _) in grammar.0.iter() {
+            // Make sure that there aren't duplicates of fragment names
+            assert!(!ret.name_to_fragment.contains_key(non_term),
+                "Duplicate non-terminal definition, fail");
+
+            // Create a new, empty fragment
+            let fragment_id = ret.allocate_fragment(
+                Fragment::NonTerminal(Vec::new()));
+
+            // Add the name resolution for the fragment
+            ret.name_to_fragment.insert(non_term.clone(), fragment_id);
+        }
+
+        // Parse the input grammar
+        for (non_term, fragments) in grammar.0.iter() {
+            // Get the non-terminal fragment identifier
+            let fragment_id = ret.name_to_fragment[non_term];
+
+            // Create a vector to hold all of the variants possible under this
+            // non-terminal fragment
+            let mut variants = Vec::new();
+
+            // Go through all sub-fragments
+            for js_sub_fragment in fragments {
+                let (weight, symbols) = match js_sub_fragment {
+                    Variant::Plain(symbols) => (1, symbols),
+                    Variant::Weighted { weight, expansion } =>
+                        (*weight, expansion),
+                };
+
+                // Different options for this sub-fragment
+                let mut options = Vec::new();
+
+                // Go through each option in the sub-fragment
+                for option in symbols {
+                    options.push(ret.allocate_symbol(option)
+                        .map_err(|e| format!("{}: {}", non_term, e))?);
+                }
+
+                // Create a new fragment of all the options
+                variants.push((
+                    ret.allocate_fragment(Fragment::Expression(options)),
+                    weight));
+            }
+
+            // Get access to the fragment we want to update based on the
+            // possible variants
+            let fragment = &mut ret.fragments[fragment_id.0];
+
+            // Overwrite the terminal definition
+            *fragment = Fragment::NonTerminal(variants);
+        }
+
+        // Resolve the start node
+        let start = *ret.name_to_fragment.get("<start>")
+            .ok_or("The grammar does not define a <start> rule")?;
+        if ret.min_depths()[start.0] == usize::MAX {
+            return Err("The <start> rule can never terminate".to_string());
+        }
+        ret.start = Some(start);
+
+        Ok(ret)
+    }
+
+    /// Allocate the fragment a single symbol of a variant resolves to
+    fn allocate_symbol(&mut self, symbol: &Symbol)
+            -> Result<FragmentId, String> {
+        Ok(match symbol {
+            Symbol::Name(name) => {
+                if let Some(&non_terminal) = self.name_to_fragment.get(name) {
+                    // If we can resolve the name of this fragment, it is a
+                    // non-terminal fragment and should be allocated as such
+                    self.allocate_fragment(
+                        Fragment::NonTerminal(vec![(non_terminal, 1)]))
+                } else {
+                    // Convert the terminal bytes into a vector and create a
+                    // new fragment containing it
+                    self.allocate_fragment(
+                        Fragment::Terminal(name.as_bytes().to_vec()))
+                }
+            }
+            Symbol::Bytes { bytes } => {
+                let bytes = unescape(bytes)?.into_iter()
+                    .flat_map(|(bytes, _)| bytes)
+                    .collect();
+                self.allocate_fragment(Fragment::Terminal(bytes))
+            }
+            Symbol::Class { class } => {
+                // Every byte of the class is its own terminal, so the byte
+                // that was picked is recorded in the derivation tree
+                let options = parse_class(class)?.into_iter()
+                    .map(|byte| (self.allocate_fragment(
+                        Fragment::Terminal(vec![byte])), 1))
+                    .collect();
+                self.allocate_fragment(Fragment::NonTerminal(options))
+            }
+            Symbol::Repeat { repeat, min, max } => {
+                if min > max {
+                    return Err(format!("Invalid repetition {}..{}",
+                        min, max));
+                }
+                let fragment_id = self.allocate_symbol(repeat)?;
+                self.allocate_fragment(
+                    Fragment::Repeat(fragment_id, *min, *max))
+            }
+        })
+    }
+
+    /// Allocate a new fragment identifier and add it to the fragment list
+    pub fn allocate_fragment(&mut self, fragment: Fragment) -> FragmentId {
+        // Get a unique fragment identifier
+        let fragment_id = FragmentId(self.fragments.len());
+
+        // Store the fragment
+        self.fragments.push(fragment);
+
+        fragment_id
+    }
+
+    /// Optimize to remove fragments with non-random effects
+    pub fn optimize(&mut self) {
+        // Keeps track of fragment identifiers which resolve to nops
+        let mut nop_fragments = BTreeSet::new();
+
+        // Track if a optimization had an effect
+        let mut changed = true;
+        while changed {
+            // Start off assuming no effect from optimzation
+            changed = false;
+
+            // Go through each fragment, looking for potential optimizations
+            for idx in 0..self.fragments.len() {
+                // Clone the fragment such that we can inspect it, but we also
+                // can mutate it in place.
+                match self.fragments[idx].clone() {
+                    Fragment::NonTerminal(options) => {
+                        // If this non-terminal only has one option, replace
+                        // itself with the only option it resolves to
+                        if options.len() == 1 {
+                            self.fragments[idx] =
+                                self.fragments[options[0].0.0].clone();
+                            changed = true;
+                        }
+                    }
+                    Fragment::Expression(expr) => {
+                        // If this expression doesn't have anything to do at
+                        // all. Then simply replace it with a `Nop`
+                        if expr.len() == 0 {
+                            self.fragments[idx] = Fragment::Nop;
+                            changed = true;
+
+                            // Track that this fragment identifier now resolves
+                            // to a nop
+                            nop_fragments.insert(idx);
+                        }
+
+                        // If this expression only does one thing, then replace
+                        // the expression with the thing that it does.
+                        if expr.len() == 1 {
+                            self.fragments[idx] =
+                                self.fragments[expr[0].0].clone();
+                            changed = true;
+                        }
+
+                        // Remove all `Nop`s from this expression, as they
+                        // wouldn't result in anything occuring.
+                        if let Fragment::Expression(exprs) =
+                                &mut self.fragments[idx] {
+                            // Only retain fragments which are not nops
+                            exprs.retain(|x| {
+                                if nop_fragments.contains(&x.0) {
+                                    // Fragment was a nop, remove it
+                                    changed = true;
+                                    false
+                                } else {
+                                    // Fragment was fine, keep it
+                                    true
+                                }
+                            });
+                        }
+                    }
+                    Fragment::Repeat(..) | Fragment::Terminal(_) |
+                            Fragment::Nop => {
+                        // Already maximally optimized
+                    }
+                }
+            }
+        }
+    }
+
+    /// Compute the minimum depth of every fragment, ie. the number of levels
+    /// its derivation tree takes up if every non-terminal picks the option
+    /// that terminates the quickest. Fragments that can never terminate have
+    /// a depth of `usize::MAX`
+    pub fn min_depths(&self) -> Vec<usize> {
+        let mut depths = vec![usize::MAX; self.fragments.len()];
+
+        // Iterate until the depths converge
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for (idx, fragment) in self.fragments.iter().enumerate() {
+                let depth = match fragment {
+                    Fragment::NonTerminal(options) => options.iter()
+                        .map(|(option, _)| depths[option.0])
+                        .min()
+                        .unwrap_or(usize::MAX),
+                    Fragment::Expression(expr) => expr.iter()
+                        .map(|exp| depths[exp.0])
+                        .max()
+                        .unwrap_or(0),
+                    Fragment::Repeat(_, 0, _) => 0,
+                    Fragment::Repeat(repeat, _, _) => depths[repeat.0],
+                    Fragment::Terminal(_) | Fragment::Nop => 0,
+                }.saturating_add(1);
+
+                if depth < depths[idx] {
+                    depths[idx] = depth;
+                    changed = true;
+                }
+            }
+        }
+        depths
+    }
+
+    /// Generate a new Rust module implementing the grammar as a generator of
+    /// derivation trees. Every fragment routine records itself as a node of
+    /// the tree, and the bytes of the input are only produced when the tree
+    /// is unparsed. This lets zfuzz store the trees of interesting inputs in
+    /// its corpus and mutate them on the grammar level.
+    pub fn program<P: AsRef<Path>>(&self, path: P, max_depth: usize) {
+        let mut program = String::new();
+
+        // Construct the base of the module
+        program += &format!(r#"
+#![allow(unused)]
+use rand::Rng;
+use rand_xoshiro::rand_core::RngCore;
+use rand_xoshiro::Xoroshiro64Star;
+use rand_xoshiro::rand_core::SeedableRng;
+
+use crate::grammar_tree::{{
+    Alternative, DerivationTree, Node, TreeGrammar, repeat_count, select_alternative,
+}};
+
+const MAX_DEPTH: usize = {};
+
+pub struct GrammarMut {{
+    /// Fast Rng
+    rng: Xoroshiro64Star,
+}}
+
+impl Default for GrammarMut {{
+    fn default() -> Self {{
+        Self::new()
+    }}
+}}
+
+impl GrammarMut {{
+    pub fn new() -> Self {{
+        let mut rng = rand::thread_rng();
+        Self {{
+            rng: Xoroshiro64Star::seed_from_u64(rng.gen()),
+        }}
+    }}
+
+    pub fn generate_input(&mut self) -> Vec<u8> {{
+        DerivationTree::generate(self).unparse(self)
+    }}
+"#, max_depth);
+
+        let min_depths = self.min_depths();
+
+        // Go through each fragment in the list of fragments
+        for (id, fragment) in self.fragments.iter().enumerate() {
+            // Create a new function for this fragment
+            program += &format!("    fn fragment_{}(&mut self, depth: usize, \
+                                 nodes: &mut Vec<Node>) {{\n", id);
+
+            // Record this fragment as a node of the tree
+            program += "        let node = nodes.len();\n";
+            program += &format!("        nodes.push(Node::new({}, depth));\n",
+                id);
+
+            match fragment {
+                Fragment::NonTerminal(options) => {
+                    // For non-terminal cases pick a random variant to select
+                    // and invoke that fragment's routine. Near the maximum
+                    // depth only variants that terminate in time are picked
+                    program += &format!("        match select_alternative(self.rand(), \
+                        &ALTERNATIVES_{}, depth, MAX_DEPTH) {{\n", id);
+
+                    for (option_id, (option, _)) in options.iter().enumerate() {
+                        program += &format!("            {} => self.fragment_{}(depth + 1, nodes),\n", option_id, option.0);
+                    }
+                    program += &format!("            _ => unreachable!(),\n");
+
+                    program += &format!("        }}\n");
+                }
+                Fragment::Expression(expr) => {
+                    // Invoke all of the expression's routines in order
+                    for &exp in expr.iter() {
+                        program += &format!("        self.fragment_{}(depth + 1, nodes);\n", exp.0);
+                    }
+                }
+                Fragment::Repeat(repeat, min, max) => {
+                    // Invoke the repeated routine a random number of times
+                    program += &format!("        for _ in 0..repeat_count(self.rand(), {}, {}, {}, \
+                        depth, MAX_DEPTH) {{\n", min, max, min_depths[repeat.0]);
+                    program += &format!("            self.fragment_{}(depth + 1, nodes);\n",
+                        repeat.0);
+                    program += "        }\n";
+                }
+                Fragment::Terminal(_) | Fragment::Nop => {
+                    // Terminals are looked up in `TERMINALS` once the tree
+                    // is unparsed
+                }
+            }
+
+            program += "        nodes[node].size = nodes.len() - node;\n";
+            program += "    }\n";
+        }
+        program += "}\n";
+
+        // Dispatch from fragment identifiers to their routines, used to
+        // regenerate subtrees during mutations
+        program += &format!(r#"
+impl TreeGrammar for GrammarMut {{
+    fn start(&self) -> usize {{
+        {}
+    }}
+
+    fn expand(&mut self, fragment: usize, depth: usize, nodes: &mut Vec<Node>) {{
+        match fragment {{
+"#, self.start.unwrap().0);
+        for id in 0..self.fragments.len() {
+            program += &format!("            {} => self.fragment_{}(depth, nodes),\n", id, id);
+        }
+        program += r#"            _ => unreachable!(),
+        }
+    }
+
+    fn terminal(&self, fragment: usize) -> &[u8] {
+        TERMINALS[fragment]
+    }
+
+    fn rand(&mut self) -> usize {
+        self.rng.next_u64() as usize
+    }
+}
+"#;
+
+        // Bytes emitted by every fragment
+        program += &format!("\nstatic TERMINALS: [&[u8]; {}] = [\n",
+            self.fragments.len());
+        for fragment in self.fragments.iter() {
+            match fragment {
+                Fragment::Terminal(value) => {
+                    program += &format!("    &{:?},\n", value);
+                }
+                _ => program += "    &[],\n",
+            }
+        }
+        program += "];\n";
+
+        // Weights and minimum depths of the variants of every non-terminal
+        for (id, fragment) in self.fragments.iter().enumerate() {
+            if let Fragment::NonTerminal(options) = fragment {
+                program += &format!("\nstatic ALTERNATIVES_{}: [Alternative; {}] = [\n",
+                    id, options.len());
+                for (option, weight) in options.iter() {
+                    program += &format!("    Alternative {{ fragment: {}, weight: {}, \
+                        min_depth: {} }},\n", option.0, weight, min_depths[option.0]);
+                }
+                program += "];\n";
+            }
+        }
+
+        // Write out the module
+        std::fs::write(path, program)
+            .expect("Failed to create output Rust application");
+    }
+}
+
+/// Resolve the escapes of a string. Every character is returned as its bytes,
+/// alongside a flag that is set if the character was escaped
+fn unescape(string: &str) -> Result<Vec<(Vec<u8>, bool)>, String> {
+    let mut ret = Vec::new();
+    let mut chars = string.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push((c.to_string().into_bytes(), false));
+            continue;
+        }
+
+        match chars.next() {
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("Invalid escape \\x{} in {}", hex, string));
+                }
+                ret.push((vec![u8::from_str_radix(&hex, 16).unwrap()], true));
+            }
+            Some(c) => ret.push((c.to_string().into_bytes(), true)),
+            None => return Err(format!("Trailing backslash in {}", string)),
+        }
+    }
+    Ok(ret)
+}
+
+/// Parse a character class into the sorted list of bytes it contains
+fn parse_class(class: &str) -> Result<Vec<u8>, String> {
+    let chars = unescape(class)?;
+    let byte = |(c, _): &(Vec<u8>, bool)| match c[..] {
+        [byte] => Ok(byte),
+        _ => Err(format!("Non-ascii character in class {}", class)),
+    };
+
+    let mut bytes = BTreeSet::new();
+    let mut idx = 0;
+    while idx < chars.len() {
+        let start = byte(&chars[idx])?;
+
+        // An unescaped dash between two characters denotes a range
+        if idx + 2 < chars.len() && chars[idx + 1] == (b"-".to_vec(), false) {
+            let end = byte(&chars[idx + 2])?;
+            if start > end {
+                return Err(format!("Invalid range in class {}", class));
+            }
+            bytes.extend(start..=end);
+            idx += 3;
+        } else {
+            bytes.insert(start);
+            idx += 1;
+        }
+    }
+
+    if bytes.is_empty() {
+        return Err(format!("Empty class {}", class));
+    }
+    Ok(bytes.into_iter().collect())
+}
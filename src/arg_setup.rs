@@ -1,8 +1,11 @@
 use crate::{
     error_exit,
-    targets::targets::TARGETS,
+    targets::{
+        targets::{ACTIVE_TARGETS, register_targets},
+        harness_config::load_harness_config,
+    },
 };
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::sync::OnceLock;
 
 /// Address at which the fuzzer attempts to create a snapshot once reached
@@ -14,12 +17,24 @@ pub static NUM_THREADS: OnceLock<usize> = OnceLock::new();
 /// File that contains the user-supplied dictionary
 pub static DICT_FILE: OnceLock<Option<String>> = OnceLock::new();
 
+/// Highest `@level` of dictionary entries that are loaded from the user-supplied dictionary
+pub static DICT_LEVEL: OnceLock<u32> = OnceLock::new();
+
+/// File that describes the structure of the fuzz-inputs
+pub static FORMAT_FILE: OnceLock<Option<String>> = OnceLock::new();
+
+/// Grammar file that is interpreted to generate fuzz-inputs
+pub static GRAMMAR_FILE: OnceLock<Option<String>> = OnceLock::new();
+
 /// Path to directory to which fuzzer-outputs are saved
 pub static OUTPUT_DIR: OnceLock<String> = OnceLock::new();
 
 /// Path to directory from which fuzzer inputs are taken
 pub static INPUT_DIR: OnceLock<String> = OnceLock::new();
 
+/// Resume a previous fuzzing campaign from the state saved in the output directory
+pub static RESUME: OnceLock<bool> = OnceLock::new();
+
 /// Additional information is printed out, alongside rolling statistics. Some parts of this only
 /// work while running single-threaded
 pub static DEBUG_PRINT: OnceLock<bool> = OnceLock::new();
@@ -29,20 +44,49 @@ pub static DEBUG_PRINT: OnceLock<bool> = OnceLock::new();
 #[clap(author = "seal9055", version, about = "tmp")]
 #[clap(override_usage = "zfuzz [OPTION] -- /path/to/fuzzed_app [ ... ] (use `@@` to specify \
     position of fuzz-input in target-argv)\n\n    ex: zfuzz -- ./test_cases/test @@")]
+#[clap(subcommand_negates_reqs = true)]
 pub struct Cli {
-    #[clap(short, value_name = "DIR", forbid_empty_values = true, display_order = 1)]
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    #[clap(short, value_name = "DIR", forbid_empty_values = true, display_order = 1,
+           required = true)]
     /// - Input directory that should contain the initial seed files
-    pub input_dir: String,
+    pub input_dir: Option<String>,
 
-    #[clap(short, value_name = "DIR", forbid_empty_values = true, display_order = 2)]
+    #[clap(short, value_name = "DIR", forbid_empty_values = true, display_order = 2,
+           required = true)]
     /// - Output directory that will be used to eg. save crashes
-    pub output_dir: String,
+    pub output_dir: Option<String>,
 
-    #[clap(short = 'd', value_name = "DICT", help_heading = "CONFIG", forbid_empty_values = true)]
-    /// - Optionally supply a new-line separated list of inputs that will be mutated into the 
-    /// fuzz-inputs
+    #[clap(short = 'd', value_name = "DICT[@LEVEL]", help_heading = "CONFIG",
+           forbid_empty_values = true)]
+    /// - Optionally supply an AFL/libFuzzer-style dictionary whose tokens will be mutated into the
+    /// fuzz-inputs. Only entries up to `@LEVEL` (default 0) are loaded
     pub dictionary: Option<String>,
 
+    #[clap(short = 'f', value_name = "FORMAT", help_heading = "CONFIG", forbid_empty_values = true)]
+    /// - Optionally supply a json description of the input format. Mutations are mostly applied to
+    /// individual fields, and length fields and checksums are fixed up before every fuzz-case
+    pub input_format: Option<String>,
+
+    #[clap(short = 'g', value_name = "GRAMMAR", help_heading = "CONFIG",
+           forbid_empty_values = true)]
+    /// - Optionally supply a json grammar (eg. from `/grammars`) that is used to generate and
+    /// mutate the fuzz-inputs instead of the mutator selected in `configurables.rs`
+    pub grammar: Option<String>,
+
+    #[clap(short = 'c', value_name = "CONFIG", help_heading = "CONFIG", forbid_empty_values = true,
+           global = true)]
+    /// - Optionally supply a json harness configuration that describes the targets to fuzz. If
+    /// this is not set, the targets registered in `TARGETS` are used
+    pub harness_config: Option<String>,
+
+    #[clap(short = 'r', help_heading = "CONFIG", takes_value = false)]
+    /// - Resume a previous campaign by reloading the corpus, crashes and statistics saved in the 
+    /// output directory
+    pub resume: bool,
+
     #[clap(short = 'V', takes_value = false)]
     /// - Print version information
     pub version: bool,
@@ -57,42 +101,141 @@ pub struct Cli {
     pub debug_print: bool,
 }
 
+/// Tasks that can be run on a single target instead of starting a fuzzing campaign
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// - Run a single input through a target and print the outcome
+    Repro {
+        #[clap(short = 't', value_name = "TARGET", default_value = "0")]
+        /// - Id of the target to run the input on
+        target: usize,
+
+        #[clap(value_name = "FILE", forbid_empty_values = true)]
+        /// - Input file to run
+        file: String,
+    },
+
+    /// - Minimize a corpus to the smallest set of inputs that preserves its edge-coverage
+    Cmin {
+        #[clap(short = 't', value_name = "TARGET", default_value = "0")]
+        /// - Id of the target to run the corpus on
+        target: usize,
+
+        #[clap(short = 'i', value_name = "DIR", forbid_empty_values = true)]
+        /// - Corpus directory that should be minimized
+        input_dir: String,
+
+        #[clap(short = 'o', value_name = "DIR", forbid_empty_values = true)]
+        /// - Directory the minimized corpus is written to
+        output_dir: String,
+    },
+
+    /// - Shrink a crashing input while making sure it still reproduces the same crash
+    Tmin {
+        #[clap(short = 't', value_name = "TARGET", default_value = "0")]
+        /// - Id of the target to run the input on
+        target: usize,
+
+        #[clap(short = 'o', value_name = "FILE", forbid_empty_values = true)]
+        /// - File the minimized input is written to. Defaults to `<FILE>.min`
+        output: Option<String>,
+
+        #[clap(value_name = "FILE", forbid_empty_values = true)]
+        /// - Crashing input file to minimize
+        file: String,
+    },
+
+    /// - Save the initial state of a target to a snapshot file that can be loaded using the
+    /// `snapshot` loader of a harness configuration
+    Snapshot {
+        #[clap(short = 't', value_name = "TARGET", default_value = "0")]
+        /// - Id of the target to snapshot
+        target: usize,
+
+        #[clap(short = 'o', value_name = "FILE", forbid_empty_values = true)]
+        /// - File the snapshot is written to
+        output: String,
+    },
+}
+
 /// Initialize configuration variables based on passed in commandline arguments, and verify that
 /// the user properly setup their fuzz-case
 pub fn handle_cli(args: &mut Cli) {
     DEBUG_PRINT.set(!args.debug_print).unwrap();
+
+    // Register the targets that will be fuzzed
+    if let Some(config_file) = &args.harness_config {
+        let config = load_harness_config(config_file).unwrap_or_else(|err| error_exit(&err));
+        register_targets(Some(config));
+    } else {
+        register_targets(None);
+    }
+
+    // Subcommands only operate on a single target and don't require the remaining setup
+    if args.command.is_some() {
+        return;
+    }
+
     // Verify that the input and output directories are valid
-    if !std::path::Path::new(&args.input_dir).is_dir() {
+    let input_dir  = args.input_dir.clone().unwrap();
+    let output_dir = args.output_dir.clone().unwrap();
+    if !std::path::Path::new(&input_dir).is_dir() {
         error_exit("You need to specify a valid input directory");
     }
 
-    if !std::path::Path::new(&args.output_dir).is_dir() {
+    if !std::path::Path::new(&output_dir).is_dir() {
         error_exit("You need to specify a valid output directory");
     }
-    OUTPUT_DIR.set(args.output_dir.clone()).unwrap();
-    INPUT_DIR.set(args.input_dir.clone()).unwrap();
+    OUTPUT_DIR.set(output_dir.clone()).unwrap();
+    INPUT_DIR.set(input_dir).unwrap();
+    RESUME.set(args.resume).unwrap();
 
     if let Some(dict) = &args.dictionary {
+        // An optional `@level` suffix selects which entries of the dictionary are loaded
+        let (dict, level) = match dict.rsplit_once('@') {
+            Some((path, level)) if !std::path::Path::new(&dict).is_file() => {
+                let level = level.parse::<u32>()
+                    .unwrap_or_else(|_| error_exit("You need to specify a valid dictionary level"));
+                (path, level)
+            },
+            _ => (dict.as_str(), 0),
+        };
         if !std::path::Path::new(&dict).is_file() {
             error_exit("You need to specify a valid dictionary file");
         }
         DICT_FILE.set(Some(dict.to_string())).unwrap();
+        DICT_LEVEL.set(level).unwrap();
     } else {
         DICT_FILE.set(None).unwrap();
+        DICT_LEVEL.set(0).unwrap();
+    }
+
+    if let Some(format) = &args.input_format {
+        if !std::path::Path::new(&format).is_file() {
+            error_exit("You need to specify a valid input format file");
+        }
+    }
+    FORMAT_FILE.set(args.input_format.clone()).unwrap();
+
+    if let Some(grammar) = &args.grammar {
+        if !std::path::Path::new(&grammar).is_file() {
+            error_exit("You need to specify a valid grammar file");
+        }
     }
+    GRAMMAR_FILE.set(args.grammar.clone()).unwrap();
 
     // Create the directory to save output too
-    for target in TARGETS {
-        let mut crash_dir = args.output_dir.clone();
+    for target in ACTIVE_TARGETS.get().unwrap() {
+        let mut crash_dir = output_dir.clone();
         crash_dir.push_str(&format!("/{}/crashes/", target.target_id));
 
-        let mut inv_insns_dir = args.output_dir.clone();
+        let mut inv_insns_dir = output_dir.clone();
         inv_insns_dir.push_str(&format!("/{}/inv_insns/", target.target_id));
         
-        let mut corpus_dir = args.output_dir.clone();
+        let mut corpus_dir = output_dir.clone();
         corpus_dir.push_str(&format!("/{}/corpus/", target.target_id));
 
-        let mut timeout_dir = args.output_dir.clone();
+        let mut timeout_dir = output_dir.clone();
         timeout_dir.push_str(&format!("/{}/timeouts/", target.target_id));
 
         std::fs::create_dir_all(crash_dir).unwrap();
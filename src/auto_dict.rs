@@ -0,0 +1,159 @@
+//! Automatically builds a dictionary for a target. Printable strings are extracted from the
+//! readable memory of the target once it is loaded and immediate constants from its executable
+//! memory. The operands of `strcmp`/`memcmp` calls are added while fuzzing. The resulting tokens
+//! are used by the `Dictionary` mutation alongside the user-supplied dictionary.
+
+use crate::configurables::{MAX_AUTO_TOKENS, MIN_TOKEN_LEN, MAX_TOKEN_LEN};
+
+use unicorn_engine::{
+    Unicorn,
+    unicorn_const::{Arch, Permission},
+};
+
+use rustc_hash::FxHashSet;
+
+/// Bounded and deduplicated table of dictionary tokens that is shared between all threads of a
+/// target
+#[derive(Debug, Default)]
+pub struct TokenTable {
+    /// Tokens in the order they were added
+    tokens: Vec<Vec<u8>>,
+
+    /// Used to deduplicate tokens
+    seen: FxHashSet<Vec<u8>>,
+
+    /// Set once the tokens of the target binary have been extracted
+    pub static_loaded: bool,
+}
+
+impl TokenTable {
+    /// Add `token` to the table. Returns false if it is a duplicate, has an unsuitable length, or
+    /// the table is already full
+    pub fn insert(&mut self, token: &[u8]) -> bool {
+        if token.len() < MIN_TOKEN_LEN || token.len() > MAX_TOKEN_LEN || self.is_full() {
+            return false;
+        }
+        if !self.seen.insert(token.to_vec()) {
+            return false;
+        }
+        self.tokens.push(token.to_vec());
+        true
+    }
+
+    /// Returns true once the table holds `MAX_AUTO_TOKENS` tokens
+    pub fn is_full(&self) -> bool {
+        self.tokens.len() >= MAX_AUTO_TOKENS
+    }
+
+    /// All tokens currently held by the table
+    pub fn tokens(&self) -> &[Vec<u8>] {
+        &self.tokens
+    }
+
+    /// Number of tokens currently held by the table
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Returns true if the table holds no tokens
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+/// Extract printable strings from all readable memory regions of the target, and immediate
+/// constants from its executable regions. This picks up string constants and magic values stored
+/// in data segments as well as those embedded in code. At most half of `MAX_AUTO_TOKENS` tokens are
+/// extracted, so tokens observed at runtime still fit into the table
+pub fn extract_static_tokens(unicorn: &Unicorn<'_, ()>) -> Vec<Vec<u8>> {
+    let mut seen: FxHashSet<Vec<u8>> = FxHashSet::default();
+    let mut tokens: Vec<Vec<u8>> = Vec::new();
+
+    for region in unicorn.mem_regions().unwrap() {
+        if !region.perms.contains(Permission::READ) {
+            continue;
+        }
+        let size = (region.end - region.begin + 1) as usize;
+        let data = match unicorn.mem_read_as_vec(region.begin, size) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+
+        let immediates = if region.perms.contains(Permission::EXEC) {
+            extract_immediates(unicorn.get_arch(), &data)
+        } else {
+            Vec::new()
+        };
+        let candidates = extract_strings(&data).into_iter().chain(immediates);
+        for token in candidates {
+            if tokens.len() >= MAX_AUTO_TOKENS / 2 {
+                return tokens;
+            }
+            if seen.insert(token.clone()) {
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
+
+/// Find runs of printable ascii characters that fit into a token
+fn extract_strings(data: &[u8]) -> Vec<Vec<u8>> {
+    data.split(|b| !(0x20..0x7f).contains(b))
+        .filter(|s| s.len() >= MIN_TOKEN_LEN && s.len() <= MAX_TOKEN_LEN)
+        .map(|s| s.to_vec())
+        .collect()
+}
+
+/// Constants are only useful as tokens if they are neither tiny nor trivial bit-patterns
+fn is_interesting_constant(bytes: &[u8]) -> bool {
+    bytes.iter().filter(|b| **b != 0 && **b != 0xff).count() >= 2
+}
+
+/// Find immediate operands of instructions that are commonly used to check magic values. This
+/// scans the raw bytes instead of properly disassembling the code, so some of the constants might
+/// be misinterpreted data
+fn extract_immediates(arch: Arch, data: &[u8]) -> Vec<Vec<u8>> {
+    let mut consts: Vec<Vec<u8>> = Vec::new();
+
+    match arch {
+        Arch::X86 => {
+            for i in 0..data.len().saturating_sub(10) {
+                let imm = match data[i] {
+                    // cmp eax, imm32
+                    0x3d => &data[i + 1..i + 5],
+                    // cmp r/m32, imm32 with a register operand
+                    0x81 if data[i + 1] & 0xf8 == 0xf8 => &data[i + 2..i + 6],
+                    // movabs r64, imm64
+                    0x48 | 0x49 if (0xb8..0xc0).contains(&data[i + 1]) => &data[i + 2..i + 10],
+                    _ => continue,
+                };
+                if is_interesting_constant(imm) {
+                    consts.push(imm.to_vec());
+                }
+            }
+        },
+        Arch::RISCV => {
+            // Larger constants are built using a `lui rd, imm20` followed by an
+            // `addi(w) rd, rd, imm12`. Instructions are only 2-byte aligned due to compressed
+            // instructions
+            for i in (0..data.len().saturating_sub(8)).step_by(2) {
+                let lui  = u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+                let addi = u32::from_le_bytes(data[i + 4..i + 8].try_into().unwrap());
+                let rd = (lui >> 7) & 0x1f;
+
+                if lui & 0x7f != 0x37 || !(addi & 0x7f == 0x13 || addi & 0x7f == 0x1b) ||
+                        (addi >> 12) & 0x7 != 0 || (addi >> 7) & 0x1f != rd ||
+                        (addi >> 15) & 0x1f != rd {
+                    continue;
+                }
+                let val = (lui & 0xfffff000).wrapping_add(((addi as i32) >> 20) as u32);
+                if is_interesting_constant(&val.to_le_bytes()) {
+                    consts.push(val.to_le_bytes().to_vec());
+                }
+            }
+        },
+        _ => {},
+    }
+    consts
+}
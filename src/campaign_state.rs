@@ -0,0 +1,85 @@
+//! Persists the state of a fuzzing campaign to the output directory so that a campaign can be
+//! resumed using `-r` after the fuzzer was stopped or the host was rebooted. The corpus itself is
+//! already written to `out/<id>/corpus` while fuzzing, so only the crash/timeout dedup maps and
+//! the cumulative statistics need to be saved here. Coverage is rebuilt by replaying the corpus.
+
+use crate::{
+    Statistics, TargetShared, Input,
+    arg_setup::OUTPUT_DIR,
+    error_exit,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// State of a single target that is saved to `out/<id>/state.json`
+#[derive(Default, Deserialize, Serialize)]
+pub struct CampaignState {
+    /// Cumulative statistics of all previous runs
+    pub stats: Statistics,
+
+    /// Total time in seconds that this campaign has been running for
+    pub run_time: f64,
+
+    /// Keys of `TargetShared::crash_mapping`
+    pub crashes: Vec<usize>,
+
+    /// Keys of `TargetShared::timeout_mapping`
+    pub timeouts: Vec<usize>,
+}
+
+/// Path to the state-file of the target with `target_id`
+fn state_path(target_id: usize) -> String {
+    format!("{}/{target_id}/state.json", OUTPUT_DIR.get().unwrap())
+}
+
+/// Save the current state of the target with the given statistics to disk. The state is first
+/// written to a temporary file and then moved in place so a crash while saving does not corrupt
+/// the previous state
+pub fn save_state(stats: &Statistics, run_time: f64, target_shared: &TargetShared) {
+    let state = CampaignState {
+        stats:    *stats,
+        run_time,
+        crashes:  target_shared.crash_mapping.read().keys().copied().collect(),
+        timeouts: target_shared.timeout_mapping.read().keys().copied().collect(),
+    };
+
+    let path     = state_path(stats.target_id);
+    let tmp_path = format!("{path}.tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(&state).unwrap())
+        .expect("Failed to write campaign state");
+    std::fs::rename(&tmp_path, &path).expect("Failed to write campaign state");
+}
+
+/// Load the previously saved state of the target with `target_id`. Returns `None` if the target
+/// has not saved any state yet
+pub fn load_state(target_id: usize) -> Option<CampaignState> {
+    let data = std::fs::read(state_path(target_id)).ok()?;
+    Some(serde_json::from_slice(&data).expect("Failed to parse campaign state"))
+}
+
+/// Restore the crash/timeout dedup maps of a target from a previously saved state
+pub fn restore_target_shared(state: &CampaignState, target_shared: &TargetShared) {
+    let mut crash_map = target_shared.crash_mapping.write();
+    state.crashes.iter().for_each(|c| { crash_map.insert(*c, 0); });
+
+    let mut timeout_map = target_shared.timeout_mapping.write();
+    state.timeouts.iter().for_each(|t| { timeout_map.insert(*t, 0); });
+}
+
+/// Load all inputs from the corpus directory of the target with `target_id`
+pub fn load_corpus(target_id: usize) -> Vec<Input> {
+    let corpus_dir = format!("{}/{target_id}/corpus", OUTPUT_DIR.get().unwrap());
+    let entries = std::fs::read_dir(&corpus_dir).unwrap_or_else(|err| {
+        error_exit(&format!("Failed to read corpus directory {corpus_dir} while resuming: {err}"));
+    });
+    entries
+        .map(|filename| {
+            let filename = filename.unwrap_or_else(|err| {
+                error_exit(&format!("Failed to read corpus directory {corpus_dir}: {err}"));
+            }).path();
+            Input::new(std::fs::read(&filename).unwrap_or_else(|err| {
+                error_exit(&format!("Failed to read corpus file {}: {err}", filename.display()));
+            }))
+        })
+        .collect()
+}
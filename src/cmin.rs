@@ -0,0 +1,105 @@
+//! Corpus minimization (`zfuzz cmin -t <target> -i <dir> -o <dir>`). Every input of a corpus 
+//! directory is replayed through the target while recording all edges it takes together with
+//! their hit-count buckets. Afterwards a minimal subset of inputs that still covers every
+//! edge/bucket combination is selected, preferring smaller inputs, similar to `afl-cmin`. Inputs
+//! that crash or time out are dropped.
+
+use crate::{
+    TargetShared, setup_fuzz_env, create_snapshot, run_case, error_exit,
+    targets::targets::find_target,
+    hooks::hit_count_bucket,
+};
+
+use rustc_hash::{FxHashMap, FxHashSet};
+use unicorn_engine::unicorn_const::uc_error;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Minimize the corpus in `input_dir` for the target with `target_id` and write the selected
+/// inputs to `output_dir`
+pub fn cmin(target_id: usize, input_dir: &str, output_dir: &str) -> Result<(), uc_error> {
+    let harness_init = find_target(target_id).unwrap_or_else(|| {
+        error_exit(&format!("Target {target_id} does not exist"));
+    });
+
+    // Load the corpus, smallest inputs first so they are preferred when multiple inputs cover the
+    // same edges
+    let mut inputs: Vec<(PathBuf, Vec<u8>)> = std::fs::read_dir(input_dir)
+        .unwrap_or_else(|_| error_exit(&format!("Failed to read input directory: {input_dir}")))
+        .map(|entry| {
+            let path = entry.unwrap().path();
+            let data = std::fs::read(&path).expect("Failed to read corpus file");
+            (path, data)
+        })
+        .collect();
+    inputs.sort_by(|a, b| a.1.len().cmp(&b.1.len()).then_with(|| a.0.cmp(&b.0)));
+
+    // Setup the target
+    let snapshot = create_snapshot(&harness_init);
+    let target_shared = Arc::new(TargetShared::default());
+    let (exec_env, mut unicorn) = setup_fuzz_env(&harness_init, &target_shared, &snapshot);
+
+    // Replay every input and record the edges it covers. Edges are combined with the hit-count
+    // bucket they were hit with
+    let mut edge_sets: Vec<Option<FxHashSet<u64>>> = Vec::with_capacity(inputs.len());
+    let mut dropped = 0;
+    for (_, data) in &inputs {
+        let mut emu_ref = exec_env.borrow_mut();
+        emu_ref.reset_snapshot(&mut unicorn, &snapshot).unwrap();
+        emu_ref.fuzz_input.clear();
+        emu_ref.fuzz_input.extend_from_slice(data);
+        drop(emu_ref);
+
+        if run_case(&harness_init, &target_shared, &exec_env, &mut unicorn).is_err() {
+            dropped += 1;
+            edge_sets.push(None);
+            continue;
+        }
+        let edges = exec_env.borrow().edge_hits.iter()
+            .map(|(hash, hits)| (hash << 8) | hit_count_bucket(hits) as u64)
+            .collect();
+        edge_sets.push(Some(edges));
+    }
+
+    // For every edge, find the smallest input that covers it and count how many inputs cover it
+    let mut best: FxHashMap<u64, usize> = FxHashMap::default();
+    let mut edge_counts: FxHashMap<u64, usize> = FxHashMap::default();
+    for (i, edges) in edge_sets.iter().enumerate() {
+        for &edge in edges.iter().flatten() {
+            best.entry(edge).or_insert(i);
+            *edge_counts.entry(edge).or_insert(0) += 1;
+        }
+    }
+
+    // Starting with the rarest edges, select the best input for every edge that is not yet covered
+    // by the selected inputs
+    let mut edges: Vec<u64> = edge_counts.keys().copied().collect();
+    edges.sort_by_key(|edge| (edge_counts[edge], *edge));
+
+    let mut covered: FxHashSet<u64> = FxHashSet::default();
+    let mut selected: Vec<usize> = Vec::new();
+    for edge in &edges {
+        if covered.contains(edge) {
+            continue;
+        }
+        let i = best[edge];
+        covered.extend(edge_sets[i].as_ref().unwrap());
+        selected.push(i);
+    }
+
+    // Write out the minimized corpus
+    std::fs::create_dir_all(output_dir).unwrap_or_else(|_| {
+        error_exit(&format!("Failed to create output directory: {output_dir}"));
+    });
+    for &i in &selected {
+        let (path, data) = &inputs[i];
+        std::fs::write(format!("{output_dir}/{}", path.file_name().unwrap().to_string_lossy()), 
+                       data).expect("Failed to write minimized corpus");
+    }
+
+    println!("Replayed {} inputs covering {} edge/hit-count tuples", inputs.len(), edges.len());
+    println!("Dropped {dropped} inputs that crashed or timed out");
+    println!("Wrote {} inputs to {output_dir}", selected.len());
+    Ok(())
+}
@@ -7,15 +7,77 @@
 pub const FUZZ_INPUT: &str = "fuzz_input";
 
 /// Once an input is chosen for mutations, it is mutated/ran in the fuzzer `SEED_ENERGY` times
-/// before moving on to the next input. Statistic updates are also only done every `SEED_ENERGY`
-/// cases. The slower the target is executing, the lower `SEED_ENERGY` should be to consistently get
-/// stats updates. Lowering it too much will hurt performance though, especially for faster targets
+/// before moving on to the next input. The power schedule scales this per input based on its
+/// metadata. Statistic updates are also only done once per chosen input. The slower the target is
+/// executing, the lower `SEED_ENERGY` should be to consistently get stats updates. Lowering it too
+/// much will hurt performance though, especially for faster targets
 pub const SEED_ENERGY: usize = 1000;
 
+/// Lower bound for the energy the power schedule assigns to an input
+pub const MIN_SEED_ENERGY: usize = SEED_ENERGY / 10;
+
+/// Upper bound for the energy the power schedule assigns to an input
+pub const MAX_SEED_ENERGY: usize = SEED_ENERGY * 16;
+
 /// Size of the coverage-map. Larger map is less cache-friendly but will result in less
 /// hash-collisions while collection coverage
 pub const COVMAP_SIZE: u64 = 1024 * 1024 * 1024;
 
+/// Percentage of the time a worker skips an input that is not favored while favored inputs exist
+pub const SKIP_NON_FAVORED: u32 = 95;
+
+/// Same as `SKIP_NON_FAVORED`, but for inputs that have not been fuzzed yet
+pub const SKIP_NEW_NON_FAVORED: u32 = 75;
+
+/// Number of cases a worker runs between updates of its mutation operator probabilities
+pub const MOPT_PERIOD: usize = SEED_ENERGY * 50;
+
+/// Lower bound for the selection probability of an enabled mutation operator, so operators that
+/// have not found anything yet are still occasionally used
+pub const MOPT_MIN_PROB: f64 = 0.005;
+
+/// Interval in seconds at which the campaign state is saved to the output directory so it can
+/// later be resumed using `-r`
+pub const STATE_SAVE_INTERVAL: u64 = 60;
+
+/// Maximum number of return addresses tracked by the shadow call-stack. Calls beyond this depth
+/// are not recorded
+pub const CALL_STACK_SIZE: usize = 256;
+
+/// Maximum number of bytes of a single `write` to stdout/stderr that is captured while tracing
+/// syscalls. Guests can pass arbitrary lengths, so larger writes are truncated
+pub const MAX_TRACED_WRITE: usize = 1 << 20;
+
+/// Number of stack frames (including the crashing pc) that are hashed to decide which bucket a
+/// crash belongs to. Lower values merge more crashes together
+pub const CRASH_STACK_DEPTH: usize = 5;
+
+/// Maximum number of comparisons that are logged during a single comparison-logging run
+pub const CMP_LOG_SIZE: usize = 4096;
+
+/// Maximum number of input-to-state replacements that are generated for a single seed
+pub const MAX_I2S_TOKENS: usize = 1024;
+
+/// Maximum number of tokens held by the automatically generated dictionary of a target
+pub const MAX_AUTO_TOKENS: usize = 512;
+
+/// Maximum number of distinct `strcmp`/`memcmp` operands a worker records before they are merged
+/// into the automatically generated dictionary
+pub const MAX_OBSERVED_TOKENS: usize = 256;
+
+/// Minimum length of automatically extracted dictionary tokens
+pub const MIN_TOKEN_LEN: usize = 3;
+
+/// Maximum length of automatically extracted dictionary tokens
+pub const MAX_TOKEN_LEN: usize = 32;
+
+/// Maximum depth up to which grammars loaded via `-g` are expanded. Higher depth results in more
+/// complex inputs but also increases the time required to generate them
+pub const GRAMMAR_MAX_DEPTH: usize = 64;
+
+/// Maximum number of nodes a derivation tree can grow to through grammar mutations
+pub const MAX_TREE_NODES: usize = 100_000;
+
 /// Enables some debug prints
 pub const DEBUG: bool = false;
 
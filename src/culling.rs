@@ -0,0 +1,82 @@
+//! Tracks which input is the best (smallest and fastest) one to reach every edge, and uses this to
+//! pick a small set of "favored" inputs that together still cover every edge seen so far. Workers
+//! spend most of their time on favored inputs and only occasionally pick the remaining ones. This
+//! follows afl's `update_bitmap_score`/`cull_queue`.
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+/// Per-target ranking of corpus inputs. Inputs are identified by their index into
+/// `AllShared::inputs`
+#[derive(Debug, Default)]
+pub struct TopRated {
+    /// Maps each edge-hash to the index and score of the best input that covers it
+    edges: FxHashMap<u64, (usize, f64)>,
+
+    /// Edges covered by inputs that are currently top-rated for at least one edge
+    traces: FxHashMap<usize, Vec<u64>>,
+
+    /// Inputs that were selected as favored during the last cull
+    favored: FxHashSet<usize>,
+
+    /// Set whenever `edges` changes, so the favored set needs to be recomputed
+    changed: bool,
+}
+
+impl TopRated {
+    /// Check if the input at `index` becomes the best input for any of the `edges` it covers. The
+    /// `score` should be lower for better inputs (eg. length * execution time)
+    pub fn update(&mut self, index: usize, score: f64, edges: &[u64]) {
+        let mut is_top_rated = false;
+
+        for edge in edges {
+            match self.edges.get(edge) {
+                Some((_, best)) if *best <= score => continue,
+                _ => {
+                    self.edges.insert(*edge, (index, score));
+                    is_top_rated = true;
+                },
+            }
+        }
+
+        if is_top_rated {
+            self.traces.insert(index, edges.to_vec());
+            self.changed = true;
+        }
+    }
+
+    /// Returns true if the favored set is outdated and `cull` should be called
+    pub fn needs_cull(&self) -> bool {
+        self.changed
+    }
+
+    /// Recompute the favored set. Every edge is walked, and if it is not yet covered by one of
+    /// the already favored inputs, its top-rated input is marked as favored. Traces of inputs that
+    /// are no longer top-rated for any edge are freed
+    pub fn cull(&mut self) {
+        let mut covered: FxHashSet<u64> = FxHashSet::default();
+        let mut referenced: FxHashSet<usize> = FxHashSet::default();
+        self.favored.clear();
+
+        for (edge, (index, _)) in &self.edges {
+            referenced.insert(*index);
+            if covered.contains(edge) {
+                continue;
+            }
+            self.favored.insert(*index);
+            covered.extend(self.traces[index].iter().copied());
+        }
+
+        self.traces.retain(|index, _| referenced.contains(index));
+        self.changed = false;
+    }
+
+    /// Returns true if the input at `index` was favored during the last cull
+    pub fn is_favored(&self, index: usize) -> bool {
+        self.favored.contains(&index)
+    }
+
+    /// Number of inputs that were favored during the last cull
+    pub fn num_favored(&self) -> usize {
+        self.favored.len()
+    }
+}
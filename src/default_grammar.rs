@@ -7,6 +7,8 @@
 
 #![allow(unreachable_code)]
 
+use crate::grammar_tree::{Node, TreeGrammar};
+
 pub struct GrammarMut {}
 impl GrammarMut {
     pub fn default() -> Self {
@@ -18,3 +20,21 @@ impl GrammarMut {
         Vec::new()
     }
 }
+
+impl TreeGrammar for GrammarMut {
+    fn start(&self) -> usize {
+        panic!("Attempting to use grammar_mut without having first generated it");
+    }
+
+    fn expand(&mut self, _fragment: usize, _depth: usize, _nodes: &mut Vec<Node>) {
+        panic!("Attempting to use grammar_mut without having first generated it");
+    }
+
+    fn terminal(&self, _fragment: usize) -> &[u8] {
+        panic!("Attempting to use grammar_mut without having first generated it");
+    }
+
+    fn rand(&mut self) -> usize {
+        panic!("Attempting to use grammar_mut without having first generated it");
+    }
+}
@@ -1,16 +1,18 @@
 use crate::{
     execution_state::FileType::{STDIN, STDOUT, STDERR},
-    configurables::{MAX_ALLOCATION_ADDR, FIRSTALLOCATION},
+    configurables::{MAX_ALLOCATION_ADDR, FIRSTALLOCATION, CALL_STACK_SIZE},
+    snapshot_image::{SnapshotImage, PrivateMappings},
 };
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 use unicorn_engine::{
     Unicorn, Context,
-    unicorn_const::{Permission, uc_error},
+    unicorn_const::{Arch, Mode, Permission, uc_error, MemType},
 };
 
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::hash_map::Entry;
 
 /// Different types of files that the fuzzer supports
 #[derive(Copy, Debug, Clone, Eq, PartialEq)]
@@ -63,11 +65,32 @@ impl File {
     }
 }
 
+/// Describes an invalid memory access that caused a fuzz-case to crash
+#[derive(Debug, Copy, Clone)]
+pub struct FaultInfo {
+    /// Address that was accessed
+    pub address: u64,
+
+    /// Size of the memory access in bytes
+    pub size: usize,
+
+    /// Type of the memory access
+    pub mem_type: MemType,
+}
+
 /// State of initial snapshot is saved in this struct and used for future snapshot restores
 pub struct SnapshotContext {
-    /// Used to maintain memory mapping. When the memory of the guest is reset for the next 
-    /// fuzz-case, these mappings are used to restore the initial context.
-    pub page_map: FxHashMap<usize, Vec<u8>>,
+    /// Memory image shared by all workers of the target. Workers map it copy-on-write, and when
+    /// the memory of the guest is reset for the next fuzz-case, dirtied pages are restored from it
+    pub image: SnapshotImage,
+
+    /// Regions that are mapped without any permissions, such as the part of the allocator's
+    /// backing that has not been handed out yet (address, size). They are not part of the image
+    pub reserved_regions: Vec<(u64, usize)>,
+
+    /// Architecture and mode of the emulator the snapshot was taken from
+    pub arch: Arch,
+    pub mode: Mode,
 
     /// CPU-Context structure used by unicorn engine
     pub cpu_context: Context,
@@ -82,6 +105,151 @@ pub struct SnapshotContext {
     pub alloc_addr: u64,
 }
 
+impl SnapshotContext {
+    /// Create a new execution environment and unicorn engine in the state of this snapshot. The
+    /// memory is mapped copy-on-write from the shared image instead of rerunning the target's
+    /// initialization, so every engine only holds private copies of the pages it writes to. Hooks
+    /// are not inserted
+    pub fn create_engine(&self) -> Result<(Rc<RefCell<ExecEnv>>, Unicorn<'static, ()>), uc_error> {
+        let exec_env: Rc<RefCell<ExecEnv>> =
+            Rc::new(RefCell::new(ExecEnv::new(64 * 1024 * 1024)));
+        let mut unicorn = Unicorn::new(self.arch, self.mode)?;
+
+        let image_mappings = self.image.map_into(&mut unicorn)?;
+        for (addr, size) in &self.reserved_regions {
+            unicorn.mem_map(*addr, *size, Permission::NONE)?;
+        }
+        unicorn.context_restore(&self.cpu_context)?;
+
+        let mut emu_ref = exec_env.borrow_mut();
+        emu_ref.fd_list        = self.fd_list.clone();
+        emu_ref.alloc_addr     = self.alloc_addr;
+        emu_ref.prev_block     = self.prev_block;
+        emu_ref.image_mappings = Some(image_mappings);
+        drop(emu_ref);
+
+        Ok((exec_env, unicorn))
+    }
+}
+
+/// Incremental snapshot taken once a fuzz-case reaches the nested snapshot address of its target.
+/// Only holds the state that changed since the root snapshot
+pub struct NestedSnapshot {
+    /// Contents of all pages that were dirtied between the root snapshot and this one
+    pub page_map: FxHashMap<usize, Vec<u8>>,
+
+    /// CPU-Context structure used by unicorn engine
+    pub cpu_context: Context,
+
+    /// File descriptors that were active when the snapshot was taken
+    pub fd_list: Vec<File>,
+
+    /// Address of last block hit before snapshot was taken
+    pub prev_block: u64,
+
+    /// Address of the next free region of memory that the allocator will use for allocations
+    pub alloc_addr: u64,
+
+    /// Heap allocations that were live or already free'd when the snapshot was taken
+    pub heap_allocations: FxHashMap<u64, usize>,
+    pub freed: FxHashMap<u64, u32>,
+
+    /// Regions that were mmap'd before the snapshot was taken
+    pub mmap_allocations: FxHashMap<u64, usize>,
+
+    /// Shadow call-stack at the snapshot, so crashes are still bucketed on their full call-stack
+    pub call_stack: Vec<u64>,
+    pub last_block_end: u64,
+
+    /// Part of the fuzz-input that was consumed before the snapshot was taken. Only inputs that
+    /// start with these bytes can be run from this snapshot
+    pub input_prefix: Vec<u8>,
+}
+
+/// Number of edges a single fuzz-case is expected to hit at most. `EdgeHits` reserves space for
+/// this many edges up front, so the map rarely has to grow while a case is running
+const EDGE_HITS_CAPACITY: usize = 1 << 14;
+
+/// Hit-counts of the edges taken by a fuzz-case. Only the edges of a single case are stored, so
+/// the map stays small regardless of how large the coverage-map is. Clearing it keeps its
+/// allocation, so it is reused by the following cases. The edges that were touched are recorded
+/// separately in the order they were first hit
+pub struct EdgeHits {
+    /// Saturating hit-count of every edge that was hit, indexed by coverage-map index
+    counts: FxHashMap<u64, u8>,
+
+    /// Coverage-map indices of all edges with a non-zero hit-count
+    touched: Vec<u64>,
+}
+
+impl EdgeHits {
+    /// Create an empty map
+    pub fn new() -> Self {
+        EdgeHits {
+            counts:  FxHashMap::with_capacity_and_hasher(EDGE_HITS_CAPACITY, Default::default()),
+            touched: Vec::with_capacity(EDGE_HITS_CAPACITY),
+        }
+    }
+
+    /// Record a hit of the edge `hash`
+    #[inline]
+    pub fn hit(&mut self, hash: u64) -> u8 {
+        let count = self.counts.entry(hash).or_insert(0);
+        if *count == 0 {
+            self.touched.push(hash);
+        }
+        *count = count.saturating_add(1);
+        *count
+    }
+
+    /// Iterate over all edges that were hit (coverage-map index, hit-count)
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u8)> + '_ {
+        self.touched.iter().map(|&hash| (hash, self.counts[&hash]))
+    }
+
+    /// Coverage-map indices of all edges that were hit
+    pub fn edges(&self) -> &[u64] {
+        &self.touched
+    }
+
+    /// Number of edges that were hit
+    pub fn len(&self) -> usize {
+        self.touched.len()
+    }
+
+    /// Returns true if no edges were hit
+    pub fn is_empty(&self) -> bool {
+        self.touched.is_empty()
+    }
+
+    /// Returns true if the edge `hash` was hit
+    pub fn contains(&self, hash: u64) -> bool {
+        self.counts.contains_key(&hash)
+    }
+
+    /// Reset the hit-counts of all edges that were hit
+    pub fn clear(&mut self) {
+        self.counts.clear();
+        self.touched.clear();
+    }
+
+    /// Replace the current hit-counts with `hits` (coverage-map index, hit-count), as returned by
+    /// `iter`
+    pub fn restore(&mut self, hits: &[(u64, u8)]) {
+        self.clear();
+        for &(hash, count) in hits {
+            self.counts.insert(hash, count);
+            self.touched.push(hash);
+        }
+    }
+}
+
+impl Default for EdgeHits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Execution environment. Keeps track of files, allocator variables, dirty-list, etc
 pub struct ExecEnv {
     /// List of file descriptors that the process can use for syscalls
@@ -119,6 +287,63 @@ pub struct ExecEnv {
 
     /// This tracks the new coverage that a fuzz-case finds. Reset after each case
     pub cov_count: usize,
+
+    /// Shadow call-stack holding the return addresses of the currently active function calls.
+    /// Used to bucket crashes based on the path that lead to them
+    pub call_stack: Vec<u64>,
+
+    /// Address right after the last executed block, used to detect calls for the shadow stack
+    pub last_block_end: u64,
+
+    /// Set by the bound-checking hook if the fuzz-case performed an invalid memory access
+    pub fault: Option<FaultInfo>,
+
+    /// If set, syscalls and data written to stdout/stderr are recorded in `syscall_trace` and 
+    /// `guest_output`. Only enabled for single-input modes since this is slow
+    pub trace_syscalls: bool,
+
+    /// Syscalls invoked by the current fuzz-case (syscall number, pc)
+    pub syscall_trace: Vec<(u64, u64)>,
+
+    /// Data written to stdout/stderr by the current fuzz-case
+    pub guest_output: Vec<u8>,
+
+    /// Hit-counts of all edges taken by the current fuzz-case, indexed by coverage-map index.
+    /// Classified into buckets and merged into the coverage-map once the case finishes
+    pub edge_hits: EdgeHits,
+
+    /// If set, the operands of executed comparisons are logged to `cmp_log`
+    pub trace_cmps: bool,
+
+    /// Operands of comparisons executed by the current fuzz-case (arg1, arg2, size in bytes)
+    pub cmp_log: Vec<(u64, u64, usize)>,
+
+    /// Operands of `strcmp`/`memcmp` calls. Unlike the other traces this is not cleared on reset,
+    /// but drained by the worker once it merges them into the automatically generated dictionary
+    pub observed_tokens: FxHashSet<Vec<u8>>,
+
+    /// Number of bytes of the fuzz-input the target has consumed so far. `usize::MAX` if the
+    /// target depends on the entire input (eg. it was injected at once, or read up to its end)
+    pub input_consumed: usize,
+
+    /// If set, the fuzz-case is stopped once the nested snapshot address of the target is reached
+    pub stop_at_nested: bool,
+
+    /// Set if the fuzz-case was stopped at the nested snapshot address
+    pub nested_reached: bool,
+
+    /// Set while the memory of the emulator is based on a nested snapshot instead of the root
+    /// snapshot. Resetting to the root snapshot clears this
+    pub nested_active: bool,
+
+    /// Pages that hold the contents of the active nested snapshot, and thus need to be restored
+    /// when resetting to the root snapshot
+    pub nested_pages: Vec<usize>,
+
+    /// Copy-on-write mappings of the snapshot image that back the memory of the emulator. The
+    /// hooks of the emulator hold references to this environment, so it is only dropped once the
+    /// emulator has been closed and no longer uses the mappings
+    pub image_mappings: Option<PrivateMappings>,
 }
 
 impl ExecEnv {
@@ -135,6 +360,22 @@ impl ExecEnv {
             error_flag:        uc_error::OK,
             prev_block:        0x8392674281237520, // (arbitrary high-entropy number)
             cov_count:         0x0,
+            call_stack:        Vec::with_capacity(CALL_STACK_SIZE),
+            last_block_end:    0x0,
+            fault:             None,
+            trace_syscalls:    false,
+            syscall_trace:     Vec::new(),
+            guest_output:      Vec::new(),
+            edge_hits:         EdgeHits::new(),
+            trace_cmps:        false,
+            cmp_log:           Vec::new(),
+            observed_tokens:   FxHashSet::default(),
+            input_consumed:    0,
+            stop_at_nested:    false,
+            nested_reached:    false,
+            nested_active:     false,
+            nested_pages:      Vec::new(),
+            image_mappings:    None,
         }
     }
 
@@ -235,18 +476,15 @@ impl ExecEnv {
         self.fd_list.len() - 1
     }
 
-    /// Take a snapshot of the current emulator state and return it
-    pub fn save_reset_state(&mut self, unicorn: &Unicorn<'_, ()>, page_list: Vec<usize>) 
+    /// Take a snapshot of the current emulator state and return it. `regions` are copied into the
+    /// memory image, `reserved_regions` are mapped without permissions and thus hold no data
+    pub fn save_reset_state(&mut self, unicorn: &Unicorn<'_, ()>, 
+                            regions: Vec<(u64, usize, Permission)>,
+                            reserved_regions: Vec<(u64, usize)>)
             -> Result<SnapshotContext, uc_error> {
 
-        // Initialize page-map that keeps track of initial memory mappings
-        let mut page_map = FxHashMap::default();
-        for addr in page_list {
-            let data = unicorn.mem_read_as_vec(addr as u64, 0x1000)?;
-
-            assert_eq!(page_map.insert(addr, data.to_owned()),
-                    None, "Attempted to insert duplicate pages into page-map");
-        }
+        // Initialize the memory image that keeps track of the initial memory mappings
+        let image = SnapshotImage::new(unicorn, regions)?;
 
         // Initialize cpu-context (keeps track of registers, eflags, etc
         let cpu_context = unicorn.context_init()?;
@@ -255,7 +493,10 @@ impl ExecEnv {
         let fd_list = self.fd_list.clone();
 
         Ok(SnapshotContext {
-            page_map,
+            image,
+            reserved_regions,
+            arch: unicorn.get_arch(),
+            mode: unicorn.get_mode(),
             cpu_context,
             fd_list,
             prev_block: self.prev_block,
@@ -269,7 +510,13 @@ impl ExecEnv {
         // Restore unicorn cpu-state context
         unicorn.context_restore(&snapshot_context.cpu_context)?;
 
-        // Restore unicorn memory-state context and dirty lists
+        // Restore unicorn memory-state context and dirty lists. If the memory is based on a nested
+        // snapshot, the pages it changed are restored as well
+        if self.nested_active {
+            self.dirty.extend_from_slice(&self.nested_pages);
+            self.nested_pages.clear();
+            self.nested_active = false;
+        }
         for &addr in &self.dirty {
             let page_start = (addr & !(0x1000-1)) as u64;
 
@@ -277,7 +524,7 @@ impl ExecEnv {
             unicorn.reset_dirty(addr as u64);
 
             // Reset all dirtied memory pages by restoring from an original copy
-            if let Some(original_memory) = snapshot_context.page_map.get(&(page_start as usize)) {
+            if let Some(original_memory) = snapshot_context.image.page(page_start as usize) {
                 unicorn.mem_write(page_start, original_memory)?;
             } else {
                 // This case is triggered with pages that were not yet initialized during our 
@@ -291,9 +538,9 @@ impl ExecEnv {
 
         if false {
             let mut reader = [0; 4096];
-            for (addr, page) in snapshot_context.page_map.iter() {
-                unicorn.mem_read(*addr as u64, &mut reader).unwrap();
-                //let orig = snapshot_context.page_map.get(&addr).unwrap();
+            for (addr, page) in snapshot_context.image.pages() {
+                unicorn.mem_read(addr as u64, &mut reader).unwrap();
+                //let orig = snapshot_context.image.page(addr).unwrap();
 
                 assert_eq!(reader.len(), 4096);
                 assert_eq!(page.len(), 4096);
@@ -330,16 +577,124 @@ impl ExecEnv {
         // Reset current base address of allocator
         self.alloc_addr = snapshot_context.alloc_addr;
 
-        // Reset error flag to `Ok`in case it was used to set an error in the previous case
-        self.error_flag = uc_error::OK;
-
         // Reset previous block to snapshot state
         self.prev_block = snapshot_context.prev_block;
 
+        // Reset crash-triage information
+        self.call_stack.clear();
+        self.last_block_end = 0;
+        self.input_consumed = 0;
+
+        self.reset_case_state();
+        Ok(())
+    }
+
+    /// Capture the current state as a nested snapshot. Only valid if the emulator was reset to the
+    /// root snapshot before the current fuzz-case, since everything that was not dirtied during it
+    /// is expected to still hold the contents of the root snapshot
+    pub fn take_nested_snapshot(&mut self, unicorn: &Unicorn<'_, ()>)
+            -> Result<NestedSnapshot, uc_error> {
+        let mut page_map = FxHashMap::default();
+        for &addr in &self.dirty {
+            let page_start = addr & !(0x1000-1);
+            if let Entry::Vacant(entry) = page_map.entry(page_start) {
+                entry.insert(unicorn.mem_read_as_vec(page_start as u64, 0x1000)?);
+            }
+        }
+
+        let prefix_len = self.input_consumed.min(self.fuzz_input.len());
+        self.nested_active = true;
+
+        Ok(NestedSnapshot {
+            page_map,
+            cpu_context:      unicorn.context_init()?,
+            fd_list:          self.fd_list.clone(),
+            prev_block:       self.prev_block,
+            alloc_addr:       self.alloc_addr,
+            heap_allocations: self.heap_allocations.clone(),
+            freed:            self.freed.clone(),
+            mmap_allocations: self.mmap_allocations.clone(),
+            call_stack:       self.call_stack.clone(),
+            last_block_end:   self.last_block_end,
+            input_prefix:     self.fuzz_input[..prefix_len].to_vec(),
+        })
+    }
+
+    /// Reset the emulator state to a nested snapshot. This only restores the pages that were
+    /// dirtied since the last reset, so the memory needs to be based on `nested` (`nested_active`)
+    pub fn reset_nested_snapshot(&mut self, unicorn: &mut Unicorn<'_, ()>,
+                                 snapshot_context: &SnapshotContext, nested: &NestedSnapshot)
+            -> Result<(), uc_error> {
+        assert!(self.nested_active, "Memory is not based on a nested snapshot");
+        unicorn.context_restore(&nested.cpu_context)?;
+
+        // Restore dirtied pages, falling back to the root snapshot for pages the nested snapshot
+        // did not change
+        for &addr in &self.dirty {
+            let page_start = addr & !(0x1000-1);
+            unicorn.reset_dirty(addr as u64);
+
+            let original_memory = nested.page_map.get(&page_start).map(Vec::as_slice)
+                .or_else(|| snapshot_context.image.page(page_start));
+            match original_memory {
+                Some(original_memory) => unicorn.mem_write(page_start as u64, original_memory)?,
+                None => unicorn.mem_write(page_start as u64, &[0x0u8; 0x1000])?,
+            }
+        }
+        self.dirty.clear();
+        self.nested_pages.clear();
+        self.nested_pages.extend(nested.page_map.keys());
+
+        // Free allocations made after the nested snapshot, and make allocations that were free'd
+        // after it accessible again
+        for (alloc_addr, size) in &self.heap_allocations {
+            if !nested.heap_allocations.contains_key(alloc_addr) {
+                unicorn.mem_protect(*alloc_addr, *size, Permission::NONE)?;
+            }
+        }
+        for alloc_addr in self.freed.keys() {
+            if nested.freed.contains_key(alloc_addr) { continue; }
+            if let Some(size) = nested.heap_allocations.get(alloc_addr) {
+                let aligned_size = (0xfff + size) & !0xfff;
+                unicorn.mem_protect(*alloc_addr, aligned_size,
+                                    Permission::READ | Permission::WRITE)?;
+            }
+        }
+        for (alloc_addr, size) in &self.mmap_allocations {
+            if !nested.mmap_allocations.contains_key(alloc_addr) {
+                unicorn.mem_unmap(*alloc_addr, *size)?;
+            }
+        }
+        self.heap_allocations.clone_from(&nested.heap_allocations);
+        self.freed.clone_from(&nested.freed);
+        self.mmap_allocations.clone_from(&nested.mmap_allocations);
+
+        self.fd_list.clone_from(&nested.fd_list);
+        self.alloc_addr     = nested.alloc_addr;
+        self.prev_block     = nested.prev_block;
+        self.last_block_end = nested.last_block_end;
+        self.input_consumed = nested.input_prefix.len();
+        self.call_stack.clone_from(&nested.call_stack);
+
+        self.reset_case_state();
+        Ok(())
+    }
+
+    /// Reset the per-case state that does not depend on the snapshot that is restored
+    fn reset_case_state(&mut self) {
+        // Reset error flag to `Ok`in case it was used to set an error in the previous case
+        self.error_flag = uc_error::OK;
+
         // Reset coverage-counter after every fuzz-case
         self.cov_count = 0;
 
-        Ok(())
+        // Reset crash-triage information
+        self.fault = None;
+        self.syscall_trace.clear();
+        self.guest_output.clear();
+        self.edge_hits.clear();
+        self.cmp_log.clear();
+        self.nested_reached = false;
     }
 }
 
@@ -347,15 +702,15 @@ impl ExecEnv {
 /// to this initial state
 pub fn take_snapshot(exec_env: &Rc<RefCell<ExecEnv>>, uc: &Unicorn<'_, ()>) 
         -> Result<SnapshotContext, uc_error> {
-    let mut page_list: Vec<usize> = Vec::new();
+    let mut regions: Vec<(u64, usize, Permission)> = Vec::new();
+    let mut reserved_regions: Vec<(u64, usize)> = Vec::new();
     for mem_region in uc.mem_regions()? {
+        let size = ((mem_region.end + 1) - mem_region.begin) as usize;
         if mem_region.perms != Permission::NONE {
-            let num_pages = ((mem_region.end + 1) - mem_region.begin) / 4096;
-            for i in 0..num_pages {
-                let addr = mem_region.begin + (4096 * i);
-                page_list.push(addr as usize);
-            }
+            regions.push((mem_region.begin, size, mem_region.perms));
+        } else {
+            reserved_regions.push((mem_region.begin, size));
         }
     }
-    exec_env.borrow_mut().save_reset_state(uc, page_list)
+    exec_env.borrow_mut().save_reset_state(uc, regions, reserved_regions)
 }
@@ -0,0 +1,162 @@
+//! Runtime grammar engine. A json grammar passed in via `-g` is parsed and optimized using fzero's
+//! `GrammarRust`, and then compiled into a compact table of fragments that is interpreted while
+//! fuzzing. This generates the same derivation trees as the Rust module fzero emits into
+//! `grammar_mut.rs`, but switching grammars does not require regenerating code and recompiling.
+
+use crate::{
+    configurables::GRAMMAR_MAX_DEPTH,
+    grammar_tree::{Alternative, Node, TreeGrammar, repeat_count, select_alternative},
+};
+
+use fzero::{Fragment, Grammar, GrammarRust};
+use rand::Rng;
+use rand_xoshiro::rand_core::{RngCore, SeedableRng};
+use rand_xoshiro::Xoroshiro64Star;
+
+/// Compiled representation of a single fragment. Alternatives, fragment-ids and terminal bytes are
+/// stored in shared tables and referenced by (offset, length)
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    /// Expand one randomly chosen alternative of `alternatives[offset..offset + len]`
+    NonTerminal(usize, usize),
+
+    /// Expand the fragment `min..=max` times, the minimum depth of the fragment is included
+    Repeat { fragment: usize, min: usize, max: usize, min_depth: usize },
+
+    /// Expand all fragments of `ids[offset..offset + len]` in order
+    Expression(usize, usize),
+
+    /// Emit `bytes[offset..offset + len]`
+    Terminal(usize, usize),
+
+    /// Does nothing
+    Nop,
+}
+
+/// Grammar generator that interprets a grammar loaded at runtime
+#[derive(Debug, Clone)]
+pub struct GrammarInterp {
+    /// Fast Rng
+    rng: Xoroshiro64Star,
+
+    /// One op per fragment, indexed by fragment-id
+    ops: Vec<Op>,
+
+    /// Alternatives of all non-terminals
+    alternatives: Vec<Alternative>,
+
+    /// Fragment-ids referenced by expressions
+    ids: Vec<usize>,
+
+    /// Bytes of all terminals
+    bytes: Vec<u8>,
+
+    /// Fragment-id of the start rule
+    start: usize,
+}
+
+impl GrammarInterp {
+    /// Load a grammar in fzero's json format and compile it
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let data = std::fs::read(filename).map_err(|e| format!("Failed to read {filename}: {e}"))?;
+        let grammar: Grammar = serde_json::from_slice(&data)
+            .map_err(|e| format!("Failed to parse {filename}: {e}"))?;
+
+        let mut grammar = GrammarRust::new(&grammar).map_err(|e| format!("{filename}: {e}"))?;
+        grammar.optimize();
+        Ok(Self::compile(&grammar))
+    }
+
+    /// Flatten the fragments of an optimized grammar into the op-table
+    fn compile(grammar: &GrammarRust) -> Self {
+        let min_depths       = grammar.min_depths();
+        let mut ops          = Vec::with_capacity(grammar.fragments.len());
+        let mut alternatives = Vec::new();
+        let mut ids          = Vec::new();
+        let mut bytes        = Vec::new();
+
+        for fragment in &grammar.fragments {
+            let op = match fragment {
+                Fragment::NonTerminal(options) => {
+                    alternatives.extend(options.iter().map(|(id, weight)| Alternative {
+                        fragment:  id.0,
+                        weight:    *weight,
+                        min_depth: min_depths[id.0],
+                    }));
+                    Op::NonTerminal(alternatives.len() - options.len(), options.len())
+                },
+                Fragment::Repeat(id, min, max) => Op::Repeat {
+                    fragment:  id.0,
+                    min:       *min,
+                    max:       *max,
+                    min_depth: min_depths[id.0],
+                },
+                Fragment::Expression(expr) => {
+                    ids.extend(expr.iter().map(|id| id.0));
+                    Op::Expression(ids.len() - expr.len(), expr.len())
+                },
+                Fragment::Terminal(value) => {
+                    bytes.extend_from_slice(value);
+                    Op::Terminal(bytes.len() - value.len(), value.len())
+                },
+                Fragment::Nop => Op::Nop,
+            };
+            ops.push(op);
+        }
+
+        Self {
+            rng: Xoroshiro64Star::seed_from_u64(rand::thread_rng().gen()),
+            ops,
+            alternatives,
+            ids,
+            bytes,
+            start: grammar.start.unwrap().0,
+        }
+    }
+}
+
+impl TreeGrammar for GrammarInterp {
+    fn start(&self) -> usize {
+        self.start
+    }
+
+    /// Interpret `fragment` the same way the generated `fragment_N` functions would
+    fn expand(&mut self, fragment: usize, depth: usize, nodes: &mut Vec<Node>) {
+        let node = nodes.len();
+        nodes.push(Node::new(fragment, depth));
+
+        match self.ops[fragment] {
+            Op::NonTerminal(offset, len) => {
+                let rand = self.rand();
+                let alternatives = &self.alternatives[offset..offset + len];
+                let choice = select_alternative(rand, alternatives, depth, GRAMMAR_MAX_DEPTH);
+                let option = alternatives[choice].fragment;
+                self.expand(option, depth + 1, nodes);
+            },
+            Op::Repeat { fragment, min, max, min_depth } => {
+                let rand = self.rand();
+                for _ in 0..repeat_count(rand, min, max, min_depth, depth, GRAMMAR_MAX_DEPTH) {
+                    self.expand(fragment, depth + 1, nodes);
+                }
+            },
+            Op::Expression(offset, len) => {
+                for i in offset..offset + len {
+                    self.expand(self.ids[i], depth + 1, nodes);
+                }
+            },
+            Op::Terminal(..) | Op::Nop => {},
+        }
+        nodes[node].size = nodes.len() - node;
+    }
+
+    fn terminal(&self, fragment: usize) -> &[u8] {
+        match self.ops[fragment] {
+            Op::Terminal(offset, len) => &self.bytes[offset..offset + len],
+            _ => &[],
+        }
+    }
+
+    fn rand(&mut self) -> usize {
+        self.rng.next_u64() as usize
+    }
+}
@@ -0,0 +1,241 @@
+//! Derivation trees for coverage-guided grammar fuzzing. Instead of only emitting bytes, the
+//! grammar generator records every fragment it expands as a node of a tree. Inputs generated this
+//! way keep their tree in the corpus, which allows them to be mutated on the grammar level:
+//! subtrees can be regenerated, swapped with compatible subtrees of other corpus entries, or
+//! recursively expanded. Since every mutation result is still derived from the grammar, it always
+//! stays syntactically valid.
+
+use crate::{
+    Input,
+    configurables::MAX_TREE_NODES,
+};
+
+/// Interface to a grammar that can generate derivation trees. Implemented by the generator that
+/// fzero emits into `grammar_mut.rs` and by the runtime interpreter in `grammar_interp.rs`
+pub trait TreeGrammar {
+    /// Fragment-id of the start rule of the grammar
+    fn start(&self) -> usize;
+
+    /// Randomly expand `fragment` at `depth` and append the resulting subtree to `nodes` in
+    /// pre-order. Close to the maximum depth of the grammar, only alternatives that terminate in
+    /// time are picked
+    fn expand(&mut self, fragment: usize, depth: usize, nodes: &mut Vec<Node>);
+
+    /// Bytes emitted by `fragment`. Empty for every fragment that is not a terminal
+    fn terminal(&self, fragment: usize) -> &[u8];
+
+    /// Random number used to pick mutations
+    fn rand(&mut self) -> usize;
+}
+
+/// One of the alternatives a non-terminal fragment can expand to
+#[derive(Debug, Clone, Copy)]
+pub struct Alternative {
+    /// Fragment-id of the alternative
+    pub fragment: usize,
+
+    /// Relative probability with which this alternative is picked
+    pub weight: usize,
+
+    /// Number of levels the shortest possible expansion of this alternative takes up
+    pub min_depth: usize,
+}
+
+/// Use the random number `rand` to pick one of `alternatives` for a non-terminal expanded at
+/// `depth`, proportionally to their weights. Alternatives that would exceed `max_depth` are not
+/// considered. If no alternative fits, the one that terminates the quickest is picked instead
+pub fn select_alternative(rand: usize, alternatives: &[Alternative], depth: usize,
+        max_depth: usize) -> usize {
+    let remaining = max_depth.saturating_sub(depth + 1);
+    let total: usize = alternatives.iter()
+        .filter(|alt| alt.min_depth <= remaining)
+        .map(|alt| alt.weight)
+        .sum();
+
+    if total == 0 {
+        return alternatives.iter()
+            .enumerate()
+            .min_by_key(|(_, alt)| alt.min_depth)
+            .unwrap().0;
+    }
+
+    let mut pick = rand % total;
+    for (i, alt) in alternatives.iter().enumerate() {
+        if alt.min_depth > remaining { continue; }
+        if pick < alt.weight {
+            return i;
+        }
+        pick -= alt.weight;
+    }
+    unreachable!();
+}
+
+/// Use the random number `rand` to pick how often a repetition of `min..=max` expanded at `depth`
+/// is repeated. Only the minimum number of repetitions is used if the repeated fragment would
+/// exceed `max_depth`
+pub fn repeat_count(rand: usize, min: usize, max: usize, min_depth: usize, depth: usize,
+        max_depth: usize) -> usize {
+    if min_depth > max_depth.saturating_sub(depth + 1) {
+        min
+    } else {
+        match (max - min).checked_add(1) {
+            Some(range) => min + rand % range,
+            // `min..=max` spans every `usize`, so any random number is a valid count
+            None => rand,
+        }
+    }
+}
+
+/// A single expanded fragment of a derivation tree
+#[derive(Debug, Clone, Copy)]
+pub struct Node {
+    /// Id of the grammar fragment this node expanded
+    pub fragment: usize,
+
+    /// Depth at which the fragment was expanded
+    pub depth: usize,
+
+    /// Number of nodes in the subtree rooted at this node, including the node itself
+    pub size: usize,
+}
+
+impl Node {
+    pub fn new(fragment: usize, depth: usize) -> Self {
+        Self { fragment, depth, size: 1 }
+    }
+}
+
+/// Derivation tree of a grammar input. Nodes are stored in pre-order, so every subtree is a
+/// contiguous range of `nodes`
+#[derive(Debug, Clone, Default)]
+pub struct DerivationTree {
+    pub nodes: Vec<Node>,
+}
+
+impl DerivationTree {
+    /// Generate a new tree from the start rule of `grammar`
+    pub fn generate<G: TreeGrammar + ?Sized>(grammar: &mut G) -> Self {
+        let mut nodes = Vec::new();
+        grammar.expand(grammar.start(), 0, &mut nodes);
+        Self { nodes }
+    }
+
+    /// Turn the tree back into an input by concatenating its terminals
+    pub fn unparse<G: TreeGrammar + ?Sized>(&self, grammar: &G) -> Vec<u8> {
+        let mut output = Vec::new();
+        for node in &self.nodes {
+            output.extend_from_slice(grammar.terminal(node.fragment));
+        }
+        output
+    }
+
+    /// Nodes of the subtree rooted at `index`
+    fn subtree(&self, index: usize) -> &[Node] {
+        &self.nodes[index..index + self.nodes[index].size]
+    }
+
+    /// Replace the subtree at `index` with a freshly generated expansion of the same fragment
+    fn replace_subtree<G: TreeGrammar + ?Sized>(&mut self, grammar: &mut G, index: usize)
+            -> Result<(), ()> {
+        let mut subtree = Vec::new();
+        grammar.expand(self.nodes[index].fragment, self.nodes[index].depth, &mut subtree);
+        replace_nodes(&mut self.nodes, index, &subtree)
+    }
+
+    /// Replace a subtree with a subtree of `other` that was expanded from the same fragment
+    fn splice_subtree<G: TreeGrammar + ?Sized>(&mut self, grammar: &mut G, other: &DerivationTree)
+            -> Result<(), ()> {
+        if other.nodes.is_empty() { return Err(()); }
+        let donor = grammar.rand() % other.nodes.len();
+
+        let candidates: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].fragment == other.nodes[donor].fragment)
+            .collect();
+        if candidates.is_empty() { return Err(()); }
+
+        let index = candidates[grammar.rand() % candidates.len()];
+        replace_nodes(&mut self.nodes, index, other.subtree(donor))
+    }
+
+    /// Find a subtree that contains another expansion of its own fragment, and replace that inner
+    /// expansion with the outer subtree 1-3 times. This grows recursive constructs such as nested
+    /// lists or expressions
+    fn recursive_expand<G: TreeGrammar + ?Sized>(&mut self, grammar: &mut G) -> Result<(), ()> {
+        let outer = grammar.rand() % self.nodes.len();
+        let end = outer + self.nodes[outer].size;
+
+        let candidates: Vec<usize> = (outer + 1..end)
+            .filter(|&i| self.nodes[i].fragment == self.nodes[outer].fragment)
+            .collect();
+        if candidates.is_empty() { return Err(()); }
+        let inner = candidates[grammar.rand() % candidates.len()] - outer;
+
+        let template = self.subtree(outer).to_vec();
+        let mut expanded = template.clone();
+        for _ in 0..(1 + grammar.rand() % 3) {
+            let mut next = template.clone();
+            replace_nodes(&mut next, inner, &expanded)?;
+            expanded = next;
+        }
+        replace_nodes(&mut self.nodes, outer, &expanded)
+    }
+}
+
+/// Replace the subtree at `index` of `nodes` with `subtree`. The depth of the new nodes is adjusted
+/// to their new position, and the sizes of all ancestors are updated. Fails if the tree would grow
+/// beyond `MAX_TREE_NODES`
+fn replace_nodes(nodes: &mut Vec<Node>, index: usize, subtree: &[Node]) -> Result<(), ()> {
+    let old_size = nodes[index].size;
+    if nodes.len() - old_size + subtree.len() > MAX_TREE_NODES { return Err(()); }
+
+    // Ancestors are exactly the nodes before `index` whose subtree range contains it
+    for (i, node) in nodes[..index].iter_mut().enumerate() {
+        if i + node.size > index {
+            node.size = node.size - old_size + subtree.len();
+        }
+    }
+
+    let depth = nodes[index].depth;
+    let new_nodes = subtree.iter().map(|node| Node {
+        depth: node.depth - subtree[0].depth + depth,
+        ..*node
+    });
+    nodes.splice(index..index + old_size, new_nodes);
+    Ok(())
+}
+
+/// Create a mutated copy of `tree`. One of the following is applied, retrying with a different
+/// mutation if the chosen one is not applicable:
+/// - Regenerate a random subtree
+/// - Replace a subtree with a compatible subtree of another corpus entry
+/// - Recursively expand a subtree
+///
+/// Falls back to generating an entirely new tree if no mutation could be applied
+pub fn mutate_tree<G: TreeGrammar + ?Sized>(grammar: &mut G, tree: &DerivationTree,
+        corpus: &[Input]) -> DerivationTree {
+    let mut mutated = tree.clone();
+    if mutated.nodes.is_empty() {
+        return DerivationTree::generate(grammar);
+    }
+
+    for _ in 0..16 {
+        let res = match grammar.rand() % 3 {
+            0 => {
+                let index = grammar.rand() % mutated.nodes.len();
+                mutated.replace_subtree(grammar, index)
+            },
+            1 => {
+                let other = &corpus[grammar.rand() % corpus.len()];
+                match &other.tree {
+                    Some(other) => mutated.splice_subtree(grammar, other),
+                    None => Err(()),
+                }
+            },
+            _ => mutated.recursive_expand(grammar),
+        };
+        if res.is_ok() {
+            return mutated;
+        }
+    }
+    DerivationTree::generate(grammar)
+}
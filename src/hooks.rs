@@ -1,23 +1,33 @@
 use crate::{
-    execution_state::ExecEnv,
+    execution_state::{ExecEnv, FaultInfo},
     syscalls, dbg_print, TargetShared,
-    configurables::{COVMAP_SIZE, EMIT_COV, LIBC_VERSION, LibcOpt},
+    configurables::{
+        COVMAP_SIZE, EMIT_COV, LIBC_VERSION, LibcOpt, CALL_STACK_SIZE, CMP_LOG_SIZE,
+        MAX_OBSERVED_TOKENS, MIN_TOKEN_LEN, MAX_TOKEN_LEN,
+    },
 };
 
 use unicorn_engine::{
     Unicorn, RegisterRISCV, RegisterX86, InsnSysX86,
-    unicorn_const::{uc_error, Permission, HookType, MemType, IsDirty, Arch, Mode},
+    unicorn_const::{
+        uc_error, Permission, HookType, MemType, IsDirty, Arch, Mode, TcgOpCode, TcgOpFlag,
+    },
 };
 
+use capstone::prelude::*;
+use capstone::arch::{ArchOperand, x86::X86OperandType};
+use rustc_hash::FxHashMap;
+
 use std::rc::Rc;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::cell::RefCell;
 use std::io::prelude::*;
 use std::fs::OpenOptions;
 
 /// Hook that makes use of zfuzz's mmu to perform a memory safe malloc_r operation
 /// TODO: Needs updates
-pub fn insert_malloc_r_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>, 
+pub fn insert_malloc_r_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
             malloc_addr: u64) -> Result<(), uc_error> {
     let exec_env_clone = Rc::clone(exec_env);
     let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
@@ -45,7 +55,7 @@ pub fn insert_malloc_r_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_
 }
 
 /// Hook that makes use of zfuzz's mmu to perform a memory safe malloc operation
-pub fn insert_malloc_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>, 
+pub fn insert_malloc_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
             malloc_addr: u64) -> Result<(), uc_error> {
     let exec_env_clone = Rc::clone(exec_env);
     let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
@@ -108,7 +118,7 @@ pub fn insert_free_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()
 
 
 /// Hook that makes use of zfuzz's mmu to perform a memory safe realloc operation
-pub fn insert_realloc_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>, 
+pub fn insert_realloc_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
             malloc_addr: u64) -> Result<(), uc_error> {
     let exec_env_clone = Rc::clone(exec_env);
     let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
@@ -174,6 +184,141 @@ pub fn insert_free_r_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_,
     Ok(())
 }
 
+/// Places a hook at `addr` that stops the fuzz-case once it is reached
+pub fn insert_exit_hook(uc: &mut Unicorn<'_, ()>, addr: u64) -> Result<(), uc_error> {
+    let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
+        uc.emu_stop().unwrap();
+    };
+
+    uc.add_code_hook(addr, addr, callback)?;
+    Ok(())
+}
+
+/// Places a hook at `addr` that stops fuzz-cases that were started with `stop_at_nested` set once
+/// they reach it, so a nested snapshot can be taken. All other cases just continue executing
+pub fn insert_nested_snapshot_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
+                                   addr: u64) -> Result<(), uc_error> {
+    let exec_env_clone = Rc::clone(exec_env);
+    let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
+        let mut exec_env = exec_env_clone.borrow_mut();
+        if exec_env.stop_at_nested && !exec_env.nested_reached {
+            exec_env.nested_reached = true;
+            uc.emu_stop().unwrap();
+        }
+    };
+
+    uc.add_code_hook(addr, addr, callback)?;
+    Ok(())
+}
+
+/// Read the 3rd argument of the function that was just called. The fork of unicorn only provides
+/// helpers for the first 2 arguments
+fn function_arg2_val(uc: &Unicorn<'_, ()>) -> Result<u64, uc_error> {
+    match (uc.get_arch(), uc.get_mode()) {
+        (Arch::X86, Mode::MODE_64) => uc.reg_read(RegisterX86::RDX),
+        (Arch::X86, _) => {
+            // Arguments are passed on the stack, right above the return address
+            let sp = uc.reg_read(RegisterX86::ESP)?;
+            let arg = uc.mem_read_as_vec(sp + 12, 4)?;
+            Ok(u32::from_le_bytes(arg.try_into().unwrap()) as u64)
+        },
+        (Arch::RISCV, _) => uc.reg_read(RegisterRISCV::A2),
+        _ => Err(uc_error::ARCH),
+    }
+}
+
+/// Read a nul-terminated string of at most `MAX_TOKEN_LEN` bytes from guest memory
+fn read_c_string(uc: &Unicorn<'_, ()>, addr: u64) -> Vec<u8> {
+    let mut string = Vec::new();
+    for i in 0..MAX_TOKEN_LEN as u64 {
+        match uc.mem_read_as_vec(addr + i, 1) {
+            Ok(byte) if byte[0] != 0 => string.push(byte[0]),
+            _ => break,
+        }
+    }
+    string
+}
+
+/// Record the operands of a string/memory comparison so they can be added to the automatically
+/// generated dictionary
+fn record_tokens(exec_env: &Rc<RefCell<ExecEnv>>, operands: [Vec<u8>; 2]) {
+    let mut exec_env = exec_env.borrow_mut();
+    for token in operands {
+        if exec_env.observed_tokens.len() >= MAX_OBSERVED_TOKENS {
+            return;
+        }
+        if token.len() >= MIN_TOKEN_LEN {
+            exec_env.observed_tokens.insert(token);
+        }
+    }
+}
+
+/// Places a hook at the start of the targets `strcmp` function (or any other function comparing 2
+/// nul-terminated strings passed in as the first 2 arguments) that records both strings for the
+/// automatically generated dictionary
+pub fn insert_strcmp_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
+                          strcmp_addr: u64) -> Result<(), uc_error> {
+    let exec_env_clone = Rc::clone(exec_env);
+    let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
+        let str1 = read_c_string(uc, uc.function_arg0_val().unwrap());
+        let str2 = read_c_string(uc, uc.function_arg1_val().unwrap());
+        record_tokens(&exec_env_clone, [str1, str2]);
+    };
+
+    uc.add_code_hook(strcmp_addr, strcmp_addr, callback)?;
+    Ok(())
+}
+
+/// Places a hook at the start of the targets `memcmp` function (or any other function comparing 2
+/// buffers whose length is passed in as the 3rd argument) that records both buffers for the
+/// automatically generated dictionary
+pub fn insert_memcmp_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
+                          memcmp_addr: u64) -> Result<(), uc_error> {
+    let exec_env_clone = Rc::clone(exec_env);
+    let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
+        let len = function_arg2_val(uc).unwrap() as usize;
+        if len > MAX_TOKEN_LEN { return; }
+
+        let buf1 = uc.mem_read_as_vec(uc.function_arg0_val().unwrap(), len).unwrap_or_default();
+        let buf2 = uc.mem_read_as_vec(uc.function_arg1_val().unwrap(), len).unwrap_or_default();
+        record_tokens(&exec_env_clone, [buf1, buf2]);
+    };
+
+    uc.add_code_hook(memcmp_addr, memcmp_addr, callback)?;
+    Ok(())
+}
+
+/// Places a hook at `addr` that writes `exec_env.fuzz_input` into the buffer pointed to by
+/// `buffer_reg`. If `length_reg` is set, the length of the injected input is written to it
+pub fn insert_input_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>, addr: u64,
+                         buffer_reg: i32, length_reg: Option<i32>, max_len: Option<usize>)
+        -> Result<(), uc_error> {
+    let exec_env_clone = Rc::clone(exec_env);
+    let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
+        let input_buffer_addr: u64 = uc.reg_read(buffer_reg).unwrap();
+        let mut mutated_data = exec_env_clone.borrow().fuzz_input.clone();
+
+        if let Some(max_len) = max_len {
+            mutated_data.truncate(max_len);
+        }
+
+        if let Some(length_reg) = length_reg {
+            uc.reg_write(length_reg, mutated_data.len() as u64).unwrap();
+        }
+
+        // Mark the memory region as dirtied so our snapshot resets properly reset this memory area
+        let mut exec_env = exec_env_clone.borrow_mut();
+        exec_env.mark_dirtied(input_buffer_addr, mutated_data.len());
+        exec_env.input_consumed = usize::MAX;
+        drop(exec_env);
+        uc.mem_write(input_buffer_addr, &mutated_data)
+            .expect("Failed to write mutated data into target");
+    };
+
+    uc.add_code_hook(addr, addr, callback)?;
+    Ok(())
+}
+
 /// Inserts a hook that is in charge of bounds-checking memory-operations
 /// This hook needs to be inserted before the dirty-bit-tracking hook so it doesn't potentially go 
 /// out of bounds when processing invalid address.
@@ -194,9 +339,11 @@ pub fn insert_ld_st_boundcheck_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Un
                 }
             }
 
-            // If we haven't returned yet, this operation was not valid, so set appropriate error
-            // flag
-            exec_env_clone.borrow_mut().error_flag = match mem_type {
+            // If we haven't returned yet, this operation was not valid, so record the access and
+            // set appropriate error flag
+            let mut exec_env = exec_env_clone.borrow_mut();
+            exec_env.fault = Some(FaultInfo { address, size, mem_type });
+            exec_env.error_flag = match mem_type {
                 MemType::READ           => uc_error::READ_UNMAPPED,
                 MemType::WRITE          => uc_error::WRITE_UNMAPPED,
                 MemType::FETCH          => uc_error::FETCH_UNMAPPED,
@@ -208,6 +355,7 @@ pub fn insert_ld_st_boundcheck_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Un
                 MemType::READ_AFTER     => uc_error::READ_UNMAPPED,
                 _ => unreachable!(),
             };
+            drop(exec_env);
 
             // Stop the emulation after the error is set
             uc.emu_stop().unwrap();
@@ -219,6 +367,25 @@ pub fn insert_ld_st_boundcheck_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Un
     Ok(())
 }
 
+/// Inserts a hook on invalid memory accesses that unicorn itself catches (eg. fetches from unmapped
+/// memory) so the faulting access is recorded for crash triage. The access is not handled, so
+/// emulation still stops with the corresponding error
+pub fn insert_invalid_mem_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>)
+        -> Result<(), uc_error> {
+    let exec_env_clone = Rc::clone(exec_env);
+    let callback = move |_uc: &mut Unicorn<'_, ()>, mem_type: MemType, address: u64, 
+        size: usize, _value: i64| -> bool {
+            let mut exec_env = exec_env_clone.borrow_mut();
+            if exec_env.fault.is_none() {
+                exec_env.fault = Some(FaultInfo { address, size, mem_type });
+            }
+            false
+    };
+
+    uc.add_mem_hook(HookType::MEM_INVALID, 0, u64::MAX, callback)?;
+    Ok(())
+}
+
 /// Inserts hook that tracks dirtied memory on memory writes
 pub fn insert_dirty_page_tracking_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>)
         -> Result<(), uc_error> {
@@ -248,7 +415,7 @@ pub fn insert_dirty_page_tracking_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut
 }
 
 /// Inserts a hook to track edge coverage
-pub fn insert_coverage_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>, 
+pub fn insert_coverage_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
         shared_data: &Arc<TargetShared>) -> Result<(), uc_error> {
     let exec_env_clone = Rc::clone(exec_env);
     let shared_data_clone = Arc::clone(shared_data);
@@ -261,33 +428,345 @@ pub fn insert_coverage_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_
         .unwrap();
 
     let callback = move |_uc: &mut Unicorn<'_, ()>, address: u64, _size: u32| {
+        let mut exec_env = exec_env_clone.borrow_mut();
+
+        // Coverage uses same hashing algorithm as afl
+        // See: https://lcamtuf.coredump.cx/afl/technical_details.txt
         let cur_location = (address >> 4) ^ (address << 8);
-        let hash = (cur_location ^ exec_env_clone.borrow().prev_block) % COVMAP_SIZE;
-
-        // This breaks rust thread-safety for the coverage map. The alternative would be to wrap 
-        // this in locks, but we would like to avoid wrapping frequently accessed ds's like 
-        // coverage-maps in locks since that would greatly impact performance.
-        //
-        // The result of races might be that an input might think that it found new coverage even 
-        // though it did not. This is an acceptable result for massive performance gains.
-        unsafe {
-            // Coverage uses same hashing algorithm as afl
-            // See: https://lcamtuf.coredump.cx/afl/technical_details.txt
-            let cov_ptr = shared_data_clone.coverage_bytemap.as_ptr() as u64;
-            let bytemap_entry: *mut u8 = (cov_ptr + hash) as *mut u8;
-            if *bytemap_entry == 0 {
-                if EMIT_COV {
-                    f.write_all(format!("{:#X?}\n", address).as_bytes()).unwrap();
+        let hash = (cur_location ^ exec_env.prev_block) % COVMAP_SIZE;
+
+        // Only count hits here, they are merged into the coverage-map once the case finishes
+        let hits = exec_env.edge_hits.hit(hash);
+
+        if EMIT_COV && hits == 1 && 
+                coverage_entry(&shared_data_clone, hash).load(Ordering::Relaxed) == 0 {
+            f.write_all(format!("{:#X?}\n", address).as_bytes()).unwrap();
+        }
+        exec_env.prev_block = cur_location >> 1;
+    };
+
+    uc.add_block_hook(callback)?;
+    Ok(())
+}
+
+/// Access an entry of the thread-shared coverage-map. All threads access the map atomically, which
+/// keeps it lock-free. Frequently accessed ds's like coverage-maps should not be wrapped in locks
+/// since that would greatly impact performance.
+pub fn coverage_entry(shared_data: &TargetShared, hash: u64) -> &AtomicU8 {
+    &shared_data.coverage_bytemap[hash as usize]
+}
+
+/// Set `bits` in the coverage-map entry `hash`. Returns true if any of them were not set before,
+/// meaning that new coverage was found
+fn mark_coverage(shared_data: &TargetShared, hash: u64, bits: u8) -> bool {
+    let prev = coverage_entry(shared_data, hash).fetch_or(bits, Ordering::Relaxed);
+    prev & bits != bits
+}
+
+/// Classify the hit-count of an edge into afl-style buckets. Each bucket is represented by a
+/// single bit so the buckets that were seen for an edge can be stored as a bitmask
+pub fn hit_count_bucket(hits: u8) -> u8 {
+    match hits {
+        0          => 0,
+        1          => 1,
+        2          => 2,
+        3          => 4,
+        4..=7      => 8,
+        8..=15     => 16,
+        16..=31    => 32,
+        32..=127   => 64,
+        _          => 128,
+    }
+}
+
+/// Classify the hit-counts of all edges taken by the current fuzz-case and merge them into the
+/// coverage-map. Every edge that was hit a number of times that falls into a bucket that was not
+/// seen before for this edge counts as new coverage, so changes in loop iteration counts are 
+/// picked up as well
+pub fn update_coverage(exec_env: &Rc<RefCell<ExecEnv>>, shared_data: &TargetShared) {
+    let mut exec_env = exec_env.borrow_mut();
+    let new_cov = exec_env.edge_hits.iter()
+        .filter(|&(hash, hits)| mark_coverage(shared_data, hash, hit_count_bucket(hits)))
+        .count();
+    exec_env.cov_count += new_cov;
+}
+
+/// Reward partial matches between the operands of a comparison at `pc`. Every additional matching 
+/// low-order byte marks its own entry in the coverage-map, so inputs that get closer to passing a 
+/// multi-byte comparison are saved to the corpus. Full matches are left to the edge-coverage
+fn cmp_coverage(exec_env: &Rc<RefCell<ExecEnv>>, shared_data: &TargetShared, pc: u64, 
+                arg1: u64, arg2: u64, size: usize) {
+    let diff = arg1 ^ arg2;
+    let matched = ((diff.trailing_zeros() / 8) as usize).min(size.saturating_sub(1));
+
+    let cur_location = (pc >> 4) ^ (pc << 8);
+    for i in 1..=matched {
+        let hash = (cur_location ^ (i as u64).wrapping_mul(0x9e3779b97f4a7c15)) % COVMAP_SIZE;
+        if mark_coverage(shared_data, hash, 1) {
+            exec_env.borrow_mut().cov_count += 1;
+        }
+    }
+}
+
+/// Handle a comparison between `arg1` and `arg2` that was executed at `pc`. Operands are
+/// rewarded with comparison-coverage if `cmpcov` is set, and logged for input-to-state
+/// replacements if comparison-logging is enabled for the current case
+fn handle_cmp(exec_env: &Rc<RefCell<ExecEnv>>, shared_data: &TargetShared, cmpcov: bool, pc: u64,
+              arg1: u64, arg2: u64, size: usize) {
+    if cmpcov {
+        cmp_coverage(exec_env, shared_data, pc, arg1, arg2, size);
+    }
+
+    let mut exec_env = exec_env.borrow_mut();
+    if exec_env.trace_cmps && arg1 != arg2 && exec_env.cmp_log.len() < CMP_LOG_SIZE {
+        exec_env.cmp_log.push((arg1, arg2, size));
+    }
+}
+
+/// Operand of a decoded x86 `test` instruction
+#[derive(Debug, Clone, Copy)]
+enum TestOperand {
+    /// Unicorn register-id
+    Reg(i32),
+
+    /// Immediate value
+    Imm(u64),
+
+    /// Memory operand. `base` and `index` are unicorn register-ids, `rip`-relative operands are
+    /// already resolved into `disp`
+    Mem { base: Option<i32>, index: Option<i32>, scale: u64, disp: u64 },
+}
+
+impl TestOperand {
+    /// Read the current value of this operand, truncated to `size` bytes
+    fn read(&self, uc: &Unicorn<'_, ()>, size: usize) -> Option<u64> {
+        let mask = if size >= 8 { u64::MAX } else { (1u64 << (size * 8)) - 1 };
+        match *self {
+            TestOperand::Reg(reg) => uc.reg_read(reg).ok().map(|val| val & mask),
+            TestOperand::Imm(imm) => Some(imm & mask),
+            TestOperand::Mem { base, index, scale, disp } => {
+                let base  = base.map_or(Some(0), |reg| uc.reg_read(reg).ok())?;
+                let index = index.map_or(Some(0), |reg| uc.reg_read(reg).ok())?;
+                let addr  = base.wrapping_add(index.wrapping_mul(scale)).wrapping_add(disp);
+
+                let mut buf = [0u8; 8];
+                uc.mem_read(addr, &mut buf[..size.min(8)]).ok()?;
+                Some(u64::from_le_bytes(buf))
+            },
+        }
+    }
+}
+
+/// Resolve capstone's name of a general purpose x86 register to its unicorn register
+fn x86_reg(name: &str) -> Option<RegisterX86> {
+    let reg = match name {
+        "rax" => RegisterX86::RAX, "eax" => RegisterX86::EAX, "ax" => RegisterX86::AX,
+        "al"  => RegisterX86::AL,  "ah"  => RegisterX86::AH,  "rbx" => RegisterX86::RBX,
+        "ebx" => RegisterX86::EBX, "bx"  => RegisterX86::BX,  "bl" => RegisterX86::BL,
+        "bh"  => RegisterX86::BH,  "rcx" => RegisterX86::RCX, "ecx" => RegisterX86::ECX,
+        "cx"  => RegisterX86::CX,  "cl"  => RegisterX86::CL,  "ch" => RegisterX86::CH,
+        "rdx" => RegisterX86::RDX, "edx" => RegisterX86::EDX, "dx" => RegisterX86::DX,
+        "dl"  => RegisterX86::DL,  "dh"  => RegisterX86::DH,  "rsi" => RegisterX86::RSI,
+        "esi" => RegisterX86::ESI, "si"  => RegisterX86::SI,  "sil" => RegisterX86::SIL,
+        "rdi" => RegisterX86::RDI, "edi" => RegisterX86::EDI, "di" => RegisterX86::DI,
+        "dil" => RegisterX86::DIL, "rbp" => RegisterX86::RBP, "ebp" => RegisterX86::EBP,
+        "bp"  => RegisterX86::BP,  "bpl" => RegisterX86::BPL, "rsp" => RegisterX86::RSP,
+        "esp" => RegisterX86::ESP, "sp"  => RegisterX86::SP,  "spl" => RegisterX86::SPL,
+        "r8"  => RegisterX86::R8,  "r8d"  => RegisterX86::R8D,  "r8w"  => RegisterX86::R8W,
+        "r8b" => RegisterX86::R8B, "r9"   => RegisterX86::R9,   "r9d"  => RegisterX86::R9D,
+        "r9w" => RegisterX86::R9W, "r9b"  => RegisterX86::R9B,  "r10"  => RegisterX86::R10,
+        "r10d" => RegisterX86::R10D, "r10w" => RegisterX86::R10W, "r10b" => RegisterX86::R10B,
+        "r11"  => RegisterX86::R11,  "r11d" => RegisterX86::R11D, "r11w" => RegisterX86::R11W,
+        "r11b" => RegisterX86::R11B, "r12"  => RegisterX86::R12,  "r12d" => RegisterX86::R12D,
+        "r12w" => RegisterX86::R12W, "r12b" => RegisterX86::R12B, "r13"  => RegisterX86::R13,
+        "r13d" => RegisterX86::R13D, "r13w" => RegisterX86::R13W, "r13b" => RegisterX86::R13B,
+        "r14"  => RegisterX86::R14,  "r14d" => RegisterX86::R14D, "r14w" => RegisterX86::R14W,
+        "r14b" => RegisterX86::R14B, "r15"  => RegisterX86::R15,  "r15d" => RegisterX86::R15D,
+        "r15w" => RegisterX86::R15W, "r15b" => RegisterX86::R15B,
+        _ => return None,
+    };
+    Some(reg)
+}
+
+/// Decode the instruction at `address` and return its operands and operand size in bytes if it
+/// is a `test` instruction
+fn decode_x86_test(cs: &Capstone, uc: &Unicorn<'_, ()>, address: u64)
+        -> Option<([TestOperand; 2], usize)> {
+    let bytes = uc.mem_read_as_vec(address, 15).ok()?;
+    let insns = cs.disasm_count(&bytes, address, 1).ok()?;
+    let insn  = insns.iter().next()?;
+    if insn.mnemonic()? != "test" {
+        return None;
+    }
+    let next_pc = address + insn.bytes().len() as u64;
+
+    let detail = cs.insn_detail(&insn).ok()?;
+    let arch_detail = detail.arch_detail();
+    let mut operands = [TestOperand::Imm(0); 2];
+    let mut size = 0;
+    for (i, op) in arch_detail.operands().into_iter().enumerate().take(2) {
+        let ArchOperand::X86Operand(op) = op else { return None; };
+        size = op.size as usize;
+        operands[i] = match op.op_type {
+            X86OperandType::Reg(reg) => TestOperand::Reg(x86_reg(&cs.reg_name(reg)?)?.into()),
+            X86OperandType::Imm(imm) => TestOperand::Imm(imm as u64),
+            X86OperandType::Mem(mem) => {
+                // Segment-relative accesses (eg. `fs:[0x28]`) are not resolved
+                if mem.segment().0 != 0 {
+                    return None;
+                }
+                let resolve = |reg: RegId| -> Option<Option<i32>> {
+                    if reg.0 == 0 {
+                        return Some(None);
+                    }
+                    Some(Some(x86_reg(&cs.reg_name(reg)?)?.into()))
+                };
+
+                let mut disp = mem.disp() as u64;
+                let base = match cs.reg_name(mem.base()).as_deref() {
+                    Some("rip") | Some("eip") => {
+                        disp = disp.wrapping_add(next_pc);
+                        None
+                    },
+                    _ => resolve(mem.base())?,
+                };
+                TestOperand::Mem {
+                    base,
+                    index: resolve(mem.index())?,
+                    scale: mem.scale() as u64,
+                    disp,
                 }
+            },
+            X86OperandType::Invalid => return None,
+        };
+    }
+    Some((operands, size))
+}
+
+/// Inserts comparison instrumentation. On x86 this hooks `cmp`/`sub` instructions through
+/// unicorn's tcg-opcode hooks. Unicorn only supports these hooks for `sub`, so `test` instructions
+/// are decoded once per address using capstone and their operands are read in an instruction
+/// hook instead. On RISC-V every instruction is checked for conditional branches (`beq`, `bne`,
+/// `blt`, ..., and the compressed `c.beqz`/`c.bnez`) whose register operands are then compared.
+/// Used for comparison-coverage (if `cmpcov` is set) and comparison-logging
+pub fn insert_cmp_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>,
+        shared_data: &Arc<TargetShared>, cmpcov: bool) -> Result<(), uc_error> {
+    let exec_env_clone = Rc::clone(exec_env);
+    let shared_data_clone = Arc::clone(shared_data);
+
+    match uc.get_arch() {
+        Arch::X86 => {
+            // `size` is the operand size in bits
+            let exec_env_sub    = Rc::clone(&exec_env_clone);
+            let shared_data_sub = Arc::clone(&shared_data_clone);
+            let callback = move |_uc: &mut Unicorn<'_, ()>, address: u64, arg1: u64, arg2: u64, 
+                    size: usize| {
+                handle_cmp(&exec_env_sub, &shared_data_sub, cmpcov, address, arg1, arg2, 
+                           size / 8);
+            };
+            uc.add_tcg_hook(TcgOpCode::SUB, TcgOpFlag::CMP | TcgOpFlag::DIRECT, 1, 0, callback)?;
+
+            // `test` sets the flags based on the `and` of its operands, so the result is compared
+            // against 0. Decoded instructions are cached (address -> (operands, size))
+            let mode = match uc.get_mode() {
+                Mode::MODE_64 => arch::x86::ArchMode::Mode64,
+                _ => arch::x86::ArchMode::Mode32,
+            };
+            let cs = Capstone::new().x86().mode(mode).detail(true).build()
+                .expect("Failed to initialize capstone");
+            let mut decode_cache: FxHashMap<u64, Option<([TestOperand; 2], usize)>> = 
+                FxHashMap::default();
+
+            let callback = move |uc: &mut Unicorn<'_, ()>, address: u64, _size: u32| {
+                let test = *decode_cache.entry(address)
+                    .or_insert_with(|| decode_x86_test(&cs, uc, address));
+
+                if let Some(([op1, op2], size)) = test {
+                    if let (Some(arg1), Some(arg2)) = (op1.read(uc, size), op2.read(uc, size)) {
+                        handle_cmp(&exec_env_clone, &shared_data_clone, cmpcov, address, 
+                                   arg1 & arg2, 0, size);
+                    }
+                }
+            };
+            uc.add_code_hook(1, 0, callback)?;
+        },
+        Arch::RISCV => {
+            // Decoding every executed instruction is expensive, so decoded branches are cached
+            // (address -> (rs1, rs2))
+            let mut decode_cache: FxHashMap<u64, Option<(i32, i32)>> = FxHashMap::default();
+
+            let callback = move |uc: &mut Unicorn<'_, ()>, address: u64, _size: u32| {
+                let operands = *decode_cache.entry(address).or_insert_with(|| {
+                    let mut half = [0u8; 2];
+                    uc.mem_read(address, &mut half).ok()?;
+                    let half = u16::from_le_bytes(half);
+
+                    // Compressed instructions don't have both low bits set. `c.beqz`/`c.bnez`
+                    // (quadrant 1, funct3 6 & 7) compare `rs1'` (x8-x15) against zero
+                    if half & 0x3 != 0x3 {
+                        let funct3 = (half >> 13) & 0x7;
+                        if half & 0x3 != 0x1 || (funct3 != 6 && funct3 != 7) {
+                            return None;
+                        }
+                        let rs1 = 8 + ((half >> 7) & 0x7) as i32;
+                        return Some((RegisterRISCV::X0 as i32 + rs1, RegisterRISCV::X0 as i32));
+                    }
 
-                // Set the entry in the thread-shared bytemap to mark this edge as taken
-                *bytemap_entry = 1;
+                    let mut insn = [0u8; 4];
+                    uc.mem_read(address, &mut insn).ok()?;
+                    let insn = u32::from_le_bytes(insn);
 
-                // Indicate that this case found new coverage
-                exec_env_clone.borrow_mut().cov_count += 1;
+                    // Conditional branches use the `BRANCH` opcode, funct3 2 & 3 are reserved
+                    let funct3 = (insn >> 12) & 0x7;
+                    if insn & 0x7f != 0x63 || funct3 == 2 || funct3 == 3 {
+                        return None;
+                    }
+                    let rs1 = ((insn >> 15) & 0x1f) as i32;
+                    let rs2 = ((insn >> 20) & 0x1f) as i32;
+                    Some((RegisterRISCV::X0 as i32 + rs1, RegisterRISCV::X0 as i32 + rs2))
+                });
+
+                if let Some((rs1, rs2)) = operands {
+                    let arg1 = uc.reg_read(rs1).unwrap();
+                    let arg2 = uc.reg_read(rs2).unwrap();
+                    handle_cmp(&exec_env_clone, &shared_data_clone, cmpcov, address, arg1, arg2, 
+                               8);
+                }
+            };
+            uc.add_code_hook(1, 0, callback)?;
+        },
+        _ => panic!("Comparison hooks are not supported for architecture: {:#?}", uc.get_arch()),
+    }
+    Ok(())
+}
+
+/// Inserts a hook that maintains a shadow call-stack in `exec_env.call_stack`. A block is treated 
+/// as the start of a call if the current function's return address points right behind the 
+/// previously executed block, and as a return if it starts at the most recently pushed return 
+/// address
+pub fn insert_call_stack_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>) 
+        -> Result<(), uc_error> {
+    let exec_env_clone = Rc::clone(exec_env);
+
+    let callback = move |uc: &mut Unicorn<'_, ()>, address: u64, size: u32| {
+        let mut exec_env = exec_env_clone.borrow_mut();
+        let last_block_end = exec_env.last_block_end;
+
+        if exec_env.call_stack.last() == Some(&address) {
+            exec_env.call_stack.pop();
+        } else if address != last_block_end && 
+                uc.func_return_addr().unwrap_or(0) == last_block_end {
+            if exec_env.call_stack.len() < CALL_STACK_SIZE {
+                exec_env.call_stack.push(last_block_end);
             }
+        } else if let Some(pos) = exec_env.call_stack.iter().rev().take(4)
+                .position(|&ret| ret == address) {
+            // Returned past some frames without a matching return (eg. tail-calls or longjmp)
+            let new_len = exec_env.call_stack.len() - pos - 1;
+            exec_env.call_stack.truncate(new_len);
         }
-        exec_env_clone.borrow_mut().prev_block = cur_location >> 1;
+
+        exec_env.last_block_end = address + size as u64;
     };
 
     uc.add_block_hook(callback)?;
@@ -333,6 +812,14 @@ pub fn insert_linux_syscall_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unico
     Ok(())
 }
 
+/// Record the syscall that is about to be executed if syscall-tracing is enabled
+fn trace_syscall(exec_env: &Rc<RefCell<ExecEnv>>, uc: &Unicorn<'_, ()>, syscall_num: u64) {
+    let mut exec_env = exec_env.borrow_mut();
+    if exec_env.trace_syscalls {
+        exec_env.syscall_trace.push((syscall_num, uc.get_pc().unwrap()));
+    }
+}
+
 /// Maps RISCV64 syscall numbers
 pub fn insert_linux_riscv_syscall_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>) 
         -> Result<(), uc_error> {
@@ -340,11 +827,14 @@ pub fn insert_linux_riscv_syscall_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut
     let callback = move |uc: &mut Unicorn<'_, ()>, interrupt_num: u32| {
         match interrupt_num {
             8 => { /* Interrupt number 8 indicates a SYSCALL on RISCV*/
-                let ret = match uc.reg_read(RegisterRISCV::A7).unwrap() {
+                let syscall_num = uc.reg_read(RegisterRISCV::A7).unwrap();
+                trace_syscall(&exec_env_clone, uc, syscall_num);
+
+                let ret = match syscall_num {
                     50   => syscalls::openat(exec_env_clone.borrow_mut(), uc),
                     57   => syscalls::close(exec_env_clone.borrow_mut(), uc),
                     63   => syscalls::read(exec_env_clone.borrow_mut(), uc),
-                    64   => syscalls::write(exec_env_clone.borrow(), uc),
+                    64   => syscalls::write(exec_env_clone.borrow_mut(), uc),
                     79   => syscalls::fstatat(exec_env_clone.borrow_mut(), uc),
                     80   => syscalls::fstat(exec_env_clone.borrow_mut(), uc),
                     93   => syscalls::exit(exec_env_clone.borrow_mut(), uc),
@@ -386,9 +876,12 @@ pub fn insert_linux_x86_64_syscall_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mu
 
     let exec_env_clone = Rc::clone(exec_env);
     let callback = move |uc: &mut Unicorn<'_, ()>| {
-        let ret = match uc.reg_read(RegisterX86::RAX).unwrap() {
+        let syscall_num = uc.reg_read(RegisterX86::RAX).unwrap();
+        trace_syscall(&exec_env_clone, uc, syscall_num);
+
+        let ret = match syscall_num {
             0   => syscalls::read(exec_env_clone.borrow_mut(), uc),
-            1   => syscalls::write(exec_env_clone.borrow(), uc),
+            1   => syscalls::write(exec_env_clone.borrow_mut(), uc),
             2   => syscalls::open(exec_env_clone.borrow_mut(), uc),
             3   => syscalls::close(exec_env_clone.borrow_mut(), uc),
             5   => syscalls::fstat(exec_env_clone.borrow_mut(), uc),
@@ -435,10 +928,13 @@ pub fn insert_linux_x86_32_syscall_hook(exec_env: &Rc<RefCell<ExecEnv>>, uc: &mu
             panic!("INT_{int_num} called, only supporting INT_80");
         }
 
-        let ret = match uc.reg_read(RegisterX86::EAX).unwrap() {
+        let syscall_num = uc.reg_read(RegisterX86::EAX).unwrap();
+        trace_syscall(&exec_env_clone, uc, syscall_num);
+
+        let ret = match syscall_num {
             1   => syscalls::exit(exec_env_clone.borrow_mut(), uc),
             3   => syscalls::read(exec_env_clone.borrow_mut(), uc),
-            4   => syscalls::write(exec_env_clone.borrow(), uc),
+            4   => syscalls::write(exec_env_clone.borrow_mut(), uc),
             5   => syscalls::open(exec_env_clone.borrow_mut(), uc),
             6   => syscalls::close(exec_env_clone.borrow_mut(), uc),
             24  => syscalls::getuid(uc),
@@ -0,0 +1,555 @@
+//! Declarative descriptions of structured input formats (eg. TLV packets with a crc trailer). An
+//! input is split into a sequence of records, each made up of the fields listed in the format.
+//! Length fields and checksums are derived from the other fields of their record, so they are
+//! recomputed after every mutation instead of being left broken by it. See `/formats` for example
+//! descriptions.
+
+use serde::Deserialize;
+
+/// Byte-order of multi-byte fields
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+/// Supported checksum algorithms
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Checksum {
+    /// CRC-32 as used by zlib/ethernet
+    Crc32,
+
+    /// CRC-16/CCITT-FALSE
+    Crc16,
+
+    /// Adler-32 as used by zlib
+    Adler32,
+
+    /// Sum of all bytes
+    Sum8,
+
+    /// Xor of all bytes
+    Xor8,
+}
+
+impl Checksum {
+    /// Size in bytes of the checksum value
+    fn size(self) -> usize {
+        match self {
+            Checksum::Crc32 | Checksum::Adler32 => 4,
+            Checksum::Crc16 => 2,
+            Checksum::Sum8 | Checksum::Xor8 => 1,
+        }
+    }
+
+    /// Compute the checksum of `data`
+    fn compute(self, data: &[u8]) -> u64 {
+        match self {
+            Checksum::Crc32 => {
+                let mut crc: u32 = 0xffffffff;
+                for byte in data {
+                    crc ^= *byte as u32;
+                    for _ in 0..8 {
+                        crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+                    }
+                }
+                !crc as u64
+            },
+            Checksum::Crc16 => {
+                let mut crc: u16 = 0xffff;
+                for byte in data {
+                    crc ^= (*byte as u16) << 8;
+                    for _ in 0..8 {
+                        crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+                    }
+                }
+                crc as u64
+            },
+            Checksum::Adler32 => {
+                let (mut a, mut b) = (1u32, 0u32);
+                for byte in data {
+                    a = (a + *byte as u32) % 65521;
+                    b = (b + a) % 65521;
+                }
+                ((b << 16) | a) as u64
+            },
+            Checksum::Sum8 => data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) as u64,
+            Checksum::Xor8 => data.iter().fold(0u8, |acc, b| acc ^ b) as u64,
+        }
+    }
+}
+
+/// The different kinds of fields a record can be made of
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FieldKind {
+    /// Field that always takes up `size` bytes
+    Fixed {
+        size: usize,
+    },
+
+    /// Variable-length field. Its length is taken from the length field that covers it. A data
+    /// field without a length field takes up the rest of the input, except for the fixed-size
+    /// fields that follow it
+    Data,
+
+    /// Integer holding the combined length of the fields listed in `of`, plus `adjust`
+    Length {
+        size: usize,
+        of: Vec<String>,
+        #[serde(default)]
+        endian: Endian,
+        #[serde(default)]
+        adjust: i64,
+    },
+
+    /// Checksum over the fields listed in `over`. Defaults to all fields that precede it. The
+    /// size defaults to the size of the algorithm's result
+    Checksum {
+        algorithm: Checksum,
+        size: Option<usize>,
+        #[serde(default)]
+        over: Vec<String>,
+        #[serde(default)]
+        endian: Endian,
+    },
+}
+
+/// A named field of a record
+#[derive(Debug, Clone, Deserialize)]
+pub struct Field {
+    /// Name used to reference this field from length and checksum fields
+    pub name: String,
+
+    /// What this field holds
+    #[serde(flatten)]
+    pub kind: FieldKind,
+}
+
+/// Description of an input format
+#[derive(Debug, Clone, Deserialize)]
+pub struct InputFormat {
+    /// Fields of a single record, in order
+    pub fields: Vec<Field>,
+
+    /// If set, the input consists of as many records as fit into it. Otherwise the input holds a
+    /// single record and anything after it is left untouched
+    #[serde(default)]
+    pub repeat: bool,
+
+    /// Size of every field that does not have a variable length
+    #[serde(skip)]
+    static_sizes: Vec<Option<usize>>,
+
+    /// Index of the length field covering each data field
+    #[serde(skip)]
+    length_fields: Vec<Option<usize>>,
+
+    /// Indexes of the fields covered by each length/checksum field
+    #[serde(skip)]
+    covered: Vec<Vec<usize>>,
+}
+
+/// The fields of a single record
+pub type Record = Vec<Vec<u8>>;
+
+impl InputFormat {
+    /// Parse an input format description from disk and verify that it is consistent
+    pub fn load(filename: &str) -> Result<Self, String> {
+        let data = std::fs::read(filename).map_err(|e| format!("Failed to read {filename}: {e}"))?;
+        let mut format: InputFormat = serde_json::from_slice(&data)
+            .map_err(|e| format!("Failed to parse {filename}: {e}"))?;
+        format.resolve().map_err(|e| format!("{filename}: {e}"))?;
+        Ok(format)
+    }
+
+    /// Resolve field references and precompute the information required to split up inputs
+    fn resolve(&mut self) -> Result<(), String> {
+        if self.fields.is_empty() {
+            return Err("The format does not have any fields".to_string());
+        }
+
+        let find = |name: &String| self.fields.iter().position(|f| &f.name == name)
+            .ok_or(format!("Unknown field: {name}"));
+
+        let mut static_sizes  = Vec::new();
+        let mut length_fields = vec![None; self.fields.len()];
+        let mut covered       = Vec::new();
+        let mut unsized_data  = 0;
+
+        for (i, field) in self.fields.iter().enumerate() {
+            if self.fields[..i].iter().any(|f| f.name == field.name) {
+                return Err(format!("Duplicate field: {}", field.name));
+            }
+
+            let (size, fields) = match &field.kind {
+                FieldKind::Fixed { size } => (Some(*size), Vec::new()),
+                FieldKind::Data => (None, Vec::new()),
+                FieldKind::Length { size, of, .. } => {
+                    if *size == 0 || *size > 8 {
+                        return Err(format!("Invalid size for length field {}", field.name));
+                    }
+                    (Some(*size), of.iter().map(find).collect::<Result<Vec<usize>, String>>()?)
+                },
+                FieldKind::Checksum { algorithm, size, over, .. } => {
+                    let size = size.unwrap_or_else(|| algorithm.size());
+                    if size == 0 || size > 8 {
+                        return Err(format!("Invalid size for checksum field {}", field.name));
+                    }
+                    let fields = if over.is_empty() {
+                        (0..i).collect()
+                    } else {
+                        over.iter().map(find).collect::<Result<Vec<usize>, String>>()?
+                    };
+                    (Some(size), fields)
+                },
+            };
+
+            // The length of a data field needs to be known by the time it is reached
+            if let FieldKind::Length { .. } = field.kind {
+                for &j in &fields {
+                    if let FieldKind::Data = self.fields[j].kind {
+                        if j < i || length_fields[j].is_some() {
+                            return Err(format!("{} needs to be covered by a single length \
+                                                field that precedes it", self.fields[j].name));
+                        }
+                        length_fields[j] = Some(i);
+                    }
+                }
+            }
+
+            static_sizes.push(size);
+            covered.push(fields);
+        }
+
+        // Remaining data fields take up the rest of the input, so there can only be one of them
+        for (i, field) in self.fields.iter().enumerate() {
+            if let FieldKind::Data = field.kind {
+                if length_fields[i].is_none() {
+                    unsized_data += 1;
+                }
+            }
+        }
+        if unsized_data > 1 {
+            return Err("Only one data field can be without a length field".to_string());
+        }
+
+        // A length field can only cover a single data field, otherwise the data can't be split up
+        for (i, fields) in covered.iter().enumerate() {
+            if let FieldKind::Length { .. } = self.fields[i].kind {
+                if fields.iter().filter(|&&j| static_sizes[j].is_none()).count() > 1 {
+                    return Err(format!("{} covers more than one data field", self.fields[i].name));
+                }
+            }
+        }
+
+        self.static_sizes  = static_sizes;
+        self.length_fields = length_fields;
+        self.covered       = covered;
+        Ok(())
+    }
+
+    /// Returns true if the field at `index` holds data that is not derived from other fields
+    pub fn is_mutable(&self, index: usize) -> bool {
+        matches!(self.fields[index].kind, FieldKind::Fixed { .. } | FieldKind::Data)
+    }
+
+    /// Split `input` into records. This never fails, fields that are cut off by the end of the
+    /// input are truncated, and length fields that point past the end are clamped. Returns the
+    /// records alongside any trailing bytes that are not part of a record
+    pub fn parse(&self, input: &[u8]) -> (Vec<Record>, Vec<u8>) {
+        let mut records = Vec::new();
+        let mut pos = 0;
+
+        while pos < input.len() {
+            let start = pos;
+            let mut record: Record = Vec::with_capacity(self.fields.len());
+
+            for i in 0..self.fields.len() {
+                let remaining = input.len() - pos;
+                let len = match (self.static_sizes[i], self.length_fields[i]) {
+                    (Some(size), _) => size,
+                    (None, Some(len_idx)) => self.covered_data_len(len_idx, &record[len_idx]),
+                    (None, None) => remaining.saturating_sub(self.trailing_size(i)),
+                }.min(remaining);
+
+                record.push(input[pos..pos + len].to_vec());
+                pos += len;
+            }
+            records.push(record);
+
+            if !self.repeat || pos == start {
+                break;
+            }
+        }
+        (records, input[pos..].to_vec())
+    }
+
+    /// Reassemble records into an input. Fixed-size fields are padded or truncated to their size,
+    /// and all length fields and checksums are recomputed. Length fields are computed first, so
+    /// checksums that cover them see the updated values regardless of the order of the fields
+    pub fn serialize(&self, records: &mut [Record], trailer: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+
+        for record in records.iter_mut() {
+            for (i, size) in self.static_sizes.iter().enumerate() {
+                if let Some(size) = size {
+                    record[i].resize(*size, 0);
+                }
+            }
+
+            for (i, field) in self.fields.iter().enumerate() {
+                if let FieldKind::Length { size, endian, adjust, .. } = &field.kind {
+                    let len: usize = self.covered[i].iter().map(|&j| record[j].len()).sum();
+                    let val = (len as i64 + adjust) as u64;
+                    record[i] = encode(val, *size, *endian);
+                }
+            }
+
+            for (i, field) in self.fields.iter().enumerate() {
+                if let FieldKind::Checksum { algorithm, endian, .. } = &field.kind {
+                    let data: Vec<u8> = self.covered[i].iter()
+                        .flat_map(|&j| record[j].iter().copied())
+                        .collect();
+                    record[i] = encode(algorithm.compute(&data), record[i].len(), *endian);
+                }
+            }
+            record.iter().for_each(|field| output.extend_from_slice(field));
+        }
+        output.extend_from_slice(trailer);
+        output
+    }
+
+    /// Recompute the length fields and checksums of `input` in place
+    pub fn fix(&self, input: &mut Vec<u8>) {
+        let (mut records, trailer) = self.parse(input);
+        *input = self.serialize(&mut records, &trailer);
+    }
+
+    /// Length of the data field covered by the length field at `len_idx`, based on its current
+    /// `value`
+    fn covered_data_len(&self, len_idx: usize, value: &[u8]) -> usize {
+        let (endian, adjust) = match &self.fields[len_idx].kind {
+            FieldKind::Length { endian, adjust, .. } => (*endian, *adjust),
+            _ => unreachable!(),
+        };
+        let static_len: usize = self.covered[len_idx].iter()
+            .filter_map(|&j| self.static_sizes[j])
+            .sum();
+        // Computed in 128 bits so huge length values are clamped instead of wrapping around
+        let total = (decode(value, endian) as i128 - adjust as i128).clamp(0, usize::MAX as i128);
+        (total as usize).saturating_sub(static_len)
+    }
+
+    /// Combined size of the fields following the field at `index`, all of which have a fixed size
+    fn trailing_size(&self, index: usize) -> usize {
+        self.static_sizes[index + 1..].iter().map(|size| size.unwrap_or(0)).sum()
+    }
+}
+
+/// Encode the lower `size` bytes of `val`
+fn encode(val: u64, size: usize, endian: Endian) -> Vec<u8> {
+    match endian {
+        Endian::Little => val.to_le_bytes()[..size].to_vec(),
+        Endian::Big    => val.to_be_bytes()[8 - size..].to_vec(),
+    }
+}
+
+/// Decode an integer of up to 8 bytes
+fn decode(bytes: &[u8], endian: Endian) -> u64 {
+    match endian {
+        Endian::Little => bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64),
+        Endian::Big    => bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Type-length-value records followed by a crc32 over the entire record
+    const TLV: &str = r#"{
+        "repeat": true,
+        "fields": [
+            { "name": "type", "type": "fixed", "size": 1 },
+            { "name": "len",  "type": "length", "size": 2, "of": ["data"] },
+            { "name": "data", "type": "data" },
+            { "name": "crc",  "type": "checksum", "algorithm": "crc32" }
+        ]
+    }"#;
+
+    fn format(desc: &str) -> InputFormat {
+        let mut format: InputFormat = serde_json::from_str(desc).unwrap();
+        format.resolve().unwrap();
+        format
+    }
+
+    /// Build a valid TLV record
+    fn tlv(typ: u8, data: &[u8]) -> Vec<u8> {
+        let mut record = vec![typ];
+        record.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        record.extend_from_slice(data);
+        let crc = Checksum::Crc32.compute(&record) as u32;
+        record.extend_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    #[test]
+    fn checksum_vectors() {
+        assert_eq!(Checksum::Crc32.compute(b"123456789"), 0xcbf43926);
+        assert_eq!(Checksum::Crc16.compute(b"123456789"), 0x29b1);
+        assert_eq!(Checksum::Adler32.compute(b"123456789"), 0x091e01de);
+        assert_eq!(Checksum::Sum8.compute(b"123456789"), 0xdd);
+        assert_eq!(Checksum::Xor8.compute(b"123456789"), 0x31);
+
+        assert_eq!(Checksum::Crc32.compute(b""), 0);
+        assert_eq!(Checksum::Crc16.compute(b""), 0xffff);
+        assert_eq!(Checksum::Adler32.compute(b""), 1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let format = format(TLV);
+        let mut input = tlv(1, b"hello");
+        input.extend_from_slice(&tlv(2, b""));
+        input.extend_from_slice(&tlv(3, b"world!"));
+
+        let (mut records, trailer) = format.parse(&input);
+        assert_eq!(records.len(), 3);
+        assert!(trailer.is_empty());
+        assert_eq!(records[0], vec![vec![1], vec![5, 0], b"hello".to_vec(),
+                                    tlv(1, b"hello")[8..].to_vec()]);
+        assert_eq!(records[1][2], b"");
+        assert_eq!(records[2][2], b"world!");
+        assert_eq!(format.serialize(&mut records, &trailer), input);
+
+        let mut fixed = input.clone();
+        format.fix(&mut fixed);
+        assert_eq!(fixed, input);
+    }
+
+    #[test]
+    fn fix_after_mutation() {
+        let format = format(TLV);
+        let (mut records, trailer) = format.parse(&tlv(7, b"abc"));
+        records[0][2] = b"abcdefgh".to_vec();
+        assert_eq!(format.serialize(&mut records, &trailer), tlv(7, b"abcdefgh"));
+    }
+
+    #[test]
+    fn truncated_input() {
+        let format = format(TLV);
+
+        // Input ends within the length field
+        let mut input = vec![9, 5];
+        format.fix(&mut input);
+        assert_eq!(input, tlv(9, b""));
+
+        // Input ends within the checksum
+        let mut input = tlv(9, b"abc");
+        input.truncate(input.len() - 2);
+        format.fix(&mut input);
+        assert_eq!(input, tlv(9, b"abc"));
+    }
+
+    #[test]
+    fn over_long_length() {
+        let format = format(TLV);
+
+        // The data is clamped to the end of the input, leaving nothing for the checksum
+        let mut input = vec![4, 0xff, 0xff, b'x', b'y', b'z'];
+        let (records, trailer) = format.parse(&input);
+        assert_eq!(records, vec![vec![vec![4], vec![0xff, 0xff], b"xyz".to_vec(), vec![]]]);
+        assert!(trailer.is_empty());
+        format.fix(&mut input);
+        assert_eq!(input, tlv(4, b"xyz"));
+
+        // Values that don't fit into a signed integer are clamped as well
+        let format = self::format(r#"{ "fields": [
+            { "name": "len",  "type": "length", "size": 8, "of": ["data"], "endian": "big" },
+            { "name": "data", "type": "data" }
+        ] }"#);
+        let (records, _) = format.parse(&[0xff; 12]);
+        assert_eq!(records[0][1], vec![0xff; 4]);
+    }
+
+    #[test]
+    fn short_length_splits_records() {
+        let format = format(TLV);
+        let mut input = tlv(1, b"abcdef");
+        input[1] = 2;
+
+        let (records, trailer) = format.parse(&input);
+        assert_eq!(records[0][2], b"ab");
+        assert_eq!(records[0][3], b"cdef");
+        assert_eq!(records.len(), 2);
+        assert_eq!(trailer, b"");
+    }
+
+    #[test]
+    fn trailer_is_kept() {
+        let format = format(r#"{ "fields": [
+            { "name": "magic", "type": "fixed", "size": 2 },
+            { "name": "len",   "type": "length", "size": 1, "of": ["magic", "data"], "adjust": 1 },
+            { "name": "data",  "type": "data" }
+        ] }"#);
+
+        let mut input = b"MZ\x04abcTRAILER".to_vec();
+        let (records, trailer) = format.parse(&input);
+        assert_eq!(records, vec![vec![b"MZ".to_vec(), vec![4], b"a".to_vec()]]);
+        assert_eq!(trailer, b"bcTRAILER");
+
+        format.fix(&mut input);
+        assert_eq!(input, b"MZ\x04abcTRAILER");
+    }
+
+    #[test]
+    fn checksum_covers_later_length() {
+        let format = format(r#"{ "fields": [
+            { "name": "crc",  "type": "checksum", "algorithm": "crc16", "over": ["len", "data"],
+              "endian": "big" },
+            { "name": "len",  "type": "length", "size": 1, "of": ["data"] },
+            { "name": "data", "type": "data" }
+        ] }"#);
+
+        let mut records = vec![vec![vec![0, 0], vec![0], b"abcd".to_vec()]];
+        let output = format.serialize(&mut records, &[]);
+
+        let crc = Checksum::Crc16.compute(b"\x04abcd") as u16;
+        let mut expected = crc.to_be_bytes().to_vec();
+        expected.extend_from_slice(b"\x04abcd");
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn invalid_formats() {
+        let resolve = |desc: &str| {
+            let mut format: InputFormat = serde_json::from_str(desc).unwrap();
+            format.resolve()
+        };
+
+        assert!(resolve(r#"{ "fields": [] }"#).is_err());
+        assert!(resolve(r#"{ "fields": [
+            { "name": "a", "type": "data" },
+            { "name": "b", "type": "data" }
+        ] }"#).is_err());
+        assert!(resolve(r#"{ "fields": [
+            { "name": "a", "type": "fixed", "size": 1 },
+            { "name": "a", "type": "fixed", "size": 1 }
+        ] }"#).is_err());
+        assert!(resolve(r#"{ "fields": [
+            { "name": "data", "type": "data" },
+            { "name": "len",  "type": "length", "size": 1, "of": ["data"] }
+        ] }"#).is_err());
+        assert!(resolve(r#"{ "fields": [
+            { "name": "len", "type": "length", "size": 9, "of": ["x"] },
+            { "name": "x",   "type": "data" }
+        ] }"#).is_err());
+        assert!(resolve(r#"{ "fields": [
+            { "name": "len", "type": "length", "size": 1, "of": ["missing"] }
+        ] }"#).is_err());
+    }
+}
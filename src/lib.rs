@@ -11,25 +11,54 @@ pub mod execution_state;
 pub mod hooks;
 pub mod arg_setup;
 pub mod configurables;
+pub mod campaign_state;
+pub mod triage;
+pub mod repro;
+pub mod cmin;
+pub mod tmin;
+pub mod power_schedule;
+pub mod culling;
+pub mod auto_dict;
+pub mod mopt;
+pub mod input_format;
 pub mod grammar_mut;
+pub mod grammar_tree;
+pub mod grammar_interp;
+pub mod snapshot_file;
+pub mod snapshot_image;
 pub mod targets {
     pub mod target_1;
     pub mod target_2;
     pub mod targets;
+    pub mod harness_config;
 }
 
 use crate::{
-    execution_state::{ExecEnv, SnapshotContext, FileType, File},
-    targets::targets::TARGET_INIT_FUNCTIONS,
-    mutator::Mutator,
+    execution_state::{ExecEnv, SnapshotContext, NestedSnapshot, FileType, File, take_snapshot},
+    targets::targets::{init_target, insert_target_hooks},
+    mutator::{create_mutator, build_i2s_tokens},
     grammar_mut::GrammarMut,
-    arg_setup::OUTPUT_DIR,
+    grammar_tree::{DerivationTree, TreeGrammar, mutate_tree},
+    grammar_interp::GrammarInterp,
+    arg_setup::{OUTPUT_DIR, GRAMMAR_FILE},
     hooks::{
         insert_ld_st_boundcheck_hook,
         insert_dirty_page_tracking_hook,
         insert_coverage_hook,
+        insert_cmp_hook,
+        update_coverage,
+        insert_call_stack_hook,
+        insert_invalid_mem_hook,
+        insert_nested_snapshot_hook,
     },
-    configurables::{DEBUG, COVMAP_SIZE, SEED_ENERGY, FUZZ_INPUT, MUTATOR, MutType},
+    triage::{CrashInfo, crash_bucket, crash_pc, count_instructions},
+    power_schedule::{CorpusStats, calibrate, assign_energy},
+    culling::TopRated,
+    auto_dict::{TokenTable, extract_static_tokens},
+    mopt::OperatorStats,
+    snapshot_file::report_loaded_snapshots,
+    configurables::{DEBUG, COVMAP_SIZE, SEED_ENERGY, FUZZ_INPUT, MUTATOR, MutType,
+        SKIP_NON_FAVORED, SKIP_NEW_NON_FAVORED},
     targets::targets::HarnessInit,
 };
 
@@ -38,13 +67,15 @@ use serde::{Deserialize, Serialize};
 use rustc_hash::FxHashMap;
 use parking_lot::RwLock;
 use fasthash::{xx::Hash32, FastHash};
+use rand::Rng;
+use rand_xoshiro::{Xoroshiro64Star, rand_core::{RngCore, SeedableRng}};
 use unicorn_engine::{
     Unicorn, RegisterX86,
     unicorn_const::{Permission, uc_error, Arch, Mode},
 };
 
 use std::{
-    sync::atomic::AtomicUsize,
+    sync::atomic::{AtomicUsize, AtomicU8},
     collections::BTreeMap,
     sync::mpsc::Sender,
     cell::RefCell,
@@ -52,6 +83,7 @@ use std::{
     rc::Rc,
     process,
     path::Path,
+    time::Instant,
 };
 
 /// Small wrapper to easily handle unrecoverable errors without panicking
@@ -67,19 +99,48 @@ pub fn dbg_print(msg: &str) {
     }
 }
 
-/// Thread-specific worker function that executes fuzz-cases, handles their return values, and
-/// transmits statistics to the main function. This is where most of the interesting work happens
-pub fn worker(harness_init: &HarnessInit, snapshot: Arc<SnapshotContext>, 
-              all_shared: Arc<AllShared>, target_shared: Arc<TargetShared>, 
-              tx: Sender<Statistics>) -> ! {
+/// Mutator type used for this run. Passing a grammar via `-g` switches to grammar-based generation
+/// without having to change `MUTATOR`
+pub fn mut_type() -> MutType {
+    match GRAMMAR_FILE.get() {
+        Some(Some(_)) => MutType::Gen,
+        _ => MUTATOR,
+    }
+}
 
-    // Create execution environment and unicorn engine
-    let (exec_env, mut unicorn) = TARGET_INIT_FUNCTIONS[harness_init.target_id]()
+/// Initialize the target in a temporary exec_env/unicorn engine to create the initial
+/// memory/file/context snapshot that all fuzz-cases of this target are reset to
+pub fn create_snapshot(harness_init: &HarnessInit) -> SnapshotContext {
+    let (exec_env, unicorn) = init_target(harness_init.target_id)
                                     .expect("Failed to initialize one of the fuzz-targets");
+    report_loaded_snapshots();
+    take_snapshot(&exec_env, &unicorn)
+        .unwrap_or_else(|_| panic!("Failed to snapshot target: {}", harness_init.target_id))
+}
 
-    // These are allocations made during setup. These should only include regions such as the
-    // stack, and argv regions and should thus never be free'd anyways
-    exec_env.borrow_mut().heap_allocations = FxHashMap::default();
+/// Create an execution environment and unicorn engine for the target described by 
+/// `harness_init` from its `snapshot`, and insert the hooks that are required for fuzzing. The
+/// target is not initialized again, its memory is mapped copy-on-write from the snapshot image
+pub fn setup_fuzz_env(harness_init: &HarnessInit, target_shared: &Arc<TargetShared>,
+                      snapshot: &SnapshotContext) -> (Rc<RefCell<ExecEnv>>, Unicorn<'static, ()>) {
+    // Create execution environment and unicorn engine
+    let (exec_env, mut unicorn) = snapshot.create_engine().unwrap_or_else(|err| {
+        error_exit(&format!("Failed to create an emulator from the snapshot: {err:?}"));
+    });
+
+    // Insert the hooks of the target's harness
+    insert_target_hooks(harness_init.target_id, &exec_env, &mut unicorn)
+        .expect("Failed to insert the target's hooks");
+
+    // Extract tokens from the target binary into the automatically generated dictionary. This
+    // only has to be done once per target
+    if harness_init.auto_dict {
+        let mut tokens = target_shared.tokens.write();
+        if !tokens.static_loaded {
+            extract_static_tokens(&unicorn).iter().for_each(|token| { tokens.insert(token); });
+            tokens.static_loaded = true;
+        }
+    }
 
     // Insert required hooks
     {
@@ -92,18 +153,210 @@ pub fn worker(harness_init: &HarnessInit, snapshot: Arc<SnapshotContext>,
         insert_dirty_page_tracking_hook(&exec_env, &mut unicorn)
             .expect("Failed to insert dirty-page tracking hook");
 
-        // Grammar based fuzzing is currently still entirely generational and not mutational, so
-        // only enable coverage tracking for mutational fuzzing
-        if MUTATOR == MutType::Mut {
-            insert_coverage_hook(&exec_env, &mut unicorn, &target_shared).unwrap();
+        // Records faulting accesses that unicorn catches itself for crash triage
+        insert_invalid_mem_hook(&exec_env, &mut unicorn)
+            .expect("Failed to insert invalid memory hook");
+
+        // Maintains a shadow call-stack that is used to bucket crashes
+        insert_call_stack_hook(&exec_env, &mut unicorn)
+            .expect("Failed to insert call-stack tracking hook");
+
+        // Coverage is tracked in both modes, grammar inputs that find new coverage are added to
+        // the corpus alongside their derivation tree
+        insert_coverage_hook(&exec_env, &mut unicorn, target_shared).unwrap();
+
+        // Stops the cases that are used to take nested snapshots
+        if let Some(addr) = harness_init.nested_snapshot {
+            insert_nested_snapshot_hook(&exec_env, &mut unicorn, addr)
+                .expect("Failed to insert nested snapshot hook");
+        }
+
+        // Comparison instrumentation is opt-in per target since it is rather expensive
+        if harness_init.cmpcov || harness_init.input_to_state {
+            insert_cmp_hook(&exec_env, &mut unicorn, target_shared, harness_init.cmpcov)
+                .expect("Failed to insert comparison hook");
+        }
+    }
+
+    (exec_env, unicorn)
+}
+
+/// Run a single fuzz-case using the input that is currently stored in `exec_env.fuzz_input`. The
+/// emulator needs to have been reset to the snapshot before calling this. Once the case finished,
+/// the edge hit-counts it collected are merged into the coverage-map of `target_shared`
+pub fn run_case(harness_init: &HarnessInit, target_shared: &TargetShared, 
+                exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>) 
+        -> Result<(), uc_error> {
+    // Run emulator and execute fuzz case until completion or a timeout is hit
+    let mut case_res = unicorn.emu_start(
+        unicorn.get_pc().unwrap(), 
+        0, 
+        harness_init.time_timeout, 
+        harness_init.instr_timeout, 
+        );
+
+    // This means a syscall caused a crash so we overwrite the case-result with the fault
+    // that occured during the syscall (since syscalls can't directly return errors, these
+    // are communicated through the error_flag)
+    if exec_env.borrow().error_flag != uc_error::OK {
+        case_res = Err(exec_env.borrow().error_flag);
+    }
+
+    if unicorn.check_timeout() && exec_env.borrow().error_flag == uc_error::OK {
+        case_res = Err(uc_error::TIMEOUT);
+    }
+
+    // Classify edge hit-counts and check if this case found new coverage
+    update_coverage(exec_env, target_shared);
+
+    case_res
+}
+
+/// Run `input` once with comparison-logging enabled and return the logged comparison operands.
+/// The emulator is reset to the snapshot before the case is run
+pub fn log_comparisons(harness_init: &HarnessInit, target_shared: &TargetShared, 
+                       exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>,
+                       snapshot: &SnapshotContext, input: &[u8]) -> Vec<(u64, u64, usize)> {
+    let mut emu_ref = exec_env.borrow_mut();
+    emu_ref.reset_snapshot(unicorn, snapshot).unwrap();
+    emu_ref.fuzz_input.clear();
+    emu_ref.fuzz_input.extend_from_slice(input);
+    emu_ref.trace_cmps = true;
+    drop(emu_ref);
+
+    // The seed was already run before it was added to the corpus, so the result is not relevant
+    let _ = run_case(harness_init, target_shared, exec_env, unicorn);
+
+    let mut emu_ref = exec_env.borrow_mut();
+    emu_ref.trace_cmps = false;
+    std::mem::take(&mut emu_ref.cmp_log)
+}
+
+/// Run `input` from the root snapshot until it reaches the nested snapshot address of the target,
+/// and take a nested snapshot there. Returns `None` if the input never reaches the address, or if
+/// the target already depends on the entire input at that point
+pub fn create_nested_snapshot(harness_init: &HarnessInit, target_shared: &TargetShared,
+                              exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>,
+                              snapshot: &SnapshotContext, input: &[u8]) -> Option<NestedSnapshot> {
+    let mut emu_ref = exec_env.borrow_mut();
+    emu_ref.reset_snapshot(unicorn, snapshot).unwrap();
+    emu_ref.fuzz_input.clear();
+    emu_ref.fuzz_input.extend_from_slice(input);
+    emu_ref.stop_at_nested = true;
+    drop(emu_ref);
+
+    let case_res = run_case(harness_init, target_shared, exec_env, unicorn);
+
+    let mut emu_ref = exec_env.borrow_mut();
+    emu_ref.stop_at_nested = false;
+    if case_res.is_err() || !emu_ref.nested_reached || emu_ref.input_consumed >= input.len() {
+        return None;
+    }
+    Some(emu_ref.take_nested_snapshot(unicorn).unwrap())
+}
+
+/// Run every input of the corpus through the target once to rebuild the coverage-map after
+/// resuming a previous fuzzing campaign. The coverage, execution time and instruction count of
+/// every input are recorded during this run, so the power schedule does not treat recovered inputs
+/// as if they had not contributed anything
+pub fn replay_corpus(harness_init: &HarnessInit, snapshot: &SnapshotContext, 
+                     target_shared: &Arc<TargetShared>, all_shared: &AllShared) {
+    let (exec_env, mut unicorn) = setup_fuzz_env(harness_init, target_shared, snapshot);
+    let corpus: Vec<Vec<u8>> = all_shared.inputs.read().iter()
+        .map(|input| input.data.clone())
+        .collect();
+
+    for (index, data) in corpus.iter().enumerate() {
+        // Crashes and timeouts were already recorded during the previous run, so the result is
+        // not relevant here
+        let (exec_time, instr_count, cov_count) = calibrate(harness_init, target_shared,
+                &exec_env, &mut unicorn, snapshot, data);
+
+        // Inputs that were already replayed for another target keep their measurements, the same
+        // way they are only calibrated once while fuzzing
+        let mut inputs = all_shared.inputs.write();
+        if !inputs[index].calibrated {
+            inputs[index].exec_time   = exec_time;
+            inputs[index].instr_count = instr_count;
+            inputs[index].coverage   += cov_count;
+            inputs[index].calibrated  = true;
+            all_shared.corpus_stats.write().add_calibration(exec_time, instr_count, cov_count);
         }
+        drop(inputs);
+
+        // Rank the input so the favored set is available right away
+        rate_input(target_shared, &exec_env.borrow(), index, data.len(), exec_time);
     }
 
-    // Initialize the simple mutational mutator
-    let mut mutator = Mutator::default();
+    // Reset the emulator so no state from the last case leaks out
+    exec_env.borrow_mut().reset_snapshot(&mut unicorn, snapshot).unwrap();
+}
+
+/// Check if the input at `index` that was just run in `exec_env` is the best input for any of the
+/// edges it covered. Smaller and faster inputs are preferred
+pub fn rate_input(target_shared: &TargetShared, exec_env: &ExecEnv, index: usize, len: usize,
+                  exec_time: f64) {
+    let edges = exec_env.edge_hits.edges();
+    target_shared.top_rated.write().update(index, len.max(1) as f64 * exec_time, edges);
+}
+
+/// Run `trimmed`, a shrunk version of the input in `exec_env.fuzz_input` that just finished with
+/// `case_res`, and replace the input with it if it has the same result and takes the same edges.
+/// Otherwise the original input and its edge hit-counts are restored
+fn apply_trim(harness_init: &HarnessInit, target_shared: &TargetShared,
+              exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>,
+              snapshot: &SnapshotContext, trimmed: Vec<u8>, case_res: Result<(), uc_error>) {
+    let (original, edge_hits, cov_count) = {
+        let emu_ref = exec_env.borrow();
+        let edge_hits: Vec<(u64, u8)> = emu_ref.edge_hits.iter().collect();
+        (emu_ref.fuzz_input.clone(), edge_hits, emu_ref.cov_count)
+    };
+
+    let mut emu_ref = exec_env.borrow_mut();
+    emu_ref.reset_snapshot(unicorn, snapshot).unwrap();
+    emu_ref.fuzz_input = trimmed;
+    drop(emu_ref);
+
+    let trim_res = run_case(harness_init, target_shared, exec_env, unicorn);
+
+    let mut emu_ref = exec_env.borrow_mut();
+    let same_edges = emu_ref.edge_hits.len() == edge_hits.len() &&
+        edge_hits.iter().all(|&(edge, _)| emu_ref.edge_hits.contains(edge));
+    if trim_res != case_res || !same_edges {
+        emu_ref.fuzz_input = original;
+        emu_ref.edge_hits.restore(&edge_hits);
+    }
+    emu_ref.cov_count = cov_count;
+}
 
-    // Initialize the grammar generator
-    let mut grammar_mutator = GrammarMut::default();
+/// Thread-specific worker function that executes fuzz-cases, handles their return values, and
+/// transmits statistics to the main function. This is where most of the interesting work happens
+pub fn worker(harness_init: &HarnessInit, snapshot: Arc<SnapshotContext>, 
+              all_shared: Arc<AllShared>, target_shared: Arc<TargetShared>, 
+              tx: Sender<Statistics>) -> ! {
+
+    // Create execution environment and unicorn engine with all hooks required for fuzzing. The
+    // memory is backed by copy-on-write mappings of the shared snapshot image, so workers only
+    // hold private copies of the pages they write to
+    let (exec_env, mut unicorn) = setup_fuzz_env(harness_init, &target_shared, &snapshot);
+
+    // Initialize the mutator that was selected for this target
+    let mut mutator = create_mutator(harness_init);
+
+    // Number of tokens of the automatically generated dictionary the mutator currently knows about
+    let mut num_auto_tokens = 0;
+
+    // Initialize the grammar generator. A grammar passed in via `-g` is interpreted, otherwise
+    // the generator compiled into `grammar_mut.rs` is used
+    let mut_type = mut_type();
+    let mut grammar_mutator: Box<dyn TreeGrammar> = match GRAMMAR_FILE.get().unwrap() {
+        Some(grammar) => Box::new(GrammarInterp::load(grammar)
+            .unwrap_or_else(|err| error_exit(&err))),
+        None => Box::new(GrammarMut::default()),
+    };
+
+    // Used to decide which inputs that are not favored are skipped
+    let mut rng = Xoroshiro64Star::seed_from_u64(rand::thread_rng().gen());
 
     // Locally count some statistics until they are eventually transmitted to main thread
     let mut local_total_crashes       = 0;
@@ -116,57 +369,193 @@ pub fn worker(harness_init: &HarnessInit, snapshot: Arc<SnapshotContext>,
     let mut input_index = 0;
 
     loop {
-        // Chose an input from the queue to use for the next `SEED_ENERGY` cases
+        // Chose an input from the queue to use for the next batch of cases
         input_index = (input_index + 1) % all_shared.inputs.read().len();
 
-        for _ in 0..SEED_ENERGY {
-            // Reset the emulator state
+        // Inputs that are not favored are mostly skipped once favored inputs exist. The favored
+        // set is recomputed first if new top-rated inputs were found since the last cull
+        if mut_type == MutType::Mut {
+            if target_shared.top_rated.read().needs_cull() {
+                target_shared.top_rated.write().cull();
+            }
+
+            let skip_prob = if all_shared.inputs.read()[input_index].times_fuzzed == 0 {
+                SKIP_NEW_NON_FAVORED
+            } else {
+                SKIP_NON_FAVORED
+            };
+
+            let top_rated = target_shared.top_rated.read();
+            if top_rated.num_favored() != 0 && !top_rated.is_favored(input_index) &&
+                    rng.next_u32() % 100 < skip_prob {
+                continue;
+            }
+        }
+
+        // Seeds are calibrated the first time they are picked so the power schedule knows how
+        // expensive they are to run. Initial seeds also find their coverage during this run
+        if mut_type == MutType::Mut && !all_shared.inputs.read()[input_index].calibrated {
+            let seed = all_shared.inputs.read()[input_index].data.clone();
+            let (exec_time, instr_count, cov_count) = calibrate(harness_init, &target_shared,
+                    &exec_env, &mut unicorn, &snapshot, &seed);
+            local_coverage_count += cov_count;
+
+            let mut inputs = all_shared.inputs.write();
+            inputs[input_index].exec_time   = exec_time;
+            inputs[input_index].instr_count = instr_count;
+            inputs[input_index].coverage   += cov_count;
+            inputs[input_index].calibrated  = true;
+            all_shared.corpus_stats.write().add_calibration(exec_time, instr_count, cov_count);
+            drop(inputs);
+
+            rate_input(&target_shared, &exec_env.borrow(), input_index, seed.len(), exec_time);
+        }
+
+        // Merge the `strcmp`/`memcmp` operands observed since the last seed into the dictionary of
+        // the target, and hand the mutator an updated copy if it grew
+        if harness_init.auto_dict {
+            let observed = std::mem::take(&mut exec_env.borrow_mut().observed_tokens);
+            if !observed.is_empty() {
+                let mut tokens = target_shared.tokens.write();
+                observed.iter().for_each(|token| { tokens.insert(token); });
+            }
+
+            let tokens = target_shared.tokens.read();
+            if tokens.len() != num_auto_tokens {
+                num_auto_tokens = tokens.len();
+                mutator.set_auto_tokens(tokens.tokens().to_vec());
+            }
+        }
+
+        // Decide how many cases to run with this seed, and remember its depth so inputs derived
+        // from it can be placed one level deeper
+        let (energy, parent_depth) = match mut_type {
+            MutType::Gen => (SEED_ENERGY, 0),
+            MutType::Mut => {
+                let inputs = all_shared.inputs.read();
+                let seed = &inputs[input_index];
+                (assign_energy(seed, &all_shared.corpus_stats.read()), seed.depth)
+            },
+        };
+
+        // Log the comparisons the seed performs and turn operands that can be found in the seed
+        // into input-to-state replacements for the mutator
+        if harness_init.input_to_state && mut_type == MutType::Mut {
+            let seed = all_shared.inputs.read()[input_index].data.clone();
+            let cmp_log = log_comparisons(harness_init, &target_shared, &exec_env, &mut unicorn, 
+                                          &snapshot, &seed);
+            mutator.set_i2s_tokens(build_i2s_tokens(&seed, &cmp_log));
+        }
+
+        // Claim the next range of deterministic steps for this seed. The cursor is advanced right
+        // away so other threads that pick the same seed continue where this batch ends
+        let (det_start, det_end) = if harness_init.deterministic && mut_type == MutType::Mut {
+            let mut inputs = all_shared.inputs.write();
+            let seed = &mut inputs[input_index];
+            if seed.det_done {
+                (0, 0)
+            } else {
+                let total = mutator.deterministic_steps(seed.data.len());
+                let start = seed.det_cursor;
+                seed.det_cursor = (start + energy).min(total);
+                seed.det_done   = seed.det_cursor == total;
+                (start, seed.det_cursor)
+            }
+        } else {
+            (0, 0)
+        };
+
+        // Take a nested snapshot at the point the seed reaches the nested snapshot address. The
+        // cases of this batch only mutate the input consumed after it, and resume from there
+        let mut nested_seed = match harness_init.nested_snapshot {
+            Some(_) if mut_type == MutType::Mut => {
+                Some(all_shared.inputs.read()[input_index].data.clone())
+            },
+            _ => None,
+        };
+        let mut nested: Option<NestedSnapshot> = None;
+
+        for case in 0..energy {
+            // Triage, trimming and cases that modified the prefix rerun inputs from the root
+            // snapshot, which discards the nested snapshot, so it has to be retaken. Seeds that
+            // never reach the nested snapshot address are run from the root snapshot
+            if let Some(seed) = &nested_seed {
+                if !exec_env.borrow().nested_active {
+                    nested = create_nested_snapshot(harness_init, &target_shared, &exec_env,
+                                                    &mut unicorn, &snapshot, seed);
+                    if nested.is_none() {
+                        nested_seed = None;
+                    }
+                }
+            }
+
             let mut emu_ref = exec_env.borrow_mut();
-            emu_ref.reset_snapshot(&mut unicorn, &snapshot).unwrap();
+
+            // Derivation tree of the current case when using the grammar generator
+            let mut case_tree: Option<DerivationTree> = None;
 
             // Reset the emulators current fuzz-input and fill it with data generated by one of the
             // mutators
             emu_ref.fuzz_input.clear();
-            match MUTATOR {
+            match mut_type {
                 MutType::Gen => {
-                    emu_ref.fuzz_input.extend_from_slice(&grammar_mutator.generate_input());
-                    // Every 5 cases, add some random corruption to the input generated by the grammar
-                    // mutator. This might uncover some weird edge-cases
-                    if (SEED_ENERGY % 5) == 0 {
-                        mutator.mutate(&mut emu_ref.fuzz_input);
+                    // Seeds that were generated from the grammar are mutated on their derivation
+                    // tree, for all other seeds a new input is generated
+                    let corpus = all_shared.inputs.read();
+                    let tree = match &corpus[input_index].tree {
+                        Some(seed) => mutate_tree(grammar_mutator.as_mut(), seed, &corpus),
+                        None => DerivationTree::generate(grammar_mutator.as_mut()),
+                    };
+                    emu_ref.fuzz_input.extend_from_slice(&tree.unparse(grammar_mutator.as_ref()));
+                    case_tree = Some(tree);
+
+                    // Every 5 cases, add some random corruption to the input generated by the
+                    // grammar mutator. This might uncover some weird edge-cases. These inputs no
+                    // longer match their tree, so it is dropped
+                    if case % 5 == 0 {
+                        mutator.mutate(&mut emu_ref.fuzz_input, &corpus);
+                        case_tree = None;
                     }
                 },
                 MutType::Mut => {
-                    emu_ref.fuzz_input.extend_from_slice(&all_shared.inputs.read()[input_index].data);
-                    // Mutate the chosen seed
-                    mutator.mutate(&mut emu_ref.fuzz_input);
+                    let corpus = all_shared.inputs.read();
+                    emu_ref.fuzz_input.extend_from_slice(&corpus[input_index].data);
+                    // Mutate the chosen seed. Deterministic steps that were claimed for this
+                    // batch are run first
+                    if det_start + case < det_end {
+                        mutator.deterministic_mutate(&mut emu_ref.fuzz_input, det_start + case);
+                    } else if let Some(nested) = &nested {
+                        let mut suffix = emu_ref.fuzz_input.split_off(nested.input_prefix.len());
+                        mutator.mutate(&mut suffix, &corpus);
+                        emu_ref.fuzz_input.extend_from_slice(&suffix);
+                    } else {
+                        mutator.mutate(&mut emu_ref.fuzz_input, &corpus);
+                    }
+                },
+            }
+            mutator.post_process(&mut emu_ref.fuzz_input);
+
+            // Reset the emulator state. Inputs that still start with the prefix the seed consumed
+            // before the nested snapshot are resumed from it. If the prefix was modified (eg. by
+            // deterministic steps or input format fixups), only this case is run from the root
+            // snapshot
+            match &nested {
+                Some(nested) if emu_ref.fuzz_input.starts_with(&nested.input_prefix) => {
+                    emu_ref.reset_nested_snapshot(&mut unicorn, &snapshot, nested).unwrap();
                 },
+                _ => emu_ref.reset_snapshot(&mut unicorn, &snapshot).unwrap(),
             }
             drop(emu_ref);
 
             // Run emulator and execute fuzz case until completion or a timeout is hit
-            let mut case_res = unicorn.emu_start(
-                unicorn.get_pc().unwrap(), 
-                0, 
-                harness_init.time_timeout, 
-                harness_init.instr_timeout, 
-                );
-
-            // This means a syscall caused a crash so we overwrite the case-result with the fault
-            // that occured during the syscall (since syscalls can't directly return errors, these
-            // are communicated through the error_flag)
-            if exec_env.borrow().error_flag != uc_error::OK {
-                case_res = Err(exec_env.borrow().error_flag);
-            }
+            let case_start = Instant::now();
+            let case_res = run_case(harness_init, &target_shared, &exec_env, &mut unicorn);
+            let case_time = case_start.elapsed().as_secs_f64() * 1_000_000.0;
 
-            if unicorn.check_timeout() && exec_env.borrow().error_flag == uc_error::OK {
-                case_res = Err(uc_error::TIMEOUT);
-            }
-
-            // If a crash occured, check if it is a unique crash (different pc from previous 
-            // crashes), and if so, save the crashing input to disk
+            // If a crash occured, check if it is a unique crash (different call-stack from 
+            // previous crashes), and if so, save the crashing input to disk
             if let Err(err) = case_res {
-                let mut pc = unicorn.get_pc().unwrap() as usize;
+                let pc = unicorn.get_pc().unwrap() as usize;
                 match err {
                     uc_error::INSN_INVALID => {
                         // Unicorn does not have great support for simd instruction sets
@@ -204,53 +593,29 @@ pub fn worker(harness_init: &HarnessInit, snapshot: Arc<SnapshotContext>,
                         let mut crash_map = target_shared.crash_mapping.write();
                         local_total_crashes += 1;
 
-                        // For some reason the pc from these crashes isn't properly propagated so
-                        // a separate pc variable is set for them
-                        if err == uc_error::NOMEM ||
-                           err == uc_error::DOUBLE_FREE ||
-                           err == uc_error::OOB_FREE {
-                               pc = unicorn.crash_pc() as usize;
-                        }
+                        // For some reason the pc from allocator crashes isn't properly propagated 
+                        // so a separate crash-pc is used for them
+                        let pc = crash_pc(&unicorn, err);
 
-                        // This checks if this is is a unique crash or one we have seen before
-                        if crash_map.get(&pc).is_some() {
+                        // Crashes are bucketed based on the call-stack that lead to them. This 
+                        // checks if this is a unique crash or one we have seen before
+                        let bucket = crash_bucket(err, pc, &exec_env.borrow().call_stack);
+                        if crash_map.get(&bucket).is_some() {
                             continue;
                         }
                         local_unique_crashes += 1;
-                        crash_map.insert(pc, 0);
-
-                        let output_dir = OUTPUT_DIR.get().unwrap();
-                        let h = Hash32::hash(&exec_env.borrow().fuzz_input);
-
-                        let target_dir = harness_init.target_id;
-                        let crash_file = match case_res {
-                            Err(uc_error::READ_PROT)        |
-                            Err(uc_error::READ_UNALIGNED)   |
-                            Err(uc_error::READ_UNMAPPED) => {
-                                format!("{output_dir}/{target_dir}/crashes/read_{h:x}_{pc:x}")
-                            },
-                            Err(uc_error::WRITE_PROT)        |
-                            Err(uc_error::WRITE_UNALIGNED)   |
-                            Err(uc_error::WRITE_UNMAPPED) => {
-                                format!("{output_dir}/{target_dir}/crashes/write_{h:x}_{pc:x}")
-                            },
-                            Err(uc_error::FETCH_PROT)        |
-                            Err(uc_error::FETCH_UNALIGNED)   |
-                            Err(uc_error::FETCH_UNMAPPED) => {
-                                format!("{output_dir}/{target_dir}/crashes/exec_{h:x}_{pc:x}")
-                            },
-                            Err(uc_error::NOMEM) => {
-                                format!("{output_dir}/{target_dir}/crashes/oom_{h:x}_{pc:x}")
-                            },
-                            Err(uc_error::DOUBLE_FREE) => {
-                                format!("{output_dir}/{target_dir}/crashes/double_free_{h:x}_{pc:x}")
-                            },
-                            Err(uc_error::OOB_FREE) => {
-                                format!("{output_dir}/{target_dir}/crashes/oob_free_{h:x}_{pc:x}")
-                            },
-                            _ => unreachable!(),
-                        };
-                        std::fs::write(&crash_file, &exec_env.borrow().fuzz_input).unwrap();
+                        crash_map.insert(bucket, 0);
+
+                        // Re-running the case to collect the crash-report can take a while, so
+                        // other workers should not be blocked on the crash-map meanwhile
+                        drop(crash_map);
+
+                        let mut crash_info = CrashInfo::collect(&unicorn, &exec_env.borrow(), 
+                                                                err, pc);
+                        crash_info.instructions_executed = count_instructions(harness_init, 
+                                &target_shared, &exec_env, &mut unicorn, &snapshot);
+                        crash_info.save(harness_init.target_id, &exec_env.borrow().fuzz_input);
+                        mutator.new_crash(&exec_env.borrow().fuzz_input);
                     },
                     uc_error::TIMEOUT => {
                         let mut timeout_map = target_shared.timeout_mapping.write();
@@ -274,15 +639,19 @@ pub fn worker(harness_init: &HarnessInit, snapshot: Arc<SnapshotContext>,
                 }
             }
 
-            // Skip coverage checks if we are performing grammar-based generational fuzzing
-            if MUTATOR == MutType::Gen {
-                continue;
-            }
-            
             // Check if this case found new coverage, if so add it to corpus
             let case_cov = exec_env.borrow().cov_count;
             if case_cov > 0 {
                 local_coverage_count += case_cov;
+
+                // Give the mutator a chance to shrink the input before it is saved
+                let mut trimmed = exec_env.borrow().fuzz_input.clone();
+                if mutator.trim(&mut trimmed) {
+                    apply_trim(harness_init, &target_shared, &exec_env, &mut unicorn, &snapshot,
+                               trimmed, case_res);
+                }
+                mutator.new_coverage(&exec_env.borrow().fuzz_input, case_cov);
+
                 let mut corp_inputs = all_shared.inputs.write();
 
                 let output_dir = OUTPUT_DIR.get().unwrap();
@@ -294,14 +663,34 @@ pub fn worker(harness_init: &HarnessInit, snapshot: Arc<SnapshotContext>,
                 // Add this input to the corpus. Since the emulator no longer needs it (its being 
                 // reset after this fuzz-case anyways), we just drain it out of the emulators 
                 // fuzz_input instead of cloning a new data-array
-                corp_inputs.push(Input::new(exec_env.borrow_mut().fuzz_input.drain(..).collect()));
+                let mut input = Input::new(exec_env.borrow_mut().fuzz_input.drain(..).collect());
+                input.coverage       = case_cov;
+                input.depth          = parent_depth + 1;
+                input.discovery_time = all_shared.start_time.elapsed().as_secs_f64();
+                input.tree           = case_tree;
+                let (index, len) = (corp_inputs.len(), input.data.len());
+                all_shared.corpus_stats.write().add_input(&input);
+                corp_inputs.push(input);
+
+                // The corpus lock is released before ranking the input to keep a consistent lock
+                // order with the seed-selection above
+                drop(corp_inputs);
+                rate_input(&target_shared, &exec_env.borrow(), index, len, case_time);
             }
         }
 
+        // Record how much time was spent on this seed so stale seeds receive less energy
+        if mut_type == MutType::Mut {
+            all_shared.inputs.write()[input_index].times_fuzzed += energy;
+        }
+
+        // Let the mutator merge the statistics of its operators with the other threads
+        mutator.sync_operator_stats(&target_shared.operator_stats);
+
         // Populate statistics that will be sent to the main thread
         let stats = Statistics {
             target_id:     harness_init.target_id,
-            total_cases:   SEED_ENERGY,
+            total_cases:   energy,
             crashes:       local_total_crashes,
             timeouts:      local_total_timeouts,
             ucrashes:      local_unique_crashes,
@@ -369,16 +758,57 @@ impl Statistics {
 }
 
 #[derive(Debug, Clone)]
-/// In the future this could contain more information about the input used for power schedules
+/// A single input of the corpus alongside metadata that is used by the power schedule
 pub struct Input {
     /// Raw byte backing of this input
     data: Vec<u8>,
+
+    /// Execution time of this input in microseconds. Only valid once `calibrated` is set
+    pub exec_time: f64,
+
+    /// Number of instructions executed by this input. Only valid once `calibrated` is set
+    pub instr_count: u64,
+
+    /// Set once the input has been run by a worker to measure `exec_time` and `instr_count`
+    pub calibrated: bool,
+
+    /// Amount of new coverage this input found when it was added to the corpus
+    pub coverage: usize,
+
+    /// Number of fuzz-cases that have been run using this input as the seed
+    pub times_fuzzed: usize,
+
+    /// Number of mutation-generations between this input and the initial seed it derives from
+    pub depth: usize,
+
+    /// Time in seconds since the fuzzer started at which this input was found
+    pub discovery_time: f64,
+
+    /// Next step of the deterministic mutation pass that has not been handed out to a worker yet
+    pub det_cursor: usize,
+
+    /// Set once all steps of the deterministic mutation pass were handed out
+    pub det_done: bool,
+
+    /// Derivation tree this input was generated from. Only set for inputs found by the grammar
+    /// generator, which mutates them on the grammar level
+    pub tree: Option<DerivationTree>,
 }
 
 impl Input {
     pub fn new(data: Vec<u8>) -> Self {
         Self {
-            data: data.to_vec(),
+            data:           data.to_vec(),
+            exec_time:      0.0,
+            instr_count:    0,
+            calibrated:     false,
+            coverage:       0,
+            times_fuzzed:   0,
+            depth:          0,
+            discovery_time: 0.0,
+            det_cursor:     0,
+            det_done:       false,
+            tree:           None,
         }
     }
 }
@@ -390,6 +820,13 @@ impl Input {
 pub struct AllShared {
     /// Actual byte-backing for the fuzz-inputs
     pub inputs: RwLock<Vec<Input>>,
+
+    /// Time at which the fuzzer started, used to record when inputs were discovered
+    pub start_time: Instant,
+
+    /// Corpus-wide averages used by the power schedule. Only modified while holding the write
+    /// lock of `inputs`
+    pub corpus_stats: RwLock<CorpusStats>,
 }
 
 impl Default for AllShared {
@@ -402,7 +839,9 @@ impl AllShared {
     /// Initialize a AllShared structure with default fields
     pub fn new() -> Self {
         Self {
-            inputs: RwLock::new(Vec::new()),
+            inputs:       RwLock::new(Vec::new()),
+            start_time:   Instant::now(),
+            corpus_stats: RwLock::new(CorpusStats::default()),
         }
     }
 }
@@ -416,11 +855,23 @@ pub struct TargetShared {
     /// Used to dedup crashes and only save off unique crashes
     pub timeout_mapping: RwLock<FxHashMap<usize, u8>>,
 
-    /// Fuzzer indexes this using a hash of the edge-coverage to check if it found new coverage
-    pub coverage_bytemap: Vec<u8>,
+    /// Fuzzer indexes this using a hash of the edge-coverage to check if it found new coverage.
+    /// Every entry holds the hit-count buckets that were seen for its edge as a bitmask. The map
+    /// is zero-initialized lazily by the OS, so only pages that hold coverage use memory
+    pub coverage_bytemap: Box<[AtomicU8]>,
 
     /// A coverage counter that is incremented whenever new coverage is found
     pub cov_counter: AtomicUsize,
+
+    /// Best input for every edge of this target, used to select the favored inputs. Inputs are
+    /// only ranked by the target that found or calibrated them
+    pub top_rated: RwLock<TopRated>,
+
+    /// Automatically generated dictionary of this target
+    pub tokens: RwLock<TokenTable>,
+
+    /// Mutation operator statistics merged from all threads of this target
+    pub operator_stats: RwLock<OperatorStats>,
 }
 
 impl Default for TargetShared {
@@ -429,14 +880,24 @@ impl Default for TargetShared {
     }
 }
 
+/// Allocate a zeroed coverage-map. Zeroed memory is provided lazily by the OS, unlike a map that
+/// is initialized entry by entry
+fn zeroed_coverage_map() -> Box<[AtomicU8]> {
+    // All-zero bytes are a valid `AtomicU8` holding 0
+    unsafe { Box::new_zeroed_slice(COVMAP_SIZE as usize).assume_init() }
+}
+
 impl TargetShared {
     /// Initialize a TargetShared structure with default fields
     pub fn new() -> Self {
         Self {
             crash_mapping:    RwLock::new(FxHashMap::default()),
             timeout_mapping:  RwLock::new(FxHashMap::default()),
-            coverage_bytemap: vec![0; COVMAP_SIZE as usize],
+            coverage_bytemap: zeroed_coverage_map(),
             cov_counter:      AtomicUsize::new(0),
+            top_rated:        RwLock::new(TopRated::default()),
+            tokens:           RwLock::new(TokenTable::default()),
+            operator_stats:   RwLock::new(OperatorStats::default()),
         }
     }
 }
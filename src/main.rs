@@ -1,9 +1,15 @@
 use zfuzz::{
-    Statistics, AllShared, Input, worker, TargetShared,
-    arg_setup::{handle_cli, Cli},
-    targets::targets::{HarnessInit, TARGET_INIT_FUNCTIONS, TARGETS},
-    execution_state::take_snapshot,
+    Statistics, AllShared, Input, worker, replay_corpus, create_snapshot, TargetShared,
+    arg_setup::{handle_cli, Cli, Command, RESUME, INPUT_DIR},
+    targets::targets::{HarnessInit, ACTIVE_TARGETS},
+    repro::repro,
+    cmin::cmin,
+    tmin::tmin,
+    snapshot_file::save_snapshot,
+    power_schedule::CorpusStats,
     pretty_printing::print_stats,
+    campaign_state::{save_state, load_state, load_corpus, restore_target_shared, CampaignState},
+    configurables::STATE_SAVE_INTERVAL,
 };
 
 use unicorn_engine::unicorn_const::uc_error;
@@ -11,24 +17,46 @@ use rustc_hash::FxHashMap;
 use console::Term;
 use clap::Parser;
 
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::mpsc::{self, Receiver, Sender, RecvTimeoutError};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::{Duration, Instant};
 
-/// Setup and spawn fuzzer-threads for target with given `target_id`
-fn start_target(tx: &Sender<Statistics>, all_shared: Arc<AllShared>, harness_init: HarnessInit) {
+/// Set by the SIGINT handler. The main thread saves the campaign state and exits once this is set
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// SIGINT handler, only sets a flag since the state can not be saved from a signal handler
+extern "C" fn handle_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Save the campaign state of all targets
+fn save_all_states(stats: &FxHashMap<usize, Statistics>, elapsed_time: f64,
+                   target_shared: &FxHashMap<usize, Arc<TargetShared>>) {
+    for (id, cur_stats) in stats {
+        save_state(cur_stats, elapsed_time, &target_shared[id]);
+    }
+}
+
+/// Setup and spawn fuzzer-threads for target with given `target_id`. If a previous campaign is
+/// being resumed, its state is restored before the threads are started
+fn start_target(tx: &Sender<Statistics>, all_shared: Arc<AllShared>, harness_init: HarnessInit,
+                resume_state: Option<&CampaignState>) -> Arc<TargetShared> {
 
     // Set up a temporary exec_env/unicorn engine to create the initial memory/file/context snapshot
-    let snapshot = {
-        let (exec_env, unicorn) = TARGET_INIT_FUNCTIONS[harness_init.target_id]()
-                                        .expect("Failed to initialize one of the fuzz-targets");
-        take_snapshot(&exec_env, &unicorn)
-            .expect(&format!("Failed to snapshot target: {}", harness_init.target_id))
-    };
+    let snapshot = create_snapshot(&harness_init);
     
     let target_shared: Arc<TargetShared> = Arc::new(TargetShared::default());
 
+    // Restore dedup maps, and rebuild the coverage map by replaying the entire corpus
+    if *RESUME.get().unwrap() {
+        if let Some(state) = resume_state {
+            restore_target_shared(state, &target_shared);
+        }
+        replay_corpus(&harness_init, &snapshot, &target_shared, &all_shared);
+    }
+
     // Wrap thread-shared objects for this target in `Arc` so they can be safely shared
     let snapshot = Arc::new(snapshot);
 
@@ -41,6 +69,7 @@ fn start_target(tx: &Sender<Statistics>, all_shared: Arc<AllShared>, harness_ini
 
         thread::spawn(move || worker(&harness_init, snapshot, all_shared, target_shared, tx));
     }
+    target_shared
 }
 
 fn main() -> Result<(), uc_error> {
@@ -48,10 +77,40 @@ fn main() -> Result<(), uc_error> {
     let mut args: Cli = Cli::parse();
     handle_cli(&mut args);
 
+    // Subcommands run a single task on a target instead of starting a fuzzing campaign
+    if let Some(command) = &args.command {
+        return match command {
+            Command::Repro { target, file } => repro(*target, file),
+            Command::Cmin { target, input_dir, output_dir } => {
+                cmin(*target, input_dir, output_dir)
+            },
+            Command::Tmin { target, output, file } => {
+                let output = output.clone().unwrap_or_else(|| format!("{file}.min"));
+                tmin(*target, file, &output)
+            },
+            Command::Snapshot { target, output } => save_snapshot(*target, output),
+        };
+    }
+
     // Statistics structure. This is kept local to the main thread and updated via message passing 
     // from the worker threads to reduce shared state
     let mut stats: FxHashMap<usize, Statistics> = FxHashMap::default();
-    TARGETS.iter().for_each(|t| { stats.insert(t.target_id, Statistics::new(t.target_id)); });
+    ACTIVE_TARGETS.get().unwrap().iter().for_each(|t| {
+        stats.insert(t.target_id, Statistics::new(t.target_id));
+    });
+
+    // If we are resuming a previous campaign, load the state that was saved for each target
+    let mut resume_states: FxHashMap<usize, CampaignState> = FxHashMap::default();
+    let mut prev_run_time: f64 = 0.0;
+    if *RESUME.get().unwrap() {
+        for t in ACTIVE_TARGETS.get().unwrap() {
+            if let Some(state) = load_state(t.target_id) {
+                stats.insert(t.target_id, state.stats);
+                prev_run_time = prev_run_time.max(state.run_time);
+                resume_states.insert(t.target_id, state);
+            }
+        }
+    }
 
     // Messaging objects used to transfer statistics between worker threads and main thread
     let (tx, rx): (Sender<Statistics>, Receiver<Statistics>) = mpsc::channel();
@@ -61,28 +120,41 @@ fn main() -> Result<(), uc_error> {
     let all_shared: AllShared = AllShared::new();
     {
         let mut corpus_tmp = all_shared.inputs.write();
-        for filename in std::fs::read_dir(args.input_dir).unwrap() {
+        for filename in std::fs::read_dir(INPUT_DIR.get().unwrap()).unwrap() {
             let filename = filename.unwrap().path();
             let data = std::fs::read(filename).expect("Failed to read input file");
 
             // Add the input to the corpus
             corpus_tmp.push(Input::new(data));
         }
+
+        // Inputs found during the previous run of a resumed campaign are added to the corpus
+        if *RESUME.get().unwrap() {
+            for t in ACTIVE_TARGETS.get().unwrap() {
+                corpus_tmp.append(&mut load_corpus(t.target_id));
+            }
+        }
         if corpus_tmp.is_empty() { panic!("Please supply at least 1 initial seed"); }
+        *all_shared.corpus_stats.write() = CorpusStats::new(&corpus_tmp);
     }
 
     // Wrap data in an `Arc` to make it thread-safe
     let all_shared = Arc::new(all_shared);
 
-    // Starts up all registered targets
-    {
-        TARGETS.iter().for_each(|t| start_target(&tx, all_shared.clone(), *t));
-    }
+    // Starts up all registered targets. The target-shared data is kept around so the campaign state
+    // can periodically be saved
+    let target_shared: FxHashMap<usize, Arc<TargetShared>> = ACTIVE_TARGETS.get().unwrap().iter()
+        .map(|t| {
+            let resume_state = resume_states.get(&t.target_id);
+            (t.target_id, start_target(&tx, all_shared.clone(), *t, resume_state))
+        })
+        .collect();
 
     // Continuous statistic tracking via message passing in main thread
     let start = Instant::now();
     let mut last_time = Instant::now();
-    let mut last_cov_event: f64 = 0.0;
+    let mut last_save = Instant::now();
+    let mut last_cov_event: f64 = prev_run_time;
     let term = Term::buffered_stdout();
     term.clear_screen().unwrap();
 
@@ -90,8 +162,24 @@ fn main() -> Result<(), uc_error> {
     // be 0, leading to a div-by-0 crash while printing statistics
     thread::sleep(Duration::from_millis(1000));
 
-    for received in rx {
-        let elapsed_time = start.elapsed().as_secs_f64();
+    // Ctrl-C saves the campaign state before exiting instead of losing everything since the
+    // last periodic save
+    unsafe { libc::signal(libc::SIGINT, handle_sigint as *const () as libc::sighandler_t); }
+
+    loop {
+        let elapsed_time = prev_run_time + start.elapsed().as_secs_f64();
+
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            save_all_states(&stats, elapsed_time, &target_shared);
+            term.show_cursor().unwrap();
+            std::process::exit(0);
+        }
+
+        let received = match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(received) => received,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
 
         // Check if we got new coverage
         if received.coverage != 0 {
@@ -110,9 +198,15 @@ fn main() -> Result<(), uc_error> {
 
         // Print out updated statistics every second
         if last_time.elapsed() >= Duration::from_millis(500) {
-            print_stats(&term, &stats, elapsed_time, last_cov_event);
+            print_stats(&term, &stats, &target_shared, elapsed_time, last_cov_event);
             last_time = Instant::now();
         }
+
+        // Periodically save the campaign state so it can be resumed later
+        if last_save.elapsed() >= Duration::from_secs(STATE_SAVE_INTERVAL) {
+            save_all_states(&stats, elapsed_time, &target_shared);
+            last_save = Instant::now();
+        }
     }
     Ok(())
 }
@@ -0,0 +1,191 @@
+//! MOpt-style adaptive selection of mutation operators. Every worker thread acts as a particle of
+//! a particle swarm whose position is the probability distribution its mutator uses to pick
+//! operators. Operators are rated by how often the cases they were used in found new coverage or
+//! unique crashes, and each particle periodically moves towards the best distribution it has seen
+//! itself and the best distribution learned by all threads of the target.
+
+use crate::configurables::{MOPT_PERIOD, MOPT_MIN_PROB};
+
+use rand_xoshiro::Xoroshiro64Star;
+use rand_xoshiro::rand_core::RngCore;
+
+/// Inertia of a particle at the start of the campaign. Higher values favor exploration
+const INERTIA_START: f64 = 0.9;
+
+/// Inertia of a particle once `INERTIA_ROUNDS` updates have been performed
+const INERTIA_END: f64 = 0.3;
+
+/// Number of updates over which the inertia linearly decays
+const INERTIA_ROUNDS: usize = 20;
+
+/// Operator statistics that are merged from all threads of a target
+#[derive(Debug, Default)]
+pub struct OperatorStats {
+    /// Name of every operator, set by the first thread that merges its statistics
+    names: Vec<String>,
+
+    /// Number of cases every operator was used in
+    usage: Vec<u64>,
+
+    /// Number of cases every operator was used in that found new coverage or a unique crash
+    finds: Vec<u64>,
+
+    /// Selection probabilities proportional to the efficiency (finds per use) of the operators.
+    /// Empty until the first find was merged
+    best: Vec<f64>,
+}
+
+impl OperatorStats {
+    /// Add the statistics of a thread to the target-wide statistics and recompute the globally
+    /// best distribution
+    pub fn merge(&mut self, names: &[String], usage: &[u64], finds: &[u64]) {
+        if self.names.is_empty() {
+            self.names = names.to_vec();
+            self.usage = vec![0; names.len()];
+            self.finds = vec![0; names.len()];
+        }
+        self.usage.iter_mut().zip(usage).for_each(|(total, n)| *total += n);
+        self.finds.iter_mut().zip(finds).for_each(|(total, n)| *total += n);
+
+        let eff: Vec<f64> = self.usage.iter().zip(&self.finds)
+            .map(|(&usage, &finds)| if usage == 0 { 0.0 } else { finds as f64 / usage as f64 })
+            .collect();
+        let sum: f64 = eff.iter().sum();
+        if sum > 0.0 {
+            self.best = eff.iter().map(|e| e / sum).collect();
+        }
+    }
+
+    /// Globally best distribution, or `None` if no operator has found anything yet
+    pub fn best(&self) -> Option<&[f64]> {
+        if self.best.is_empty() { None } else { Some(&self.best) }
+    }
+
+    /// Learned distribution as (name, probability) for every operator that has been used
+    pub fn distribution(&self) -> Vec<(&str, f64)> {
+        match self.best() {
+            Some(best) => self.names.iter().zip(best).zip(&self.usage)
+                .filter(|(_, &usage)| usage != 0)
+                .map(|((name, &prob), _)| (name.as_str(), prob))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// A single particle of the swarm, owned by the mutator of a worker thread
+#[derive(Debug, Clone)]
+pub struct Particle {
+    /// Operator names, passed on to `OperatorStats`
+    names: Vec<String>,
+
+    /// Current selection probability of every operator. Operators that start out with a
+    /// probability of 0 are disabled and never selected
+    position: Vec<f64>,
+
+    /// Current velocity of every operator
+    velocity: Vec<f64>,
+
+    /// Probability of every operator during the period in which it performed best
+    local_best: Vec<f64>,
+
+    /// Best efficiency every operator has reached during a period
+    local_best_eff: Vec<f64>,
+
+    /// Number of cases every operator was used in during the current period
+    usage: Vec<u64>,
+
+    /// Number of cases every operator was used in that found something during the current period
+    finds: Vec<u64>,
+
+    /// Number of cases run during the current period
+    cases: usize,
+
+    /// Number of updates performed so far
+    rounds: usize,
+}
+
+impl Particle {
+    /// Create a particle that starts out with probabilities proportional to `weights`
+    pub fn new(names: Vec<String>, weights: &[usize]) -> Self {
+        let total: usize = weights.iter().sum();
+        let position: Vec<f64> = weights.iter()
+            .map(|&w| if total == 0 { 0.0 } else { w as f64 / total as f64 })
+            .collect();
+        let num_ops = weights.len();
+
+        Self {
+            names,
+            local_best:     position.clone(),
+            position,
+            velocity:       vec![0.0; num_ops],
+            local_best_eff: vec![0.0; num_ops],
+            usage:          vec![0; num_ops],
+            finds:          vec![0; num_ops],
+            cases:          0,
+            rounds:         0,
+        }
+    }
+
+    /// Current selection probability of every operator
+    pub fn position(&self) -> &[f64] {
+        &self.position
+    }
+
+    /// Record a case that used the operators `ops`
+    pub fn record_case(&mut self, ops: &[usize]) {
+        ops.iter().for_each(|&op| self.usage[op] += 1);
+        self.cases += 1;
+    }
+
+    /// Record that the case that used the operators `ops` found new coverage or a unique crash
+    pub fn record_find(&mut self, ops: &[usize]) {
+        ops.iter().for_each(|&op| self.finds[op] += 1);
+    }
+
+    /// Returns true once enough cases were run during the current period to perform an update
+    pub fn ready(&self) -> bool {
+        self.cases >= MOPT_PERIOD
+    }
+
+    /// Merge the statistics of the current period into `shared`, and move the particle towards
+    /// its local best and the global best distribution. Starts a new period
+    pub fn update(&mut self, shared: &mut OperatorStats, rng: &mut Xoroshiro64Star) {
+        shared.merge(&self.names, &self.usage, &self.finds);
+
+        let enabled: Vec<usize> = (0..self.position.len())
+            .filter(|&op| self.position[op] > 0.0)
+            .collect();
+
+        for &op in &enabled {
+            if self.usage[op] == 0 { continue; }
+            let eff = self.finds[op] as f64 / self.usage[op] as f64;
+            if eff > self.local_best_eff[op] {
+                self.local_best_eff[op] = eff;
+                self.local_best[op]     = self.position[op];
+            }
+        }
+
+        // Without any finds yet there is nothing to move towards
+        let global = shared.best().unwrap_or(&self.position).to_vec();
+        let progress = self.rounds.min(INERTIA_ROUNDS) as f64 / INERTIA_ROUNDS as f64;
+        let inertia = INERTIA_START - (INERTIA_START - INERTIA_END) * progress;
+
+        for &op in &enabled {
+            let r1 = rng.next_u32() as f64 / u32::MAX as f64;
+            let r2 = rng.next_u32() as f64 / u32::MAX as f64;
+            self.velocity[op] = inertia * self.velocity[op] +
+                r1 * (self.local_best[op] - self.position[op]) +
+                r2 * (global[op] - self.position[op]);
+            self.position[op] = (self.position[op] + self.velocity[op]).clamp(MOPT_MIN_PROB, 1.0);
+        }
+
+        let sum: f64 = self.position.iter().sum();
+        self.position.iter_mut().for_each(|p| *p /= sum);
+
+        self.usage.iter_mut().for_each(|n| *n = 0);
+        self.finds.iter_mut().for_each(|n| *n = 0);
+        self.cases   = 0;
+        self.rounds += 1;
+    }
+}
@@ -1,14 +1,57 @@
-use crate::arg_setup::DICT_FILE;
+use crate::{
+    Input,
+    arg_setup::{DICT_FILE, DICT_LEVEL, FORMAT_FILE},
+    error_exit,
+    configurables::MAX_I2S_TOKENS,
+    mopt::{OperatorStats, Particle},
+    input_format::InputFormat,
+    targets::targets::HarnessInit,
+};
 
 use rand_xoshiro::rand_core::RngCore;
 use rand_xoshiro::Xoroshiro64Star;
 use rand_xoshiro::rand_core::SeedableRng;
+use rustc_hash::FxHashSet;
+use parking_lot::RwLock;
 
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
+use std::sync::Arc;
 use rand::Rng;
 
+/// Largest value that is added to/subtracted from the input during the deterministic arithmetic
+/// stages
+const ARITH_MAX: u64 = 35;
+
+/// Interesting values that are written to every offset of the input during the deterministic
+/// stages, by width. Wider widths also contain the values of the smaller ones
+const INTERESTING_8: &[i64] = &[-128, -1, 0, 1, 16, 32, 64, 100, 127];
+const INTERESTING_16: &[i64] = &[
+    -128, -1, 0, 1, 16, 32, 64, 100, 127,
+    -32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767,
+];
+const INTERESTING_32: &[i64] = &[
+    -128, -1, 0, 1, 16, 32, 64, 100, 127,
+    -32768, -129, 128, 255, 256, 512, 1000, 1024, 4096, 32767,
+    -2147483648, -100663046, -32769, 32768, 65535, 65536, 100663045, 2147483647,
+];
+
+/// Stages of the deterministic mutation pass in the order they are run. Multi-byte stages are
+/// parameterized by their width in bytes
+#[derive(Copy, Clone, Debug)]
+enum DetStage {
+    BitFlip,
+    ByteFlip,
+    Arith(usize),
+    Interesting(usize),
+    DictInsert,
+}
+
+/// Number of entries `mutation_strats` is filled with. Every enabled mutation receives a share
+/// according to its current selection probability
+const STRAT_SLOTS: usize = 4096;
+
 /// Different mutations this mutator supports
 #[derive(Copy, Clone, Debug)]
 pub enum Mutation {
@@ -20,65 +63,243 @@ pub enum Mutation {
     DupBlock,
     Resize,
     Dictionary,
+    DictInsert,
+    InputToState,
+    Splice,
+    CorpusInsert,
+}
+
+/// Number of mutations in `enum Mutation`
+const NUM_MUTATIONS: usize = std::mem::variant_count::<Mutation>();
+
+/// All mutations, indexed by their discriminant
+const MUTATIONS: [Mutation; NUM_MUTATIONS] = [
+    Mutation::ByteReplace,
+    Mutation::BitFlip,
+    Mutation::MagicNum,
+    Mutation::SimpleArithmetic,
+    Mutation::RemoveBlock,
+    Mutation::DupBlock,
+    Mutation::Resize,
+    Mutation::Dictionary,
+    Mutation::DictInsert,
+    Mutation::InputToState,
+    Mutation::Splice,
+    Mutation::CorpusInsert,
+];
+
+/// Interface between the fuzzer and a mutator. Only `mutate` has to be implemented, the remaining
+/// methods let a mutator hook into additional steps of the fuzz-loop or make use of information
+/// collected by the fuzzer
+pub trait Mutator {
+    /// Mutate `input` in place. `corpus` holds all inputs that are currently in the corpus
+    fn mutate(&mut self, input: &mut Vec<u8>, corpus: &[Input]);
+
+    /// Called on every mutated input right before it is run, eg. to fix up checksums
+    fn post_process(&mut self, _input: &mut Vec<u8>) {}
+
+    /// Called on inputs that found new coverage before they are added to the corpus. Return true
+    /// if the input was shrunk, in which case the trimmed input is only kept if it still takes the
+    /// same edges
+    fn trim(&mut self, _input: &mut Vec<u8>) -> bool {
+        false
+    }
+
+    /// Called whenever an input found `cov_count` new coverage and was added to the corpus
+    fn new_coverage(&mut self, _input: &[u8], _cov_count: usize) {}
+
+    /// Called whenever an input caused a unique crash
+    fn new_crash(&mut self, _input: &[u8]) {}
+
+    /// Called after every batch of cases. Mutators that adapt how they select their operators
+    /// merge their statistics into the statistics of the target here, which are also displayed
+    /// on the stats screen
+    fn sync_operator_stats(&mut self, _shared: &RwLock<OperatorStats>) {}
+
+    /// Receives the input-to-state replacements of the current seed (see `build_i2s_tokens`)
+    fn set_i2s_tokens(&mut self, _tokens: Vec<(Vec<u8>, Vec<u8>)>) {}
+
+    /// Receives the tokens of the automatically generated dictionary whenever it grows
+    fn set_auto_tokens(&mut self, _tokens: Vec<Vec<u8>>) {}
+
+    /// Number of steps of the deterministic pass for an input of length `len`. Mutators without a
+    /// deterministic pass return 0
+    fn deterministic_steps(&self, _len: usize) -> usize {
+        0
+    }
+
+    /// Apply step `step` of the deterministic pass to `input`. Returns false if `step` is out of
+    /// range
+    fn deterministic_mutate(&mut self, _input: &mut Vec<u8>, _step: usize) -> bool {
+        false
+    }
 }
 
-/// A simple mutator that implements the mutations listed in `enum Mutation`
+/// Registered mutators
+/// Give your mutator a `MutatorId` that lines up with its index in `MUTATOR_INIT_FUNCTIONS`, and
+/// select it for a target using `HarnessInit::mutator`
+pub enum MutatorId {
+    Default = 0,
+}
+
+/// Function that creates a mutator for the target described by the passed in `HarnessInit`
+pub type MutatorInit = fn(&HarnessInit) -> Box<dyn Mutator>;
+
+/// List of functions used to create the mutator of a worker thread
+/// Use this to register your mutator
+pub const MUTATOR_INIT_FUNCTIONS: [MutatorInit; std::mem::variant_count::<MutatorId>()] = [
+    DefaultMutator::for_target,
+];
+
+/// Create the mutator that is selected for the target described by `harness_init`
+pub fn create_mutator(harness_init: &HarnessInit) -> Box<dyn Mutator> {
+    let init = MUTATOR_INIT_FUNCTIONS.get(harness_init.mutator).unwrap_or_else(|| {
+        error_exit(&format!("Unknown mutator: {}", harness_init.mutator));
+    });
+    init(harness_init)
+}
+
+/// The default mutator that implements the mutations listed in `enum Mutation`
 #[derive(Debug, Clone)]
-pub struct Mutator {
+pub struct DefaultMutator {
     /// Fast Rng
     rng: Xoroshiro64Star,
 
-    /// Available mutation strategies
+    /// Available mutation strategies. Every mutation appears according to its current selection
+    /// probability
     mutation_strats: Vec<Mutation>,
 
+    /// Initial weight of every mutation, a weight of 0 disables it
+    weights: [usize; NUM_MUTATIONS],
+
+    /// Adapts the selection probabilities based on which mutations lead to new coverage or crashes
+    particle: Particle,
+
+    /// Mutations (by discriminant) that were applied to the most recent input returned by `mutate`
+    last_muts: Vec<usize>,
+
     /// Count-down to havoc mode
     havoc_counter: usize,
 
     /// Can optionally be enabled via command-line flags, adds dictionary based fuzzing as an 
     /// additional mutation
-    dictionary: Option<Vec<String>>,
+    dictionary: Option<Vec<Vec<u8>>>,
+
+    /// Tokens of the automatically generated dictionary of the target. Used by the `Dictionary`
+    /// mutation alongside `dictionary`
+    auto_tokens: Vec<Vec<u8>>,
+
+    /// Input-to-state replacements (pattern, replacement) built from the comparison operands of
+    /// the current seed
+    i2s_tokens: Vec<(Vec<u8>, Vec<u8>)>,
+
+    /// Can optionally be enabled via command-line flags, confines most mutations to a single field
+    /// of the input and fixes up length fields and checksums afterwards
+    format: Option<Arc<InputFormat>>,
 }
 
-impl Default for Mutator {
+impl Default for DefaultMutator {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl Mutator {
+impl DefaultMutator {
     /// Initialize a default mutator
     pub fn new() -> Self {
-        // Initialize the individual strategies for the mutation_strats array alongside their
-        // weight. This creates a larger array since weight is created by inserting new
-        // elements into the array, but I believe that this should be much faster than
-        // alternatives
-        let mut mut_strats: Vec<Mutation> = Vec::new();
-        mut_strats.append(&mut (0..1000).map(|_| { Mutation::ByteReplace }).collect());
-        mut_strats.append(&mut (0..1000).map(|_| { Mutation::BitFlip }).collect());
-        mut_strats.append(&mut (0..200).map(|_|  { Mutation::MagicNum }).collect());
-        mut_strats.append(&mut (0..500).map(|_|  { Mutation::SimpleArithmetic }).collect());
-        mut_strats.append(&mut (0..30).map(|_|   { Mutation::RemoveBlock }).collect());
-        mut_strats.append(&mut (0..30).map(|_|   { Mutation::DupBlock }).collect());
-        mut_strats.append(&mut (0..10).map(|_|   { Mutation::Resize }).collect());
+        // Initial weights of the individual strategies. These are only the starting point, the
+        // selection probabilities are adapted while fuzzing
+        let mut weights = [0; NUM_MUTATIONS];
+        weights[Mutation::ByteReplace as usize]      = 1000;
+        weights[Mutation::BitFlip as usize]          = 1000;
+        weights[Mutation::MagicNum as usize]         = 200;
+        weights[Mutation::SimpleArithmetic as usize] = 500;
+        weights[Mutation::RemoveBlock as usize]      = 30;
+        weights[Mutation::DupBlock as usize]         = 30;
+        weights[Mutation::Resize as usize]           = 10;
+        weights[Mutation::Splice as usize]           = 20;
+        weights[Mutation::CorpusInsert as usize]     = 20;
 
         // If the user specified a dictionary to be used while fuzzing, parse it and add dictionary
         // replacements to the fuzz methods
         let dict_vec = if let Some(dict) = DICT_FILE.get().unwrap() {
-            mut_strats.append(&mut (0..30).map(|_|   { Mutation::Dictionary }).collect());
-            Some(parse_dict(dict))
+            weights[Mutation::Dictionary as usize] = 30;
+            weights[Mutation::DictInsert as usize] = 30;
+            let dict = parse_dict(dict, *DICT_LEVEL.get().unwrap())
+                .unwrap_or_else(|err| error_exit(&err));
+            Some(dict)
         } else {
             None
         };
 
+        let format = FORMAT_FILE.get().unwrap().as_ref().map(|format| {
+            Arc::new(InputFormat::load(format).unwrap_or_else(|err| error_exit(&err)))
+        });
+
         let mut rng = rand::thread_rng();
 
         // Seed Xoroshiro64Star with pseudo-random value and then only use
         // Xoroshiro64Star for randum numbers generation since it is a lot faster than `rand`
-        Self {
+        let mut mutator = Self {
             rng: Xoroshiro64Star::seed_from_u64(rng.gen()),
-            mutation_strats: mut_strats,
+            mutation_strats: Vec::new(),
+            weights,
+            particle: Particle::new(mutation_names(), &weights),
+            last_muts: Vec::new(),
             havoc_counter: 0,
             dictionary: dict_vec,
+            auto_tokens: Vec::new(),
+            i2s_tokens: Vec::new(),
+            format,
+        };
+        mutator.rebuild_strats();
+        mutator
+    }
+
+    /// Initialize a default mutator with the mutations enabled for the target described by
+    /// `harness_init`
+    pub fn for_target(harness_init: &HarnessInit) -> Box<dyn Mutator> {
+        let mut mutator = Self::new();
+        if harness_init.input_to_state {
+            mutator.enable_input_to_state();
+        }
+        if harness_init.auto_dict {
+            mutator.enable_auto_dict();
+        }
+        Box::new(mutator)
+    }
+
+    /// Add input-to-state replacements to the mutation strategies
+    pub fn enable_input_to_state(&mut self) {
+        self.weights[Mutation::InputToState as usize] = 100;
+        self.reset_particle();
+    }
+
+    /// Add dictionary replacements using the automatically generated dictionary to the mutation
+    /// strategies. They are only added once if a user-supplied dictionary already enabled them
+    pub fn enable_auto_dict(&mut self) {
+        if self.dictionary.is_none() {
+            self.weights[Mutation::Dictionary as usize] = 30;
+            self.weights[Mutation::DictInsert as usize] = 30;
+            self.reset_particle();
+        }
+    }
+
+    /// Restart the adaptive selection from the initial weights. Used while the mutator is set up
+    fn reset_particle(&mut self) {
+        self.particle = Particle::new(mutation_names(), &self.weights);
+        self.rebuild_strats();
+    }
+
+    /// Refill `mutation_strats` according to the current selection probabilities. This creates a
+    /// larger array since weight is created by inserting new elements into the array, but I
+    /// believe that this should be much faster than alternatives
+    fn rebuild_strats(&mut self) {
+        self.mutation_strats.clear();
+        for (mutation, prob) in MUTATIONS.iter().zip(self.particle.position()) {
+            if *prob == 0.0 { continue; }
+            let slots = ((prob * STRAT_SLOTS as f64) as usize).max(1);
+            self.mutation_strats.extend((0..slots).map(|_| *mutation));
         }
     }
 
@@ -273,13 +494,29 @@ impl Mutator {
         Ok(())
     }
 
-    /// Replace some of the input bytes with a provided dictionary entry
+    /// Pick an entry of the provided or automatically generated dictionary using the random value
+    /// `r`. Returns `None` if both dictionaries are empty
+    fn dict_entry(&self, r: usize) -> Option<&[u8]> {
+        let user_len = self.dictionary.as_ref().map_or(0, |dict| dict.len());
+        let total = user_len + self.auto_tokens.len();
+        if total == 0 { return None; }
+
+        let dict_idx = r % total;
+        if dict_idx < user_len {
+            Some(&self.dictionary.as_ref().unwrap()[dict_idx])
+        } else {
+            Some(&self.auto_tokens[dict_idx - user_len])
+        }
+    }
+
+    /// Replace some of the input bytes with an entry of the provided or automatically generated
+    /// dictionary
     fn dict_replace(&mut self, input: &mut Vec<u8>) -> Result<(), ()> {
-        let dict_idx = self.rng.next_u32() as usize % self.dictionary.as_ref().unwrap().len();
-        let entry = self.dictionary.as_ref().unwrap()[dict_idx].as_bytes();
+        let (r1, r2) = self.get2_rand();
+        let entry = self.dict_entry(r1).ok_or(())?;
 
         if input.len() <= entry.len() { return Err(()); }
-        let input_idx = self.rng.next_u64() as usize % (input.len() - entry.len());
+        let input_idx = r2 % (input.len() - entry.len());
         for (i, j) in (input_idx..(input_idx + entry.len())).enumerate() {
             input[j] = entry[i];
         }
@@ -287,6 +524,98 @@ impl Mutator {
         Ok(())
     }
 
+    /// Insert an entry of the provided or automatically generated dictionary at a random location
+    /// of the input
+    fn dict_insert(&mut self, input: &mut Vec<u8>) -> Result<(), ()> {
+        let (r1, r2) = self.get2_rand();
+        let entry = self.dict_entry(r1).ok_or(())?;
+
+        let input_idx = r2 % (input.len() + 1);
+        input.splice(input_idx..input_idx, entry.iter().copied());
+        Ok(())
+    }
+
+    /// Replace an occurrence of a logged comparison operand in the input with the value it was
+    /// compared against
+    fn input_to_state(&mut self, input: &mut [u8]) -> Result<(), ()> {
+        if self.i2s_tokens.is_empty() { return Err(()); }
+        let (r1, r2) = self.get2_rand();
+        let (pattern, replacement) = &self.i2s_tokens[r1 % self.i2s_tokens.len()];
+
+        // Find all locations of the pattern in the input and replace one of them
+        let positions: Vec<usize> = input.windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| window == pattern)
+            .map(|(i, _)| i)
+            .collect();
+        if positions.is_empty() { return Err(()); }
+
+        let pos = positions[r2 % positions.len()];
+        input[pos..pos + replacement.len()].copy_from_slice(replacement);
+        Ok(())
+    }
+
+    /// Splice the input with a different corpus entry. Both inputs are split at the same random
+    /// point between the first and last byte at which they differ, and the prefix of the input is
+    /// combined with the suffix of the other entry
+    fn splice(&mut self, input: &mut Vec<u8>, corpus: &[Input]) -> Result<(), ()> {
+        if corpus.is_empty() { return Err(()); }
+        let (r1, r2) = self.get2_rand();
+        let other = &corpus[r1 % corpus.len()].data;
+
+        // Find the range in which the 2 inputs differ. Splicing outside of it would not change
+        // the input
+        let len = core::cmp::min(input.len(), other.len());
+        let first_diff = (0..len).find(|&i| input[i] != other[i]).ok_or(())?;
+        let last_diff  = (0..len).rev().find(|&i| input[i] != other[i]).ok_or(())?;
+        if last_diff - first_diff < 2 { return Err(()); }
+
+        let split = first_diff + 1 + (r2 % (last_diff - first_diff - 1));
+        input.truncate(split);
+        input.extend_from_slice(&other[split..]);
+        Ok(())
+    }
+
+    /// Take a random block out of a different corpus entry and insert it into a random location
+    /// of the input
+    fn corpus_insert(&mut self, input: &mut Vec<u8>, corpus: &[Input]) -> Result<(), ()> {
+        if corpus.is_empty() { return Err(()); }
+        let (r1, r2) = self.get2_rand();
+        let other = &corpus[r1 % corpus.len()].data;
+        if other.is_empty() { return Err(()); }
+
+        // Calculate a random range within the other entry
+        let block_len = 1 + (self.rng.next_u32() % 128) as usize;
+        let start = r2 % other.len();
+        let end   = start + core::cmp::min(other.len() - start, block_len);
+
+        // Chose random location to insert the block into
+        let idx = self.rng.next_u32() as usize % (input.len() + 1);
+        input.splice(idx..idx, other[start..end].iter().copied());
+        Ok(())
+    }
+
+    /// Stages of the deterministic pass alongside the number of steps each of them takes for an
+    /// input of length `len`
+    fn det_stages(&self, len: usize) -> Vec<(DetStage, usize)> {
+        let positions = |width: usize| (len + 1).saturating_sub(width);
+        let endians   = |width: usize| if width == 1 { 1 } else { 2 };
+
+        let mut stages = vec![(DetStage::BitFlip, len * 8), (DetStage::ByteFlip, len)];
+        for width in [1, 2, 4] {
+            let steps = positions(width) * endians(width) * 2 * ARITH_MAX as usize;
+            stages.push((DetStage::Arith(width), steps));
+        }
+        for width in [1, 2, 4] {
+            let steps = positions(width) * endians(width) * interesting_values(width).len();
+            stages.push((DetStage::Interesting(width), steps));
+        }
+        if let Some(dict) = &self.dictionary {
+            stages.push((DetStage::DictInsert, (len + 1) * dict.len()));
+        }
+        stages
+    }
+
     /// Chose a random mutation strategy
     fn chose_mut(&mut self) -> Mutation {
         let tmp_rand = self.rng.next_u32() as usize % self.mutation_strats.len();
@@ -294,10 +623,12 @@ impl Mutator {
     }
 
     /// Apply various implemented mutation strategies. Every 100 cases, use 'havoc-mode' which
-    /// applies multiple strategies at the same time
-    pub fn mutate(&mut self, input: &mut Vec<u8>) {
+    /// applies multiple strategies at the same time. `corpus` is used by the mutations that 
+    /// combine the input with other corpus entries
+    fn apply_mutations(&mut self, input: &mut Vec<u8>, corpus: &[Input]) {
         let mut muts = Vec::new();
         self.havoc_counter += 1;
+        self.last_muts.clear();
 
         // Usually only perform 1 mutation, but if havoc is invoked, we queue up multiple
         // mutations onto the input in this fuzz-case
@@ -321,6 +652,10 @@ impl Mutator {
                     Mutation::DupBlock         => self.duplicate_block(input),
                     Mutation::Resize           => self.resize(input),
                     Mutation::Dictionary       => self.dict_replace(input),
+                    Mutation::DictInsert       => self.dict_insert(input),
+                    Mutation::InputToState     => self.input_to_state(input),
+                    Mutation::Splice           => self.splice(input, corpus),
+                    Mutation::CorpusInsert     => self.corpus_insert(input, corpus),
                 };
 
                 // If the chosen strategy failed, chose a different mutation and rerun the
@@ -331,10 +666,214 @@ impl Mutator {
                     *mutation = self.chose_mut();
                 }
             }
+
+            if !self.last_muts.contains(&(*mutation as usize)) {
+                self.last_muts.push(*mutation as usize);
+            }
         }
+        self.particle.record_case(&self.last_muts);
+    }
+
+    /// Mutate a single field of a random record of `input` and reassemble it. Only fields that are
+    /// not derived from other fields and that are large enough for most mutations are picked.
+    /// Returns false if the input does not contain such a field
+    fn mutate_field(&mut self, format: &InputFormat, input: &mut Vec<u8>, corpus: &[Input])
+            -> bool {
+        let (mut records, trailer) = format.parse(input);
+        let fields: Vec<(usize, usize)> = records.iter().enumerate()
+            .flat_map(|(r, record)| record.iter().enumerate()
+                .filter(|(f, data)| format.is_mutable(*f) && data.len() >= 4)
+                .map(move |(f, _)| (r, f)))
+            .collect();
+        if fields.is_empty() { return false; }
+
+        let (r, f) = fields[self.rng.next_u32() as usize % fields.len()];
+        self.apply_mutations(&mut records[r][f], corpus);
+        *input = format.serialize(&mut records, &trailer);
+        true
     }
 }
 
+impl Mutator for DefaultMutator {
+    /// Apply various implemented mutation strategies (see `apply_mutations`). If the format of
+    /// the input is known, 3 out of 4 cases only mutate a single field of a random record, which
+    /// keeps the structure around it intact
+    fn mutate(&mut self, input: &mut Vec<u8>, corpus: &[Input]) {
+        if let Some(format) = self.format.clone() {
+            if self.rng.next_u32() & 3 != 0 && self.mutate_field(&format, input, corpus) {
+                return;
+            }
+        }
+        self.apply_mutations(input, corpus);
+    }
+
+    /// Fix up the length fields and checksums of the input if its format is known
+    fn post_process(&mut self, input: &mut Vec<u8>) {
+        if let Some(format) = &self.format {
+            format.fix(input);
+        }
+    }
+
+    /// Credit the mutations that produced this input
+    fn new_coverage(&mut self, _input: &[u8], _cov_count: usize) {
+        self.particle.record_find(&self.last_muts);
+        self.last_muts.clear();
+    }
+
+    /// Credit the mutations that produced this input
+    fn new_crash(&mut self, _input: &[u8]) {
+        self.particle.record_find(&self.last_muts);
+        self.last_muts.clear();
+    }
+
+    /// Once enough cases were run, merge the statistics of this thread into those of the target
+    /// and update the selection probabilities
+    fn sync_operator_stats(&mut self, shared: &RwLock<OperatorStats>) {
+        if !self.particle.ready() { return; }
+        self.particle.update(&mut shared.write(), &mut self.rng);
+        self.rebuild_strats();
+    }
+
+    /// Replace the tokens of the automatically generated dictionary. Called whenever the worker
+    /// notices that the dictionary of the target grew
+    fn set_auto_tokens(&mut self, tokens: Vec<Vec<u8>>) {
+        self.auto_tokens = tokens;
+    }
+
+    /// Replace the input-to-state replacements used by the `InputToState` mutation. Called 
+    /// whenever a new seed is chosen
+    fn set_i2s_tokens(&mut self, tokens: Vec<(Vec<u8>, Vec<u8>)>) {
+        self.i2s_tokens = tokens;
+    }
+
+    /// Total number of steps the deterministic pass takes for an input of length `len`
+    fn deterministic_steps(&self, len: usize) -> usize {
+        self.det_stages(len).iter().map(|(_, steps)| steps).sum()
+    }
+
+    /// Apply step `step` of the deterministic pass to `input`. Every step is a single mutation at
+    /// a fixed offset, so the pass can be split up and resumed at any step. Returns false if `step`
+    /// is out of range
+    fn deterministic_mutate(&mut self, input: &mut Vec<u8>, mut step: usize) -> bool {
+        // Finds of deterministic steps are not credited to any of the random mutations
+        self.last_muts.clear();
+
+        // Find the stage this step belongs to and make `step` relative to it
+        let mut cur_stage = None;
+        for (stage, steps) in self.det_stages(input.len()) {
+            if step < steps {
+                cur_stage = Some(stage);
+                break;
+            }
+            step -= steps;
+        }
+        let stage = match cur_stage {
+            Some(stage) => stage,
+            None => return false,
+        };
+
+        match stage {
+            DetStage::BitFlip => input[step / 8] ^= 1 << (step % 8),
+            DetStage::ByteFlip => input[step] ^= 0xff,
+            DetStage::Arith(width) => {
+                let deltas  = 2 * ARITH_MAX as usize;
+                let per_pos = deltas * if width == 1 { 1 } else { 2 };
+                let (pos, r) = (step / per_pos, step % per_pos);
+                let big_endian = r >= deltas;
+                let delta = (r % deltas) as u64 / 2 + 1;
+
+                let bytes = &mut input[pos..pos + width];
+                let val = if big_endian {
+                    bytes.iter().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+                } else {
+                    bytes.iter().rev().fold(0u64, |acc, b| (acc << 8) | *b as u64)
+                };
+                let val = if r & 1 == 0 {
+                    val.wrapping_add(delta)
+                } else {
+                    val.wrapping_sub(delta)
+                };
+                bytes.copy_from_slice(&encode_operand(val, width, big_endian));
+            },
+            DetStage::Interesting(width) => {
+                let values  = interesting_values(width);
+                let per_pos = values.len() * if width == 1 { 1 } else { 2 };
+                let (pos, r) = (step / per_pos, step % per_pos);
+                let val = values[r % values.len()] as u64;
+                input[pos..pos + width]
+                    .copy_from_slice(&encode_operand(val, width, r >= values.len()));
+            },
+            DetStage::DictInsert => {
+                let dict = self.dictionary.as_ref().unwrap();
+                let pos  = step / dict.len();
+                input.splice(pos..pos, dict[step % dict.len()].iter().copied());
+            },
+        }
+        true
+    }
+}
+
+/// Names of all mutations, used to display their selection probabilities
+fn mutation_names() -> Vec<String> {
+    MUTATIONS.iter().map(|m| format!("{:?}", m)).collect()
+}
+
+/// Interesting values used by the deterministic stage of the given width
+fn interesting_values(width: usize) -> &'static [i64] {
+    match width {
+        1 => INTERESTING_8,
+        2 => INTERESTING_16,
+        _ => INTERESTING_32,
+    }
+}
+
+/// Encode the lower `width` bytes of `val` in little- or big-endian byte-order
+fn encode_operand(val: u64, width: usize, big_endian: bool) -> Vec<u8> {
+    if big_endian {
+        val.to_be_bytes()[8 - width..].to_vec()
+    } else {
+        val.to_le_bytes()[..width].to_vec()
+    }
+}
+
+/// Build input-to-state replacements from logged comparison operands (arg1, arg2, size). For every
+/// operand that can be found in `input`, encoded little- or big-endian at any width both operands 
+/// fit into, replacements with the other operand and its +/-1 variants are generated. The +/-1 
+/// variants help with `<`/`>` comparisons
+pub fn build_i2s_tokens(input: &[u8], cmp_log: &[(u64, u64, usize)]) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut seen: FxHashSet<(Vec<u8>, Vec<u8>)> = FxHashSet::default();
+    let mut tokens: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+
+    for &(arg1, arg2, size) in cmp_log {
+        for (pattern, other) in [(arg1, arg2), (arg2, arg1)] {
+            // Zero-patterns match almost everywhere, so they are not useful
+            if pattern == 0 { continue; }
+
+            for width in [1, 2, 4, 8] {
+                if width > size.max(1) { break; }
+                if width < 8 && (pattern >> (width * 8) != 0 || other >> (width * 8) != 0) {
+                    continue;
+                }
+
+                for big_endian in [false, true] {
+                    let encoded = encode_operand(pattern, width, big_endian);
+                    if !input.windows(width).any(|window| window == encoded) { continue; }
+
+                    for repl in [other, other.wrapping_add(1), other.wrapping_sub(1)] {
+                        let token = (encoded.clone(), encode_operand(repl, width, big_endian));
+                        if token.0 != token.1 && seen.insert(token.clone()) {
+                            tokens.push(token);
+                        }
+                    }
+                }
+            }
+        }
+
+        if tokens.len() >= MAX_I2S_TOKENS { break; }
+    }
+    tokens
+}
+
 /// Read lines from a file
 fn read_lines<P>(file_name: P) -> io::Result<io::Lines<io::BufReader<File>>>
 where P: AsRef<Path>, {
@@ -342,13 +881,163 @@ where P: AsRef<Path>, {
     Ok(io::BufReader::new(file).lines())
 }
 
-/// Parse a provided dictionary file
-pub fn parse_dict(file_name: &str) -> Vec<String> {
-    let mut dict: Vec<String> = Vec::new();
-    if let Ok(lines) = read_lines(file_name) {
-        for line in lines {
-            dict.push(line.unwrap());
+/// Parse a dictionary file in the AFL/libFuzzer format. Every line that is not empty or a `#`
+/// comment holds a token in double quotes, optionally preceded by a name and a level, eg.
+/// `magic@1="\x7fELF"`. Only tokens with a level of at most `max_level` are returned, tokens
+/// without a level are level 0
+pub fn parse_dict(file_name: &str, max_level: u32) -> Result<Vec<Vec<u8>>, String> {
+    let lines = read_lines(file_name)
+        .map_err(|err| format!("Failed to read dictionary {file_name}: {err}"))?;
+
+    let mut dict: Vec<Vec<u8>> = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line.map_err(|err| format!("Failed to read dictionary {file_name}: {err}"))?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (level, token) = parse_dict_line(line)
+            .map_err(|err| format!("{file_name}:{}: {err}", i + 1))?;
+        if level <= max_level {
+            dict.push(token);
+        }
+    }
+    Ok(dict)
+}
+
+/// Parse a single `[name[@level]=]"token"` dictionary line into its level and token
+fn parse_dict_line(line: &str) -> Result<(u32, Vec<u8>), String> {
+    let (header, value) = match line.find('=') {
+        Some(idx) if !line.starts_with('"') => (line[..idx].trim(), line[idx + 1..].trim()),
+        _ => ("", line),
+    };
+
+    // The name is only used to document the token, but the level decides if it is loaded
+    let (name, level) = match header.split_once('@') {
+        Some((name, level)) => {
+            (name, level.parse::<u32>().map_err(|_| format!("Invalid level: {level}"))?)
+        },
+        None => (header, 0),
+    };
+    if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!("Invalid token name: {name}"));
+    }
+
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return Err("Token is not enclosed in double quotes".to_string());
+    }
+
+    // Resolve `\\`, `\"` and `\xNN` escapes
+    let mut token = Vec::new();
+    let mut bytes = value[1..value.len() - 1].bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            token.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'\\') => token.push(b'\\'),
+            Some(b'"')  => token.push(b'"'),
+            Some(b'x')  => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                let hex = String::from_utf8_lossy(&hex);
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("Invalid hex escape: \\x{hex}"));
+                }
+                token.push(u8::from_str_radix(&hex, 16).unwrap());
+            },
+            _ => return Err("Invalid escape sequence".to_string()),
         }
     }
-    dict
+    if token.is_empty() {
+        return Err("Empty token".to_string());
+    }
+    Ok((level, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dict_line_plain() {
+        assert_eq!(parse_dict_line(r#""GET""#), Ok((0, b"GET".to_vec())));
+        assert_eq!(parse_dict_line(r#"kw_get="GET""#), Ok((0, b"GET".to_vec())));
+    }
+
+    #[test]
+    fn dict_line_escapes() {
+        assert_eq!(parse_dict_line(r#""\x7fELF\x00""#), Ok((0, b"\x7fELF\x00".to_vec())));
+        assert_eq!(parse_dict_line(r#""\xAb\xcD""#), Ok((0, vec![0xab, 0xcd])));
+        assert_eq!(parse_dict_line(r#""a\"b""#), Ok((0, b"a\"b".to_vec())));
+        assert_eq!(parse_dict_line(r#""a\\b""#), Ok((0, b"a\\b".to_vec())));
+        assert_eq!(parse_dict_line(r#""\\""#), Ok((0, b"\\".to_vec())));
+    }
+
+    #[test]
+    fn dict_line_embedded_equals() {
+        assert_eq!(parse_dict_line(r#""a=b""#), Ok((0, b"a=b".to_vec())));
+        assert_eq!(parse_dict_line(r#"kw="==""#), Ok((0, b"==".to_vec())));
+        assert_eq!(parse_dict_line(r#"kw@2 = "x=1""#), Ok((2, b"x=1".to_vec())));
+    }
+
+    #[test]
+    fn dict_line_levels() {
+        assert_eq!(parse_dict_line(r#"magic@1="\x7fELF""#), Ok((1, b"\x7fELF".to_vec())));
+        assert_eq!(parse_dict_line(r#"@3="abc""#), Ok((3, b"abc".to_vec())));
+        assert!(parse_dict_line(r#"magic@="abc""#).is_err());
+        assert!(parse_dict_line(r#"magic@x="abc""#).is_err());
+        assert!(parse_dict_line(r#"magic@-1="abc""#).is_err());
+    }
+
+    #[test]
+    fn dict_line_malformed() {
+        // Escapes
+        assert!(parse_dict_line(r#""\q""#).is_err());
+        assert!(parse_dict_line(r#""\x""#).is_err());
+        assert!(parse_dict_line(r#""\x4""#).is_err());
+        assert!(parse_dict_line(r#""\xzz""#).is_err());
+        assert!(parse_dict_line(r#""\x+1""#).is_err());
+        assert!(parse_dict_line(r#""abc\""#).is_err());
+
+        // Quotes
+        assert!(parse_dict_line("abc").is_err());
+        assert!(parse_dict_line(r#"""#).is_err());
+        assert!(parse_dict_line(r#""""#).is_err());
+        assert!(parse_dict_line(r#""abc"#).is_err());
+        assert!(parse_dict_line(r#"abc""#).is_err());
+        assert!(parse_dict_line(r#"kw=abc"#).is_err());
+
+        // Names
+        assert!(parse_dict_line(r#"bad name="abc""#).is_err());
+        assert!(parse_dict_line(r#"a-b="abc""#).is_err());
+    }
+
+    #[test]
+    fn dict_levels_above_max_are_skipped() {
+        let path = std::env::temp_dir().join(format!("zfuzz_dict_{}", std::process::id()));
+        std::fs::write(&path, "# comment\n\n\"zero\"\none@1=\"one\"\ntwo@2=\"two\"\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let level_0 = parse_dict(path, 0);
+        let level_1 = parse_dict(path, 1);
+        let level_9 = parse_dict(path, 9);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(level_0, Ok(vec![b"zero".to_vec()]));
+        assert_eq!(level_1, Ok(vec![b"zero".to_vec(), b"one".to_vec()]));
+        assert_eq!(level_9, Ok(vec![b"zero".to_vec(), b"one".to_vec(), b"two".to_vec()]));
+    }
+
+    #[test]
+    fn dict_reports_line_numbers() {
+        let path = std::env::temp_dir().join(format!("zfuzz_bad_dict_{}", std::process::id()));
+        std::fs::write(&path, "\"ok\"\n\"bad\\q\"\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let res = parse_dict(path, 0);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(res, Err(format!("{path}:2: Invalid escape sequence")));
+    }
 }
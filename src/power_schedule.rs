@@ -0,0 +1,177 @@
+//! Assigns energy (the number of fuzz-cases that are run with a seed once it is picked) to the
+//! inputs of the corpus. This loosely follows afl's `calculate_score` combined with the
+//! exploration-based decay of aflfast. Seeds that execute quickly, contributed a lot of coverage,
+//! or were found recently receive more energy, while slow seeds and seeds that have already been
+//! fuzzed a lot receive less.
+
+use crate::{
+    Input, TargetShared, run_case,
+    execution_state::{ExecEnv, SnapshotContext},
+    targets::targets::HarnessInit,
+    triage::with_instruction_count,
+    configurables::{SEED_ENERGY, MIN_SEED_ENERGY, MAX_SEED_ENERGY},
+};
+
+use unicorn_engine::Unicorn;
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    time::Instant,
+};
+
+/// Corpus-wide averages that the metadata of a single seed is compared against. The sums are
+/// updated whenever inputs are added or calibrated, so the averages don't have to be recomputed
+/// over the entire corpus every time a seed is picked
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CorpusStats {
+    /// Sum of the execution times of calibrated seeds in microseconds
+    total_exec_time: f64,
+
+    /// Sum of the number of instructions executed by calibrated seeds
+    total_instr_count: f64,
+
+    /// Number of calibrated seeds
+    num_calibrated: usize,
+
+    /// Sum of the coverage contributed by all seeds
+    total_coverage: f64,
+
+    /// Sum of the times at which seeds were discovered, in seconds since the fuzzer started
+    total_discovery_time: f64,
+
+    /// Number of seeds in the corpus
+    num_inputs: usize,
+}
+
+impl CorpusStats {
+    /// Compute the statistics of `inputs`
+    pub fn new(inputs: &[Input]) -> Self {
+        let mut stats = CorpusStats::default();
+        inputs.iter().for_each(|input| stats.add_input(input));
+        stats
+    }
+
+    /// Account for an input that was added to the corpus
+    pub fn add_input(&mut self, input: &Input) {
+        if input.calibrated {
+            self.total_exec_time   += input.exec_time;
+            self.total_instr_count += input.instr_count as f64;
+            self.num_calibrated    += 1;
+        }
+        self.total_coverage       += input.coverage as f64;
+        self.total_discovery_time += input.discovery_time;
+        self.num_inputs           += 1;
+    }
+
+    /// Account for a seed of the corpus that was just calibrated and found `cov_count` coverage
+    pub fn add_calibration(&mut self, exec_time: f64, instr_count: u64, cov_count: usize) {
+        self.total_exec_time   += exec_time;
+        self.total_instr_count += instr_count as f64;
+        self.num_calibrated    += 1;
+        self.total_coverage    += cov_count as f64;
+    }
+
+    /// Average execution time of calibrated seeds in microseconds
+    pub fn avg_exec_time(&self) -> f64 {
+        self.total_exec_time / self.num_calibrated.max(1) as f64
+    }
+
+    /// Average number of instructions executed by calibrated seeds
+    pub fn avg_instr_count(&self) -> f64 {
+        self.total_instr_count / self.num_calibrated.max(1) as f64
+    }
+
+    /// Average amount of coverage contributed by a seed
+    pub fn avg_coverage(&self) -> f64 {
+        self.total_coverage / self.num_inputs.max(1) as f64
+    }
+
+    /// Average time at which seeds were discovered, in seconds since the fuzzer started
+    pub fn avg_discovery_time(&self) -> f64 {
+        self.total_discovery_time / self.num_inputs.max(1) as f64
+    }
+}
+
+/// Run `input` once to measure its execution time in microseconds and the number of instructions
+/// it executes. Returns `(exec_time, instr_count, cov_count)` where `cov_count` is the new
+/// coverage found by the run. This is the case for initial seeds that are run for the first time.
+/// The instruction-counting hook adds the same overhead to every calibration, so execution times
+/// can still be compared against each other
+pub fn calibrate(harness_init: &HarnessInit, target_shared: &TargetShared,
+                 exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>,
+                 snapshot: &SnapshotContext, input: &[u8]) -> (f64, u64, usize) {
+    let mut emu_ref = exec_env.borrow_mut();
+    emu_ref.reset_snapshot(unicorn, snapshot).unwrap();
+    emu_ref.fuzz_input.clear();
+    emu_ref.fuzz_input.extend_from_slice(input);
+    drop(emu_ref);
+
+    // Crashes and timeouts are handled once the seed is actually fuzzed, so the result is not
+    // relevant here
+    let start = Instant::now();
+    let (_, instr_count) = with_instruction_count(unicorn, |unicorn| {
+        run_case(harness_init, target_shared, exec_env, unicorn)
+    });
+    let exec_time = start.elapsed().as_secs_f64() * 1_000_000.0;
+    let cov_count = exec_env.borrow().cov_count;
+
+    (exec_time, instr_count, cov_count)
+}
+
+/// Number of fuzz-cases that should be run with `input` the next time it is picked
+pub fn assign_energy(input: &Input, stats: &CorpusStats) -> usize {
+    let mut score = 1.0;
+
+    // Favor fast seeds and punish slow ones. A single timed run is rather noisy, so it is combined
+    // with the deterministic instruction count
+    let (avg_exec_time, avg_instr_count) = (stats.avg_exec_time(), stats.avg_instr_count());
+    if input.calibrated && avg_exec_time > 0.0 && avg_instr_count > 0.0 {
+        let ratio = (input.exec_time / avg_exec_time +
+                     input.instr_count as f64 / avg_instr_count) / 2.0;
+        score *= match ratio {
+            r if r <= 0.1  => 3.0,
+            r if r <= 0.25 => 2.0,
+            r if r <= 0.5  => 1.5,
+            r if r >= 10.0 => 0.1,
+            r if r >= 4.0  => 0.25,
+            r if r >= 2.0  => 0.5,
+            r if r >= 1.33 => 0.75,
+            _ => 1.0,
+        };
+    }
+
+    // Seeds that contributed more coverage are more likely to lead to new coverage again
+    if stats.avg_coverage() > 0.0 {
+        let ratio = input.coverage as f64 / stats.avg_coverage();
+        score *= match ratio {
+            r if r >= 3.0  => 3.0,
+            r if r >= 2.0  => 2.0,
+            r if r >= 1.5  => 1.5,
+            r if r <= 0.33 => 0.5,
+            r if r <= 0.5  => 0.75,
+            _ => 1.0,
+        };
+    }
+
+    // Seeds that were derived from many other seeds tend to reach deeper into the target
+    score *= match input.depth {
+        0..=3   => 1.0,
+        4..=7   => 2.0,
+        8..=13  => 3.0,
+        14..=25 => 4.0,
+        _       => 5.0,
+    };
+
+    // Prefer seeds that were found more recently than the average seed
+    if input.discovery_time > stats.avg_discovery_time() {
+        score *= 1.5;
+    }
+
+    // Exponentially decay the energy of seeds that have already been fuzzed a lot so stale seeds
+    // stop consuming as much time
+    let rounds = (input.times_fuzzed / SEED_ENERGY).min(4);
+    score /= (1 << rounds) as f64;
+
+    ((SEED_ENERGY as f64 * score) as usize).clamp(MIN_SEED_ENERGY, MAX_SEED_ENERGY)
+}
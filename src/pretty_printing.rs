@@ -1,10 +1,11 @@
 use crate::{
     arg_setup::{DEBUG_PRINT, OUTPUT_DIR, INPUT_DIR},
-    Statistics,
-    targets::targets::TARGETS,
+    Statistics, TargetShared,
+    targets::targets::ACTIVE_TARGETS,
 };
 
 use std::time::Duration;
+use std::sync::Arc;
 use core::fmt;
 
 use console::Term;
@@ -68,8 +69,10 @@ pub fn log(color: LogType, msg: &str) {
     }
 }
 
-/// Print out statistics in a nicely formated static screen
-pub fn print_stats(term: &Term, stats: &FxHashMap<usize, Statistics>, elapsed_time: f64, 
+/// Print out statistics in a nicely formated static screen. `target_shared` is used to display the
+/// mutation operator probabilities each target learned
+pub fn print_stats(term: &Term, stats: &FxHashMap<usize, Statistics>, 
+                   target_shared: &FxHashMap<usize, Arc<TargetShared>>, elapsed_time: f64, 
                    last_cov: f64) {
     term.clear_screen().unwrap();
     term.move_cursor_to(0, 2).unwrap();
@@ -143,8 +146,25 @@ pub fn print_stats(term: &Term, stats: &FxHashMap<usize, Statistics>, elapsed_ti
             term.write_line(&format!("-  (0.00-1.00, 1.00 is bad)")).unwrap();   
         }
 
+        // Learned mutation operator probabilities, 2 operators per line. Nothing is shown until an
+        // operator found something
+        let operator_stats = target_shared[&cur_stats.target_id].operator_stats.read();
+        let distribution = operator_stats.distribution();
+        if !distribution.is_empty() {
+            term.move_cursor_down(1).unwrap();
+            term.write_line("\t\t+----Mutation Operators ----+").unwrap();
+            for ops in distribution.chunks(2) {
+                let line: Vec<String> = ops.iter()
+                    .map(|(name, prob)| format!("   {:<18} {:5.1}%", name, prob * 100.0))
+                    .collect();
+                term.write_line(&line.join("   ")).unwrap();
+            }
+        }
+        drop(operator_stats);
+
+        let targets = ACTIVE_TARGETS.get().unwrap();
         let mut cur_target_index = std::usize::MAX;
-        TARGETS.iter().enumerate().for_each(|(i, t)| if t.target_id == cur_stats.target_id {
+        targets.iter().enumerate().for_each(|(i, t)| if t.target_id == cur_stats.target_id {
             cur_target_index = i;
         });
 
@@ -155,13 +175,13 @@ pub fn print_stats(term: &Term, stats: &FxHashMap<usize, Statistics>, elapsed_ti
         term.move_cursor_up(1).unwrap();
         term.write_line(&format!("   Output Dir: {}", OUTPUT_DIR.get().unwrap())).unwrap();
         term.write_line(&format!("   Num Threads: {}", 
-                                 TARGETS[cur_target_index].num_threads)).unwrap();
+                                 targets[cur_target_index].num_threads)).unwrap();
         term.move_cursor_right(27).unwrap();
         term.move_cursor_up(1).unwrap();
         term.write_line(&format!("   Instr Timeout set: {}", 
-                                 TARGETS[cur_target_index].instr_timeout)).unwrap();
+                                 targets[cur_target_index].instr_timeout)).unwrap();
         term.write_line(&format!("   Time Timeout set: {}", 
-                                 TARGETS[cur_target_index].time_timeout)).unwrap();
+                                 targets[cur_target_index].time_timeout)).unwrap();
 
         term.move_cursor_down(2).unwrap();
 
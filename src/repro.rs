@@ -0,0 +1,66 @@
+//! Single-input replay mode (`zfuzz repro -t <target> <file>`). Runs one input through a target
+//! with all sanitizing hooks enabled and prints the outcome, the syscalls it invoked and the
+//! output it wrote to stdout/stderr. Useful to confirm fixes and to build regression suites from
+//! `out/*/crashes`.
+
+use crate::{
+    TargetShared, setup_fuzz_env, create_snapshot, run_case, error_exit,
+    targets::targets::find_target,
+    triage::{CrashInfo, is_crash, crash_pc},
+};
+
+use unicorn_engine::unicorn_const::uc_error;
+
+use std::sync::Arc;
+
+/// Run `file` through the target with `target_id` once. Returns the error the case ended with, so
+/// the process exits with a non-zero status if the input crashed or timed out
+pub fn repro(target_id: usize, file: &str) -> Result<(), uc_error> {
+    let harness_init = find_target(target_id).unwrap_or_else(|| {
+        error_exit(&format!("Target {target_id} does not exist"));
+    });
+    let input = std::fs::read(file).unwrap_or_else(|_| {
+        error_exit(&format!("Failed to read input file: {file}"));
+    });
+
+    // Setup the target the same way the fuzzer does, but with syscall-tracing enabled
+    let snapshot = create_snapshot(&harness_init);
+    let target_shared = Arc::new(TargetShared::default());
+    let (exec_env, mut unicorn) = setup_fuzz_env(&harness_init, &target_shared, &snapshot);
+
+    let mut emu_ref = exec_env.borrow_mut();
+    emu_ref.reset_snapshot(&mut unicorn, &snapshot).unwrap();
+    emu_ref.trace_syscalls = true;
+    emu_ref.fuzz_input.extend_from_slice(&input);
+    drop(emu_ref);
+
+    let case_res = run_case(&harness_init, &target_shared, &exec_env, &mut unicorn);
+    let exec_env = exec_env.borrow();
+
+    println!("Target:   {target_id}");
+    println!("Input:    {file} ({} bytes)", input.len());
+    match case_res {
+        Ok(()) => println!("Result:   OK"),
+        Err(err) => println!("Result:   {err:?}"),
+    }
+    println!("Pc:       {:#x}", unicorn.get_pc().unwrap());
+
+    println!("\nSyscalls ({}):", exec_env.syscall_trace.len());
+    for (num, pc) in &exec_env.syscall_trace {
+        println!("    {num:<4} @ {pc:#x}");
+    }
+
+    println!("\nGuest output ({} bytes):", exec_env.guest_output.len());
+    println!("{}", String::from_utf8_lossy(&exec_env.guest_output));
+
+    // Print the same crash-report that the fuzzer saves for unique crashes
+    if let Err(err) = case_res {
+        if is_crash(err) {
+            let pc = crash_pc(&unicorn, err);
+            let crash_info = CrashInfo::collect(&unicorn, &exec_env, err, pc);
+            println!("Crash report:\n{}", serde_json::to_string_pretty(&crash_info).unwrap());
+        }
+    }
+
+    case_res
+}
@@ -0,0 +1,604 @@
+//! Persistent snapshot files (`zfuzz snapshot -t <target> -o <file>`). The state a target is in
+//! once it is initialized (memory layout and contents, registers, open files and allocator state)
+//! is saved to a single compressed file. Harness configurations can load it through the `snapshot`
+//! loader, so the fuzzer and the single-input modes start from it directly instead of re-running
+//! the original loader. The checksum of a snapshot is printed whenever it is created or loaded,
+//! which makes it easy to confirm that two machines are fuzzing the identical snapshot.
+//!
+//! File layout: `MAGIC | version: u32 | checksum: u64 | payload-size: u64 | zlib(payload)`. The
+//! checksum is the xxhash64 of the uncompressed payload and all integers are little-endian.
+//!
+//! Unicorn's cpu-context is opaque, so the registers are saved individually. This covers the
+//! general purpose registers, the pc, the flags, (on x86_64) the segment registers, and the
+//! floating point and vector state (x87, SSE, MXCSR on x86 and F0-F31, FCSR on riscv64).
+
+use crate::{
+    execution_state::{ExecEnv, File, FileType, SnapshotContext, take_snapshot},
+    targets::{targets::{find_target, init_target}, harness_config::ArchConfig},
+    error_exit,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use fasthash::{xx::Hash64, FastHash};
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use unicorn_engine::{
+    Unicorn, RegisterX86, RegisterRISCV,
+    unicorn_const::{Arch, Mode, Permission, uc_error},
+};
+
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+/// Identifies zfuzz snapshot files
+const MAGIC: &[u8; 8] = b"ZFUZZSNP";
+
+/// Version of the file format. Bumped whenever the layout of the payload changes
+pub const SNAPSHOT_VERSION: u32 = 2;
+
+/// Size of the header that precedes the compressed payload
+const HEADER_SIZE: usize = 28;
+
+/// Registers saved for x86_64 targets
+const X86_64_REGS: [RegisterX86; 30] = [
+    RegisterX86::RAX, RegisterX86::RBX, RegisterX86::RCX, RegisterX86::RDX, RegisterX86::RSI,
+    RegisterX86::RDI, RegisterX86::RBP, RegisterX86::RSP, RegisterX86::R8,  RegisterX86::R9,
+    RegisterX86::R10, RegisterX86::R11, RegisterX86::R12, RegisterX86::R13, RegisterX86::R14,
+    RegisterX86::R15, RegisterX86::RIP, RegisterX86::EFLAGS, RegisterX86::CS, RegisterX86::SS,
+    RegisterX86::DS,  RegisterX86::ES,  RegisterX86::FS,  RegisterX86::GS,
+    RegisterX86::FS_BASE, RegisterX86::GS_BASE,
+    RegisterX86::FPCW, RegisterX86::FPSW, RegisterX86::FPTAG, RegisterX86::MXCSR,
+];
+
+/// Registers saved for x86_32 targets. Unicorn crashes when the segment registers are written in
+/// 32-bit mode (see `resolve_x86_32_regs`), so they are not included
+const X86_32_REGS: [RegisterX86; 14] = [
+    RegisterX86::EAX, RegisterX86::EBX, RegisterX86::ECX, RegisterX86::EDX, RegisterX86::ESI,
+    RegisterX86::EDI, RegisterX86::EBP, RegisterX86::ESP, RegisterX86::EIP, RegisterX86::EFLAGS,
+    RegisterX86::FPCW, RegisterX86::FPSW, RegisterX86::FPTAG, RegisterX86::MXCSR,
+];
+
+/// Registers saved for riscv64 targets
+const RISCV64_REGS: [RegisterRISCV; 65] = [
+    RegisterRISCV::X1,  RegisterRISCV::X2,  RegisterRISCV::X3,  RegisterRISCV::X4,
+    RegisterRISCV::X5,  RegisterRISCV::X6,  RegisterRISCV::X7,  RegisterRISCV::X8,
+    RegisterRISCV::X9,  RegisterRISCV::X10, RegisterRISCV::X11, RegisterRISCV::X12,
+    RegisterRISCV::X13, RegisterRISCV::X14, RegisterRISCV::X15, RegisterRISCV::X16,
+    RegisterRISCV::X17, RegisterRISCV::X18, RegisterRISCV::X19, RegisterRISCV::X20,
+    RegisterRISCV::X21, RegisterRISCV::X22, RegisterRISCV::X23, RegisterRISCV::X24,
+    RegisterRISCV::X25, RegisterRISCV::X26, RegisterRISCV::X27, RegisterRISCV::X28,
+    RegisterRISCV::X29, RegisterRISCV::X30, RegisterRISCV::X31, RegisterRISCV::PC,
+    RegisterRISCV::F0,  RegisterRISCV::F1,  RegisterRISCV::F2,  RegisterRISCV::F3,
+    RegisterRISCV::F4,  RegisterRISCV::F5,  RegisterRISCV::F6,  RegisterRISCV::F7,
+    RegisterRISCV::F8,  RegisterRISCV::F9,  RegisterRISCV::F10, RegisterRISCV::F11,
+    RegisterRISCV::F12, RegisterRISCV::F13, RegisterRISCV::F14, RegisterRISCV::F15,
+    RegisterRISCV::F16, RegisterRISCV::F17, RegisterRISCV::F18, RegisterRISCV::F19,
+    RegisterRISCV::F20, RegisterRISCV::F21, RegisterRISCV::F22, RegisterRISCV::F23,
+    RegisterRISCV::F24, RegisterRISCV::F25, RegisterRISCV::F26, RegisterRISCV::F27,
+    RegisterRISCV::F28, RegisterRISCV::F29, RegisterRISCV::F30, RegisterRISCV::F31,
+    RegisterRISCV::FCSR,
+];
+
+/// Registers wider than 64 bits saved for x86_64 targets (x87 stack and SSE registers)
+const X86_64_LONG_REGS: [RegisterX86; 24] = [
+    RegisterX86::ST0,  RegisterX86::ST1,  RegisterX86::ST2,  RegisterX86::ST3,
+    RegisterX86::ST4,  RegisterX86::ST5,  RegisterX86::ST6,  RegisterX86::ST7,
+    RegisterX86::XMM0, RegisterX86::XMM1, RegisterX86::XMM2,  RegisterX86::XMM3,
+    RegisterX86::XMM4, RegisterX86::XMM5, RegisterX86::XMM6,  RegisterX86::XMM7,
+    RegisterX86::XMM8, RegisterX86::XMM9, RegisterX86::XMM10, RegisterX86::XMM11,
+    RegisterX86::XMM12, RegisterX86::XMM13, RegisterX86::XMM14, RegisterX86::XMM15,
+];
+
+/// Registers wider than 64 bits saved for x86_32 targets, which only have 8 SSE registers
+const X86_32_LONG_REGS: [RegisterX86; 16] = [
+    RegisterX86::ST0,  RegisterX86::ST1,  RegisterX86::ST2,  RegisterX86::ST3,
+    RegisterX86::ST4,  RegisterX86::ST5,  RegisterX86::ST6,  RegisterX86::ST7,
+    RegisterX86::XMM0, RegisterX86::XMM1, RegisterX86::XMM2, RegisterX86::XMM3,
+    RegisterX86::XMM4, RegisterX86::XMM5, RegisterX86::XMM6, RegisterX86::XMM7,
+];
+
+/// Upper bound on the ratio between the decompressed and compressed size of a payload that is
+/// trusted when preallocating memory for it. The size in the header is not covered by the
+/// checksum, so a corrupted header could otherwise request arbitrary amounts of memory
+const MAX_PREALLOC_RATIO: usize = 64;
+
+/// Snapshot files that were loaded, indexed by their path. Only the checksums are kept so they can
+/// be reported, the decoded contents are dropped once the target was restored from them
+static LOADED: OnceLock<Mutex<FxHashMap<String, LoadedSnapshot>>> = OnceLock::new();
+
+/// Entry of `LOADED`
+#[derive(Debug)]
+struct LoadedSnapshot {
+    /// Checksum of the file
+    checksum: u64,
+
+    /// Set once the checksum has been printed
+    reported: bool,
+}
+
+/// Target state stored in a snapshot file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SnapshotFile {
+    /// Architecture/mode of the target
+    pub arch: ArchConfig,
+
+    /// All mapped memory regions (address, size, permissions). This includes regions without
+    /// permissions, such as the part of the allocator's backing that has not been handed out yet
+    pub regions: Vec<(u64, usize, Permission)>,
+
+    /// Contents of all accessible pages, sorted by address
+    pub pages: Vec<(u64, Vec<u8>)>,
+
+    /// Register values, in the order of the register-list of `arch`
+    pub regs: Vec<u64>,
+
+    /// Values of registers wider than 64 bits, in the order of the long register-list of `arch`
+    pub long_regs: Vec<Vec<u8>>,
+
+    /// File descriptors that were active when the snapshot was taken
+    pub fd_list: Vec<File>,
+
+    /// Address of the next free region of memory that the allocator will use for allocations
+    pub alloc_addr: u64,
+
+    /// Allocations made using mmap (address, size)
+    pub mmap_allocations: Vec<(u64, usize)>,
+
+    /// Address of last block hit before snapshot was taken
+    pub prev_block: u64,
+}
+
+/// Unicorn register-ids of the registers saved for `arch`
+fn arch_regs(arch: ArchConfig) -> Vec<i32> {
+    match arch {
+        ArchConfig::X86_64  => X86_64_REGS.iter().map(|&reg| reg.into()).collect(),
+        ArchConfig::X86_32  => X86_32_REGS.iter().map(|&reg| reg.into()).collect(),
+        ArchConfig::Riscv64 => RISCV64_REGS.iter().map(|&reg| reg.into()).collect(),
+    }
+}
+
+/// Unicorn register-ids of the registers wider than 64 bits saved for `arch`
+fn arch_long_regs(arch: ArchConfig) -> Vec<i32> {
+    match arch {
+        ArchConfig::X86_64  => X86_64_LONG_REGS.iter().map(|&reg| reg.into()).collect(),
+        ArchConfig::X86_32  => X86_32_LONG_REGS.iter().map(|&reg| reg.into()).collect(),
+        ArchConfig::Riscv64 => Vec::new(),
+    }
+}
+
+/// Determine the architecture/mode the emulator was created with
+fn unicorn_arch(uc: &Unicorn<'_, ()>) -> Result<ArchConfig, uc_error> {
+    match (uc.get_arch(), uc.get_mode()) {
+        (Arch::X86,   Mode::MODE_64) => Ok(ArchConfig::X86_64),
+        (Arch::X86,   Mode::MODE_32) => Ok(ArchConfig::X86_32),
+        (Arch::RISCV, Mode::RISCV64) => Ok(ArchConfig::Riscv64),
+        _ => Err(uc_error::ARCH),
+    }
+}
+
+/// Numeric representation of an architecture used in the payload
+fn arch_id(arch: ArchConfig) -> u8 {
+    match arch {
+        ArchConfig::X86_64  => 0,
+        ArchConfig::X86_32  => 1,
+        ArchConfig::Riscv64 => 2,
+    }
+}
+
+/// Numeric representation of a filetype used in the payload
+fn ftype_id(ftype: FileType) -> u8 {
+    match ftype {
+        FileType::STDIN     => 0,
+        FileType::STDOUT    => 1,
+        FileType::STDERR    => 2,
+        FileType::FUZZINPUT => 3,
+        FileType::OTHER     => 4,
+        FileType::INVALID   => 5,
+    }
+}
+
+impl SnapshotFile {
+    /// Collect the state of a freshly initialized target. `snapshot` has to be taken from the same
+    /// `exec_env` and emulator
+    pub fn capture(exec_env: &ExecEnv, uc: &Unicorn<'_, ()>, snapshot: &SnapshotContext)
+            -> Result<Self, uc_error> {
+        let arch = unicorn_arch(uc)?;
+
+        let regions = uc.mem_regions()?.iter()
+            .map(|region| (region.begin, (region.end + 1 - region.begin) as usize, region.perms))
+            .collect();
+
+        let pages = snapshot.image.pages()
+            .map(|(addr, data)| (addr as u64, data.to_vec()))
+            .collect();
+
+        let regs = arch_regs(arch).into_iter()
+            .map(|reg| uc.reg_read(reg))
+            .collect::<Result<Vec<u64>, uc_error>>()?;
+
+        let long_regs = arch_long_regs(arch).into_iter()
+            .map(|reg| uc.reg_read_long(reg).map(|val| val.to_vec()))
+            .collect::<Result<Vec<Vec<u8>>, uc_error>>()?;
+
+        let mut mmap_allocations: Vec<(u64, usize)> = exec_env.mmap_allocations.iter()
+            .map(|(addr, size)| (*addr, *size))
+            .collect();
+        mmap_allocations.sort();
+
+        Ok(SnapshotFile {
+            arch,
+            regions,
+            pages,
+            regs,
+            long_regs,
+            fd_list: snapshot.fd_list.clone(),
+            alloc_addr: snapshot.alloc_addr,
+            mmap_allocations,
+            prev_block: snapshot.prev_block,
+        })
+    }
+
+    /// Map the saved memory into `uc` and restore registers, files and allocator state
+    pub fn restore(&self, exec_env: &Rc<RefCell<ExecEnv>>, uc: &mut Unicorn<'_, ()>)
+            -> Result<(), uc_error> {
+        if unicorn_arch(uc)? != self.arch {
+            return Err(uc_error::ARCH);
+        }
+
+        for (addr, size, perms) in &self.regions {
+            uc.mem_map(*addr, *size, *perms)?;
+        }
+        for (addr, data) in &self.pages {
+            uc.mem_write(*addr, data)?;
+        }
+        for (reg, val) in arch_regs(self.arch).into_iter().zip(&self.regs) {
+            uc.reg_write(reg, *val)?;
+        }
+        for (reg, val) in arch_long_regs(self.arch).into_iter().zip(&self.long_regs) {
+            uc.reg_write_long(reg, val)?;
+        }
+
+        let mut exec_env = exec_env.borrow_mut();
+        exec_env.fd_list          = self.fd_list.clone();
+        exec_env.alloc_addr       = self.alloc_addr;
+        exec_env.mmap_allocations = self.mmap_allocations.iter().copied().collect();
+        exec_env.prev_block       = self.prev_block;
+        Ok(())
+    }
+
+    /// Serialize the snapshot into its uncompressed payload
+    fn encode(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = Vec::new();
+        out.write_u8(arch_id(self.arch)).unwrap();
+
+        out.write_u64::<LittleEndian>(self.regions.len() as u64).unwrap();
+        for (addr, size, perms) in &self.regions {
+            out.write_u64::<LittleEndian>(*addr).unwrap();
+            out.write_u64::<LittleEndian>(*size as u64).unwrap();
+            out.write_u32::<LittleEndian>(perms.bits()).unwrap();
+        }
+
+        out.write_u64::<LittleEndian>(self.pages.len() as u64).unwrap();
+        for (addr, data) in &self.pages {
+            out.write_u64::<LittleEndian>(*addr).unwrap();
+            out.write_u64::<LittleEndian>(data.len() as u64).unwrap();
+            out.extend_from_slice(data);
+        }
+
+        out.write_u64::<LittleEndian>(self.regs.len() as u64).unwrap();
+        for val in &self.regs {
+            out.write_u64::<LittleEndian>(*val).unwrap();
+        }
+
+        out.write_u64::<LittleEndian>(self.long_regs.len() as u64).unwrap();
+        for val in &self.long_regs {
+            out.write_u64::<LittleEndian>(val.len() as u64).unwrap();
+            out.extend_from_slice(val);
+        }
+
+        out.write_u64::<LittleEndian>(self.fd_list.len() as u64).unwrap();
+        for file in &self.fd_list {
+            out.write_u8(ftype_id(file.ftype)).unwrap();
+            match &file.backing {
+                Some(backing) => {
+                    out.write_u8(1).unwrap();
+                    out.write_u64::<LittleEndian>(backing.len() as u64).unwrap();
+                    out.extend_from_slice(backing);
+                },
+                None => out.write_u8(0).unwrap(),
+            }
+            match file.cursor {
+                Some(cursor) => {
+                    out.write_u8(1).unwrap();
+                    out.write_u64::<LittleEndian>(cursor as u64).unwrap();
+                },
+                None => out.write_u8(0).unwrap(),
+            }
+        }
+
+        out.write_u64::<LittleEndian>(self.alloc_addr).unwrap();
+        out.write_u64::<LittleEndian>(self.mmap_allocations.len() as u64).unwrap();
+        for (addr, size) in &self.mmap_allocations {
+            out.write_u64::<LittleEndian>(*addr).unwrap();
+            out.write_u64::<LittleEndian>(*size as u64).unwrap();
+        }
+        out.write_u64::<LittleEndian>(self.prev_block).unwrap();
+        out
+    }
+
+    /// Parse an uncompressed payload
+    fn decode(mut data: &[u8]) -> Result<Self, String> {
+        let err = |_| "Snapshot payload is truncated".to_string();
+
+        let arch = match data.read_u8().map_err(err)? {
+            0 => ArchConfig::X86_64,
+            1 => ArchConfig::X86_32,
+            2 => ArchConfig::Riscv64,
+            id => return Err(format!("Unknown architecture in snapshot: {id}")),
+        };
+
+        let mut regions = Vec::new();
+        for _ in 0..data.read_u64::<LittleEndian>().map_err(err)? {
+            let addr  = data.read_u64::<LittleEndian>().map_err(err)?;
+            let size  = data.read_u64::<LittleEndian>().map_err(err)? as usize;
+            let perms = Permission::from_bits_truncate(data.read_u32::<LittleEndian>()
+                                                      .map_err(err)?);
+            regions.push((addr, size, perms));
+        }
+
+        let mut pages = Vec::new();
+        for _ in 0..data.read_u64::<LittleEndian>().map_err(err)? {
+            let addr = data.read_u64::<LittleEndian>().map_err(err)?;
+            let page = read_bytes(&mut data)?;
+            pages.push((addr, page));
+        }
+
+        let mut regs = Vec::new();
+        for _ in 0..data.read_u64::<LittleEndian>().map_err(err)? {
+            regs.push(data.read_u64::<LittleEndian>().map_err(err)?);
+        }
+        let mut long_regs = Vec::new();
+        for _ in 0..data.read_u64::<LittleEndian>().map_err(err)? {
+            long_regs.push(read_bytes(&mut data)?);
+        }
+        if regs.len() != arch_regs(arch).len() || long_regs.len() != arch_long_regs(arch).len() {
+            return Err("Snapshot does not hold the registers of its architecture".to_string());
+        }
+
+        let mut fd_list = Vec::new();
+        for _ in 0..data.read_u64::<LittleEndian>().map_err(err)? {
+            let ftype = match data.read_u8().map_err(err)? {
+                0 => FileType::STDIN,
+                1 => FileType::STDOUT,
+                2 => FileType::STDERR,
+                3 => FileType::FUZZINPUT,
+                4 => FileType::OTHER,
+                5 => FileType::INVALID,
+                id => return Err(format!("Unknown filetype in snapshot: {id}")),
+            };
+            let backing = match data.read_u8().map_err(err)? {
+                0 => None,
+                _ => Some(read_bytes(&mut data)?),
+            };
+            let cursor = match data.read_u8().map_err(err)? {
+                0 => None,
+                _ => Some(data.read_u64::<LittleEndian>().map_err(err)? as usize),
+            };
+            fd_list.push(File { ftype, backing, cursor });
+        }
+
+        let alloc_addr = data.read_u64::<LittleEndian>().map_err(err)?;
+        let mut mmap_allocations = Vec::new();
+        for _ in 0..data.read_u64::<LittleEndian>().map_err(err)? {
+            let addr = data.read_u64::<LittleEndian>().map_err(err)?;
+            let size = data.read_u64::<LittleEndian>().map_err(err)? as usize;
+            mmap_allocations.push((addr, size));
+        }
+        let prev_block = data.read_u64::<LittleEndian>().map_err(err)?;
+
+        Ok(SnapshotFile {
+            arch,
+            regions,
+            pages,
+            regs,
+            long_regs,
+            fd_list,
+            alloc_addr,
+            mmap_allocations,
+            prev_block,
+        })
+    }
+
+    /// Compress the snapshot and write it to `path`. Returns the checksum of the snapshot
+    pub fn save(&self, path: &str) -> Result<u64, String> {
+        let payload  = self.encode();
+        let checksum = Hash64::hash(&payload);
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut out: Vec<u8> = Vec::with_capacity(HEADER_SIZE + compressed.len());
+        out.extend_from_slice(MAGIC);
+        out.write_u32::<LittleEndian>(SNAPSHOT_VERSION).unwrap();
+        out.write_u64::<LittleEndian>(checksum).unwrap();
+        out.write_u64::<LittleEndian>(payload.len() as u64).unwrap();
+        out.extend_from_slice(&compressed);
+
+        std::fs::write(path, out).map_err(|e| format!("Failed to write {path}: {e}"))?;
+        Ok(checksum)
+    }
+
+    /// Read a snapshot from `path` and verify its version and checksum. Returns the snapshot
+    /// together with its checksum
+    pub fn load(path: &str) -> Result<(Self, u64), String> {
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+        if data.len() < HEADER_SIZE || &data[..8] != MAGIC {
+            return Err(format!("{path} is not a zfuzz snapshot file"));
+        }
+
+        let mut header = &data[8..HEADER_SIZE];
+        let version  = header.read_u32::<LittleEndian>().unwrap();
+        let checksum = header.read_u64::<LittleEndian>().unwrap();
+        let size     = header.read_u64::<LittleEndian>().unwrap() as usize;
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("{path} uses snapshot version {version}, but this build of zfuzz \
+                               only supports version {SNAPSHOT_VERSION}"));
+        }
+
+        // Never decompress more than the header claims, so a corrupted size can't be used to
+        // exhaust memory
+        let compressed = &data[HEADER_SIZE..];
+        let mut payload: Vec<u8> =
+            Vec::with_capacity(size.min(compressed.len() * MAX_PREALLOC_RATIO));
+        ZlibDecoder::new(compressed).take((size as u64).saturating_add(1)).read_to_end(&mut payload)
+            .map_err(|e| format!("Failed to decompress {path}: {e}"))?;
+        if payload.len() != size || Hash64::hash(&payload) != checksum {
+            return Err(format!("{path} is corrupted, its checksum does not match its contents"));
+        }
+
+        Ok((Self::decode(&payload)?, checksum))
+    }
+}
+
+/// Read a length-prefixed byte-string
+fn read_bytes(data: &mut &[u8]) -> Result<Vec<u8>, String> {
+    let len = data.read_u64::<LittleEndian>()
+        .map_err(|_| "Snapshot payload is truncated".to_string())? as usize;
+    if data.len() < len {
+        return Err("Snapshot payload is truncated".to_string());
+    }
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    Ok(bytes.to_vec())
+}
+
+/// Load the snapshot file at `path` and record its checksum so it can be reported
+pub fn load_snapshot_file(path: &str) -> SnapshotFile {
+    let (snapshot, checksum) = SnapshotFile::load(path).unwrap_or_else(|err| error_exit(&err));
+    let mut loaded = LOADED.get_or_init(|| Mutex::new(FxHashMap::default())).lock();
+    loaded.entry(path.to_string()).or_insert(LoadedSnapshot { checksum, reported: false });
+    snapshot
+}
+
+/// Print the checksums of snapshot files that were loaded since the last call. This is only called
+/// from the main thread before the worker threads of a target are started, so it does not
+/// interfere with the statistics screen
+pub fn report_loaded_snapshots() {
+    let Some(loaded) = LOADED.get() else { return; };
+    for (path, entry) in loaded.lock().iter_mut().filter(|(_, entry)| !entry.reported) {
+        println!("Loaded snapshot {path} (checksum: {:016x})", entry.checksum);
+        entry.reported = true;
+    }
+}
+
+/// Initialize the target with `target_id` and save its initial state to `output`
+pub fn save_snapshot(target_id: usize, output: &str) -> Result<(), uc_error> {
+    if find_target(target_id).is_none() {
+        error_exit(&format!("Target {target_id} does not exist"));
+    }
+
+    let (exec_env, unicorn) = init_target(target_id)?;
+    let snapshot = take_snapshot(&exec_env, &unicorn)?;
+    let snapshot_file = SnapshotFile::capture(&exec_env.borrow(), &unicorn, &snapshot)?;
+    let checksum = snapshot_file.save(output).unwrap_or_else(|err| error_exit(&err));
+
+    println!("Target:   {target_id}");
+    println!("Snapshot: {output} (version {SNAPSHOT_VERSION})");
+    println!("Regions:  {}", snapshot_file.regions.len());
+    println!("Pages:    {}", snapshot_file.pages.len());
+    println!("Checksum: {checksum:016x}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small snapshot that uses every part of the payload
+    fn snapshot() -> SnapshotFile {
+        SnapshotFile {
+            arch:             ArchConfig::X86_64,
+            regions:          vec![(0x1000, 0x2000, Permission::READ | Permission::EXEC),
+                                   (0x8000, 0x1000, Permission::NONE)],
+            pages:            vec![(0x1000, vec![0x90; 0x1000]), (0x2000, vec![0xcc; 0x1000])],
+            regs:             (0..X86_64_REGS.len() as u64).collect(),
+            long_regs:        vec![vec![0x41; 16]; X86_64_LONG_REGS.len()],
+            fd_list:          vec![
+                File { ftype: FileType::STDIN,     backing: None,              cursor: None },
+                File { ftype: FileType::FUZZINPUT, backing: None,              cursor: Some(0) },
+                File { ftype: FileType::OTHER,     backing: Some(vec![1, 2]), cursor: Some(1) },
+            ],
+            alloc_addr:       0x4000,
+            mmap_allocations: vec![(0x6000, 0x1000)],
+            prev_block:       0x1234,
+        }
+    }
+
+    /// Path of a temporary file that is unique to the test `name`
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("zfuzz_snapshot_{}_{name}", std::process::id()))
+            .to_str().unwrap().to_string()
+    }
+
+    /// Save `snapshot()` to a temporary file, apply `corrupt` to its raw contents and load it
+    fn load_corrupted(name: &str, corrupt: impl FnOnce(&mut Vec<u8>)) -> Result<(), String> {
+        let path = temp_path(name);
+        snapshot().save(&path).unwrap();
+        let mut data = std::fs::read(&path).unwrap();
+        corrupt(&mut data);
+        std::fs::write(&path, data).unwrap();
+        let result = SnapshotFile::load(&path).map(|_| ());
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round_trip");
+        let checksum = snapshot().save(&path).unwrap();
+        let loaded = SnapshotFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let (loaded, loaded_checksum) = loaded.unwrap();
+        assert_eq!(loaded, snapshot());
+        assert_eq!(loaded_checksum, checksum);
+    }
+
+    #[test]
+    fn rejects_corrupted_payload() {
+        let err = load_corrupted("corrupted_payload", |data| {
+            // Rewrite the payload with a single flipped byte so it still decompresses
+            let mut payload = snapshot().encode();
+            payload[100] ^= 1;
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&payload).unwrap();
+            data.truncate(HEADER_SIZE);
+            data.extend_from_slice(&encoder.finish().unwrap());
+        }).unwrap_err();
+        assert!(err.contains("checksum"));
+    }
+
+    #[test]
+    fn rejects_wrong_version() {
+        let err = load_corrupted("wrong_version", |data| {
+            data[8..12].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        }).unwrap_err();
+        assert!(err.contains("version"));
+    }
+
+    #[test]
+    fn rejects_oversized_header() {
+        for size in [u64::MAX, 1 << 40] {
+            load_corrupted("oversized_header", |data| {
+                let compressed = (data.len() - HEADER_SIZE) as u64;
+                assert!(size > compressed * MAX_PREALLOC_RATIO as u64);
+                data[20..28].copy_from_slice(&size.to_le_bytes());
+            }).unwrap_err();
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Memory image of the root snapshot that is shared by all worker threads of a target. The
+//! accessible memory of the target is copied into a single memfd-backed image once. Workers build
+//! their emulator by mapping the regions of this image copy-on-write (`MAP_PRIVATE`), so pages are
+//! only duplicated once a worker writes to them. Resetting a worker copies the dirtied pages back
+//! from a read-only view of the same image.
+
+use unicorn_engine::{
+    Unicorn,
+    unicorn_const::{Permission, uc_error},
+};
+
+use std::ptr::null_mut;
+
+/// Region of guest memory stored in the image
+#[derive(Debug, Clone, Copy)]
+pub struct ImageRegion {
+    /// Guest address the region is mapped at
+    pub addr: u64,
+
+    /// Size of the region in bytes, always a multiple of the page size
+    pub size: usize,
+
+    /// Permissions of the region when the snapshot was taken
+    pub perms: Permission,
+
+    /// Offset of the region's data in the image
+    pub offset: usize,
+}
+
+/// Shared, read-only memory image of a snapshot
+#[derive(Debug)]
+pub struct SnapshotImage {
+    /// Memfd that backs the image. Workers map their private copies from this
+    fd: libc::c_int,
+
+    /// Read-only view of the entire image
+    base: *const u8,
+
+    /// Size of the image in bytes
+    size: usize,
+
+    /// Regions stored in the image, sorted by address
+    regions: Vec<ImageRegion>,
+}
+
+// The image is never written after creation, so it can safely be read from multiple threads
+unsafe impl Send for SnapshotImage {}
+unsafe impl Sync for SnapshotImage {}
+
+impl SnapshotImage {
+    /// Copy the memory of `regions` (address, size, permissions) from `uc` into a new image
+    pub fn new(uc: &Unicorn<'_, ()>, mut regions: Vec<(u64, usize, Permission)>)
+            -> Result<Self, uc_error> {
+        regions.sort_by_key(|(addr, _, _)| *addr);
+
+        // The image can not be empty since mapping it would fail
+        let size = regions.iter().map(|(_, size, _)| *size).sum::<usize>().max(0x1000);
+
+        let fd = unsafe { libc::memfd_create(c"zfuzz_snapshot".as_ptr(), libc::MFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(uc_error::NOMEM);
+        }
+        if unsafe { libc::ftruncate(fd, size as libc::off_t) } != 0 {
+            unsafe { libc::close(fd); }
+            return Err(uc_error::NOMEM);
+        }
+
+        let base = unsafe {
+            libc::mmap(null_mut(), size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                       fd, 0)
+        };
+        if base == libc::MAP_FAILED {
+            unsafe { libc::close(fd); }
+            return Err(uc_error::NOMEM);
+        }
+
+        // From here on the image is unmapped and its fd closed on drop, so none of the error
+        // paths below leak them
+        let mut image = SnapshotImage {
+            fd,
+            base: base as *const u8,
+            size,
+            regions: Vec::with_capacity(regions.len()),
+        };
+
+        // Copy the memory of every region into the image
+        let mut offset = 0;
+        for (addr, size, perms) in regions {
+            let data = unsafe {
+                std::slice::from_raw_parts_mut((base as *mut u8).add(offset), size)
+            };
+            uc.mem_read(addr, data)?;
+
+            image.regions.push(ImageRegion { addr, size, perms, offset });
+            offset += size;
+        }
+
+        // Nothing writes to the image after this point
+        if unsafe { libc::mprotect(base, size, libc::PROT_READ) } != 0 {
+            return Err(uc_error::NOMEM);
+        }
+
+        Ok(image)
+    }
+
+    /// Regions stored in the image
+    pub fn regions(&self) -> &[ImageRegion] {
+        &self.regions
+    }
+
+    /// Original contents of the page at `addr`, or `None` if the page is not part of the image
+    pub fn page(&self, addr: usize) -> Option<&[u8]> {
+        let addr = addr as u64 & !0xfff;
+        let idx = self.regions.partition_point(|r| r.addr + r.size as u64 <= addr);
+        let region = self.regions.get(idx).filter(|r| r.addr <= addr)?;
+
+        let offset = region.offset + (addr - region.addr) as usize;
+        Some(unsafe { std::slice::from_raw_parts(self.base.add(offset), 0x1000) })
+    }
+
+    /// Iterate over all pages of the image (address, contents)
+    pub fn pages(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.regions.iter().flat_map(move |region| {
+            (0..region.size).step_by(0x1000).map(move |i| {
+                let page = unsafe {
+                    std::slice::from_raw_parts(self.base.add(region.offset + i), 0x1000)
+                };
+                (region.addr as usize + i, page)
+            })
+        })
+    }
+
+    /// Map all regions of the image into `uc` as copy-on-write mappings of the image. None of the
+    /// regions may be mapped in `uc` yet. The returned mappings back the memory of `uc`, so they
+    /// must not be dropped before `uc` is closed
+    pub fn map_into(&self, uc: &mut Unicorn<'_, ()>) -> Result<PrivateMappings, uc_error> {
+        let mut mappings = PrivateMappings { mappings: Vec::with_capacity(self.regions.len()) };
+        for region in &self.regions {
+            let ptr = unsafe {
+                libc::mmap(null_mut(), region.size, libc::PROT_READ | libc::PROT_WRITE,
+                           libc::MAP_PRIVATE, self.fd, region.offset as libc::off_t)
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(uc_error::NOMEM);
+            }
+            mappings.mappings.push((ptr, region.size));
+
+            unsafe { uc.mem_map_ptr(region.addr, region.size, region.perms, ptr)?; }
+        }
+        Ok(mappings)
+    }
+}
+
+/// Copy-on-write mappings of a snapshot image that back the memory of a single emulator. They are
+/// unmapped when this is dropped
+#[derive(Debug)]
+pub struct PrivateMappings {
+    /// Address and size of every mapping
+    mappings: Vec<(*mut libc::c_void, usize)>,
+}
+
+impl Drop for PrivateMappings {
+    fn drop(&mut self) {
+        for (ptr, size) in &self.mappings {
+            unsafe { libc::munmap(*ptr, *size); }
+        }
+    }
+}
+
+impl Drop for SnapshotImage {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.base as *mut libc::c_void, self.size);
+            libc::close(self.fd);
+        }
+    }
+}
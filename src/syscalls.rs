@@ -1,6 +1,6 @@
 use crate::{
     execution_state::{ExecEnv, FileType::{self, STDOUT, STDERR, INVALID}},
-    configurables::FUZZ_INPUT,
+    configurables::{FUZZ_INPUT, MAX_TRACED_WRITE},
     dbg_print,
 };
 
@@ -9,7 +9,7 @@ use unicorn_engine::{
     unicorn_const::{uc_error, Permission},
 };
 
-use std::cell::{RefMut, Ref};
+use std::cell::RefMut;
 
 // Helper Strunicornts for syscalls {{{
 
@@ -195,6 +195,10 @@ pub fn read(mut exec_env: RefMut<ExecEnv>, unicorn: &mut Unicorn<'_, ()>) -> Res
 
         unicorn.reg_write(unicorn.syscall_return_reg()?, len as u64)?;
         exec_env.fd_list[fd].cursor = Some(offset + len);
+
+        // A short read tells the target where the input ends, so it depends on all of it
+        let consumed = if len < count { usize::MAX } else { offset + len };
+        exec_env.input_consumed = exec_env.input_consumed.max(consumed);
     } else {
         // Read in a different file
         unicorn.reg_write(unicorn.syscall_return_reg()?, count as u64)?;
@@ -203,7 +207,8 @@ pub fn read(mut exec_env: RefMut<ExecEnv>, unicorn: &mut Unicorn<'_, ()>) -> Res
     Ok(())
 }
 
-pub fn write(exec_env: Ref<ExecEnv>, unicorn: &mut Unicorn<'_, ()>) -> Result<(), uc_error> {
+pub fn write(mut exec_env: RefMut<ExecEnv>, unicorn: &mut Unicorn<'_, ()>) 
+        -> Result<(), uc_error> {
     dbg_print("SYSCALL write");
 
     let fd    = unicorn.reg_read(unicorn.syscall_arg0_reg()?)? as usize;
@@ -217,19 +222,14 @@ pub fn write(exec_env: Ref<ExecEnv>, unicorn: &mut Unicorn<'_, ()>) -> Result<()
         return Ok(());
     }
 
-    // Set to true if you wish to see the actual stdout output of this syscall
-    if false {
-        let file = file.unwrap();
-        if file.ftype == STDOUT || file.ftype == STDERR {
-            let mut read_data = vec![0u8; count];
-            unicorn.mem_read(buf, &mut read_data).unwrap();
-
-            match std::str::from_utf8(&read_data) {
-                Ok(v) => print!("{v}"),
-                Err(_) => print!("{read_data:?}"),
+    // Capture the actual stdout output of this syscall if syscall-tracing is enabled. This must
+    // not change what the guest observes, so output that can't be read is just not captured
+    if exec_env.trace_syscalls {
+        let ftype = file.unwrap().ftype;
+        if ftype == STDOUT || ftype == STDERR {
+            if let Ok(read_data) = unicorn.mem_read_as_vec(buf, count.min(MAX_TRACED_WRITE)) {
+                exec_env.guest_output.extend_from_slice(&read_data);
             }
-        } else {
-            panic!("Write to unsupported file occured");
         }
     }
 
@@ -391,6 +391,10 @@ pub fn mmap(mut exec_env: RefMut<ExecEnv>, unicorn: &mut Unicorn<'_, ()>) -> Res
 
     // fd is set so user intends to map a file into memory here
     if fd != 0 && fd != 0xffffffff && fd != 0xffffffffffffffff {
+        if exec_env.fd_list.get(fd).is_some_and(|file| file.ftype == FileType::FUZZINPUT) {
+            exec_env.input_consumed = usize::MAX;
+        }
+
         if let Some(file) = exec_env.fd_list.get(fd) {
             assert!((offset % 0x1000) == 0, 
                     "According to man-pages, `offset` needs to be a page-size multiple");
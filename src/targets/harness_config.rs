@@ -0,0 +1,396 @@
+//! Runtime harness descriptions. Instead of hard-wiring a target into `TARGETS` and
+//! `TARGET_INIT_FUNCTIONS`, a json file passed via `-c` can describe one or more targets. The
+//! fuzzer turns each entry into a `HarnessInit` and initializes it using the generic
+//! `initialize_target` and `insert_target_hooks` functions below, so switching targets or tweaking
+//! thread-counts and timeouts does not require recompiling the fuzzer. See `/harnesses` for
+//! example configurations.
+
+use crate::{
+    execution_state::ExecEnv,
+    hooks::{
+        insert_linux_syscall_hook,
+        insert_malloc_hook,
+        insert_free_hook,
+        insert_realloc_hook,
+        insert_exit_hook,
+        insert_input_hook,
+        insert_strcmp_hook,
+        insert_memcmp_hook,
+    },
+    targets::targets::HarnessInit,
+    snapshot_file::load_snapshot_file,
+    configurables::{VMMAP_ALLOCATION_SIZE, FIRSTALLOCATION},
+    error_exit, load_elf_segments, load_dump,
+};
+
+use serde::{Deserialize, Deserializer};
+use unicorn_engine::{
+    Unicorn, RegisterX86, RegisterRISCV,
+    unicorn_const::{Permission, uc_error, Arch, Mode},
+};
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+/// Size of the stack that is allocated for targets loaded through the elf-loader
+const STACK_SIZE: usize = 1024 * 1024;
+
+/// A guest address. Can be specified as either a json number or a string (eg. "0x401670")
+#[derive(Debug, Clone, Copy)]
+pub struct Addr(pub u64);
+
+impl<'de> Deserialize<'de> for Addr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum AddrRepr {
+            Num(u64),
+            Str(String),
+        }
+
+        match AddrRepr::deserialize(deserializer)? {
+            AddrRepr::Num(addr) => Ok(Addr(addr)),
+            AddrRepr::Str(addr) => parse_int::parse::<u64>(&addr).map(Addr)
+                .map_err(|_| serde::de::Error::custom(format!("Invalid address: {addr}"))),
+        }
+    }
+}
+
+/// Top-level structure of a harness configuration file
+#[derive(Debug, Clone, Deserialize)]
+pub struct HarnessConfig {
+    /// Targets that should be fuzzed. Their index in this list is used as their target-id
+    pub targets: Vec<TargetConfig>,
+}
+
+/// Architecture/mode combinations supported by the generic harness
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize)]
+pub enum ArchConfig {
+    #[serde(rename = "x86_64")]
+    X86_64,
+
+    #[serde(rename = "x86_32")]
+    X86_32,
+
+    #[serde(rename = "riscv64")]
+    Riscv64,
+}
+
+/// Describes how the target is loaded into the emulator
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum LoaderConfig {
+    /// Statically linked elf binary loaded using `load_elf_segments`. A stack is set up with the
+    /// provided `argv` (use `fuzz_input` as a filename to pass the fuzz-input via a file)
+    Elf {
+        path: String,
+        #[serde(default)]
+        argv: Vec<String>,
+    },
+
+    /// Memory dump generated by `tools/snapshot.py` and loaded using `load_dump`
+    Dump {
+        path: String,
+    },
+
+    /// Snapshot file created using `zfuzz snapshot` and loaded using `SnapshotFile::restore`. The
+    /// file already holds the stack and allocator backing of the target it was taken from
+    Snapshot {
+        path: String,
+    },
+}
+
+/// Describes a location at which the fuzz-input is written directly into guest memory
+#[derive(Debug, Clone, Deserialize)]
+pub struct InputConfig {
+    /// Address at which the input is injected. Defaults to the pc the target starts executing at,
+    /// which is what you want for dumps that were taken right after the input was read in
+    pub addr: Option<Addr>,
+
+    /// Register that holds the address of the buffer the input is written to
+    pub buffer_reg: String,
+
+    /// Optional register that receives the length of the injected input
+    pub length_reg: Option<String>,
+
+    /// Optionally truncate inputs to this length before injecting them
+    pub max_len: Option<usize>,
+}
+
+/// Description of a single target
+#[derive(Debug, Clone, Deserialize)]
+pub struct TargetConfig {
+    /// Architecture and mode the target is run in
+    pub arch: ArchConfig,
+
+    /// How the target is loaded into memory
+    pub loader: LoaderConfig,
+
+    /// If any of these addresses are hit, the fuzz-case is stopped
+    #[serde(default)]
+    pub exit_addrs: Vec<Addr>,
+
+    /// Address of the targets `malloc` function. Hooked with zfuzz's allocator if specified
+    pub malloc_addr: Option<Addr>,
+
+    /// Address of the targets `free` function. Hooked with zfuzz's allocator if specified
+    pub free_addr: Option<Addr>,
+
+    /// Address of the targets `realloc` function. Hooked with zfuzz's allocator if specified
+    pub realloc_addr: Option<Addr>,
+
+    /// Addresses of `strcmp`-like functions whose operands are added to the automatic dictionary
+    #[serde(default)]
+    pub strcmp_addrs: Vec<Addr>,
+
+    /// Addresses of `memcmp`-like functions whose operands are added to the automatic dictionary
+    #[serde(default)]
+    pub memcmp_addrs: Vec<Addr>,
+
+    /// Optional input-injection point. If this is not set the target is expected to read the
+    /// fuzz-input through the `fuzz_input` file
+    pub input: Option<InputConfig>,
+
+    /// Number of threads to run this target with
+    #[serde(default = "default_num_threads")]
+    pub num_threads: usize,
+
+    /// Maximum amount of instructions to execute before terminating fuzz-case, 0 to never timeout
+    #[serde(default)]
+    pub instr_timeout: usize,
+
+    /// Maximum time in microseconds before terminating fuzz-case, 0 to never timeout
+    #[serde(default)]
+    pub time_timeout: u64,
+
+    /// Enable comparison coverage for this target
+    #[serde(default)]
+    pub cmpcov: bool,
+
+    /// Enable input-to-state replacement mutations for this target
+    #[serde(default)]
+    pub input_to_state: bool,
+
+    /// Run the deterministic mutation stages on new corpus entries of this target
+    #[serde(default)]
+    pub deterministic: bool,
+
+    /// Build a dictionary from the target binary and the `strcmp`/`memcmp` hooks
+    #[serde(default)]
+    pub auto_dict: bool,
+
+    /// Index of the mutator in `MUTATOR_INIT_FUNCTIONS` that is used for this target
+    #[serde(default)]
+    pub mutator: usize,
+
+    /// Address at which a nested snapshot is taken for every seed
+    pub nested_snapshot: Option<Addr>,
+}
+
+fn default_num_threads() -> usize {
+    1
+}
+
+impl TargetConfig {
+    /// Turn this configuration into the run-options used by the worker threads
+    pub fn harness_init(&self, target_id: usize) -> HarnessInit {
+        HarnessInit {
+            target_id,
+            num_threads:     self.num_threads,
+            instr_timeout:   self.instr_timeout,
+            time_timeout:    self.time_timeout,
+            cmpcov:          self.cmpcov,
+            input_to_state:  self.input_to_state,
+            deterministic:   self.deterministic,
+            auto_dict:       self.auto_dict,
+            mutator:         self.mutator,
+            nested_snapshot: self.nested_snapshot.map(|addr| addr.0),
+        }
+    }
+}
+
+/// Parse a harness configuration file from disk
+pub fn load_harness_config(filename: &str) -> Result<HarnessConfig, String> {
+    let data = std::fs::read(filename).map_err(|e| format!("Failed to read {filename}: {e}"))?;
+    let config: HarnessConfig = serde_json::from_slice(&data)
+        .map_err(|e| format!("Failed to parse {filename}: {e}"))?;
+
+    if config.targets.is_empty() {
+        return Err(format!("{filename} does not describe any targets"));
+    }
+    Ok(config)
+}
+
+/// Resolve the string representation of a register to its unicorn register-id
+fn resolve_reg(arch: ArchConfig, name: &str) -> Option<i32> {
+    let reg = match arch {
+        ArchConfig::X86_64 => match name {
+            "rax" => RegisterX86::RAX, "rbx" => RegisterX86::RBX, "rcx" => RegisterX86::RCX,
+            "rdx" => RegisterX86::RDX, "rsi" => RegisterX86::RSI, "rdi" => RegisterX86::RDI,
+            "rbp" => RegisterX86::RBP, "rsp" => RegisterX86::RSP, "r8"  => RegisterX86::R8,
+            "r9"  => RegisterX86::R9,  "r10" => RegisterX86::R10, "r11" => RegisterX86::R11,
+            "r12" => RegisterX86::R12, "r13" => RegisterX86::R13, "r14" => RegisterX86::R14,
+            "r15" => RegisterX86::R15,
+            _ => return None,
+        }.into(),
+        ArchConfig::X86_32 => match name {
+            "eax" => RegisterX86::EAX, "ebx" => RegisterX86::EBX, "ecx" => RegisterX86::ECX,
+            "edx" => RegisterX86::EDX, "esi" => RegisterX86::ESI, "edi" => RegisterX86::EDI,
+            "ebp" => RegisterX86::EBP, "esp" => RegisterX86::ESP,
+            _ => return None,
+        }.into(),
+        ArchConfig::Riscv64 => match name {
+            "zero" => RegisterRISCV::ZERO, "ra" => RegisterRISCV::RA, "sp" => RegisterRISCV::SP,
+            "gp"   => RegisterRISCV::GP,   "tp" => RegisterRISCV::TP, "fp" => RegisterRISCV::FP,
+            "a0"  => RegisterRISCV::A0,  "a1"  => RegisterRISCV::A1,  "a2" => RegisterRISCV::A2,
+            "a3"  => RegisterRISCV::A3,  "a4"  => RegisterRISCV::A4,  "a5" => RegisterRISCV::A5,
+            "a6"  => RegisterRISCV::A6,  "a7"  => RegisterRISCV::A7,  "s0" => RegisterRISCV::S0,
+            "s1"  => RegisterRISCV::S1,  "s2"  => RegisterRISCV::S2,  "s3" => RegisterRISCV::S3,
+            "s4"  => RegisterRISCV::S4,  "s5"  => RegisterRISCV::S5,  "s6" => RegisterRISCV::S6,
+            "s7"  => RegisterRISCV::S7,  "s8"  => RegisterRISCV::S8,  "s9" => RegisterRISCV::S9,
+            "s10" => RegisterRISCV::S10, "s11" => RegisterRISCV::S11, "t0" => RegisterRISCV::T0,
+            "t1"  => RegisterRISCV::T1,  "t2"  => RegisterRISCV::T2,  "t3" => RegisterRISCV::T3,
+            "t4"  => RegisterRISCV::T4,  "t5"  => RegisterRISCV::T5,  "t6" => RegisterRISCV::T6,
+            _ => return None,
+        }.into(),
+    };
+    Some(reg)
+}
+
+/// Allocate a stack and populate argc, argv & envp for targets loaded through the elf-loader
+fn setup_stack(exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'_, ()>, arch: ArchConfig,
+               args: &[String]) -> Result<(), uc_error> {
+    let (sp_reg, ptr_size): (i32, usize) = match arch {
+        ArchConfig::X86_64  => (RegisterX86::RSP.into(), 8),
+        ArchConfig::X86_32  => (RegisterX86::ESP.into(), 4),
+        ArchConfig::Riscv64 => (RegisterRISCV::SP.into(), 8),
+    };
+
+    let stack = exec_env.borrow_mut()
+        .allocate(unicorn, STACK_SIZE, Permission::READ | Permission::WRITE)
+        .expect("Error allocating stack");
+    unicorn.reg_write(sp_reg, stack + STACK_SIZE as u64 - 8)?;
+
+    // Write every argument into its own allocation so it can be referenced from argv
+    let mut argv: Vec<u64> = Vec::new();
+    for arg in args {
+        let addr = exec_env.borrow_mut().allocate(unicorn, arg.len() + 1,
+                                                   Permission::READ | Permission::WRITE)?;
+        let mut bytes = arg.as_bytes().to_vec();
+        bytes.push(0);
+        unicorn.mem_write(addr, &bytes)?;
+        argv.push(addr);
+    }
+
+    // Auxp, Envp & the null-terminator of argv, followed by argv and argc
+    let mut stack_vals: Vec<u64> = vec![0, 0, 0];
+    stack_vals.extend(argv.iter().rev());
+    stack_vals.push(argv.len() as u64);
+
+    for val in stack_vals {
+        let sp = unicorn.reg_read(sp_reg)? - ptr_size as u64;
+        unicorn.mem_write(sp, &val.to_le_bytes()[..ptr_size])?;
+        unicorn.reg_write(sp_reg, sp)?;
+    }
+    Ok(())
+}
+
+/// Generic harness initialization function that sets up a target based on its configuration
+pub fn initialize_target(config: &TargetConfig)
+        -> Result<(Rc<RefCell<ExecEnv>>, Unicorn<'static, ()>), uc_error> {
+    // Create emulator that will hold system context such as open files, dirty pages, etc
+    let exec_env: Rc<RefCell<ExecEnv>> =
+        Rc::new(RefCell::new(ExecEnv::new(64 * 1024 * 1024)));
+
+    // Create unicorn cpu emulator
+    let mut unicorn = match config.arch {
+        ArchConfig::X86_64  => Unicorn::new(Arch::X86, Mode::MODE_64)?,
+        ArchConfig::X86_32  => Unicorn::new(Arch::X86, Mode::MODE_32)?,
+        ArchConfig::Riscv64 => Unicorn::new(Arch::RISCV, Mode::RISCV64)?,
+    };
+
+    // Load the target into memory
+    match &config.loader {
+        LoaderConfig::Elf { path, .. } => {
+            load_elf_segments(&mut unicorn, path).unwrap_or_else(|err| {
+                let error_string = format!("{err:#?}");
+                error_exit(&format!("Unrecoverable error while loading elf segments: \
+                                    {error_string}"));
+            });
+        },
+        LoaderConfig::Dump { path } => {
+            load_dump(&exec_env, &mut unicorn, path).unwrap_or_else(|err| {
+                let error_string = format!("{err:#?}");
+                error_exit(&format!("Unrecoverable error while loading memory dump: \
+                                    {error_string}"));
+            });
+        },
+        LoaderConfig::Snapshot { path } => {
+            load_snapshot_file(path).restore(&exec_env, &mut unicorn).unwrap_or_else(|err| {
+                let error_string = format!("{err:#?}");
+                error_exit(&format!("Unrecoverable error while loading snapshot file (make sure \
+                                    `arch` matches the snapshot): {error_string}"));
+            });
+        },
+    }
+
+    // Allocate memory map for emulator. This backing will be used to allocate the initial stack
+    // and handle later heap allocations during program execution. Snapshot files already contain
+    // this region
+    if !matches!(config.loader, LoaderConfig::Snapshot { .. }) {
+        unicorn.mem_map(FIRSTALLOCATION, VMMAP_ALLOCATION_SIZE, Permission::NONE)?;
+    }
+
+    // Elf targets start at their entry-point so they still require a stack
+    if let LoaderConfig::Elf { argv, .. } = &config.loader {
+        setup_stack(&exec_env, &mut unicorn, config.arch, argv)?;
+    }
+
+    // Return initialized execution-environment/emulator to caller
+    Ok((exec_env, unicorn))
+}
+
+/// Insert the hooks requested by the configuration of a target into an emulator that was created
+/// from its snapshot
+pub fn insert_target_hooks(config: &TargetConfig, exec_env: &Rc<RefCell<ExecEnv>>,
+                           unicorn: &mut Unicorn<'static, ()>) -> Result<(), uc_error> {
+    insert_linux_syscall_hook(exec_env, unicorn)?;
+
+    if let Some(addr) = config.malloc_addr {
+        insert_malloc_hook(exec_env, unicorn, addr.0)?;
+    }
+    if let Some(addr) = config.free_addr {
+        insert_free_hook(exec_env, unicorn, addr.0)?;
+    }
+    if let Some(addr) = config.realloc_addr {
+        insert_realloc_hook(exec_env, unicorn, addr.0)?;
+    }
+
+    if let Some(input) = &config.input {
+        let buffer_reg = resolve_reg(config.arch, &input.buffer_reg).unwrap_or_else(|| {
+            error_exit(&format!("Unknown register in harness config: {}", input.buffer_reg));
+        });
+        let length_reg = input.length_reg.as_ref().map(|reg| {
+            resolve_reg(config.arch, reg).unwrap_or_else(|| {
+                error_exit(&format!("Unknown register in harness config: {reg}"));
+            })
+        });
+        let addr = match input.addr {
+            Some(addr) => addr.0,
+            None => unicorn.get_pc()?,
+        };
+        insert_input_hook(exec_env, unicorn, addr, buffer_reg, length_reg, input.max_len)?;
+    }
+
+    for addr in &config.strcmp_addrs {
+        insert_strcmp_hook(exec_env, unicorn, addr.0)?;
+    }
+    for addr in &config.memcmp_addrs {
+        insert_memcmp_hook(exec_env, unicorn, addr.0)?;
+    }
+
+    for addr in &config.exit_addrs {
+        insert_exit_hook(unicorn, addr.0)?;
+    }
+    Ok(())
+}
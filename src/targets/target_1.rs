@@ -1,6 +1,6 @@
 //! This loads the first target. In this case it is a static linux elf binary, so the default
-//! elf-parser can be used to load it into memory. Afterwards we just setup a stack and insert
-//! some hooks.
+//! elf-parser can be used to load it into memory. Afterwards we just setup a stack. The hooks are
+//! inserted separately by `insert_hooks`.
 //! In this case the target takes its input via a filename passed through argv, so we just
 //! append the filename to argv during the stack setup. If you want to pass input to the 
 //! target in a different way, the best way would be to setup a hook at a specific address/syscall
@@ -95,20 +95,23 @@ pub fn initialize_target()
         push!(argv.len());      // Argc
     }
 
-    // Insert optional target-specific hooks
-    {
-        // This hook catches interrupts to hook syscalls. For eg. Windows or embedded targets, you 
-        // will want to define a different interrupt hook to hook their respective context-switch 
-        // operations
-        insert_linux_syscall_hook(&exec_env, &mut unicorn)?;
-
-        // Insert memory allocator hooks (On linux, addresses of `_malloc_r` & `_free_r`)
-        // This is optional, but highly recommended to use an allocator that can find potentially
-        // non-crashing bugs such as double-free's or uaf's
-        insert_malloc_hook(&exec_env, &mut unicorn, 0x103a4)?;
-        insert_free_hook(&exec_env, &mut unicorn, 0x10fe4)?;
-    }
-
     // Return initialized execution-environment/emulator to caller
     Ok((exec_env, unicorn))
 }
+
+/// Insert optional target-specific hooks. This is done for every emulator that is created from the
+/// snapshot of the initialized target
+pub fn insert_hooks(exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>)
+        -> Result<(), uc_error> {
+    // This hook catches interrupts to hook syscalls. For eg. Windows or embedded targets, you 
+    // will want to define a different interrupt hook to hook their respective context-switch 
+    // operations
+    insert_linux_syscall_hook(exec_env, unicorn)?;
+
+    // Insert memory allocator hooks (On linux, addresses of `_malloc_r` & `_free_r`)
+    // This is optional, but highly recommended to use an allocator that can find potentially
+    // non-crashing bugs such as double-free's or uaf's
+    insert_malloc_hook(exec_env, unicorn, 0x103a4)?;
+    insert_free_hook(exec_env, unicorn, 0x10fe4)?;
+    Ok(())
+}
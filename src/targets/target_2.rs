@@ -5,7 +5,7 @@
 
 use crate::{
     execution_state::ExecEnv,
-    hooks::insert_linux_syscall_hook,
+    hooks::{insert_linux_syscall_hook, insert_exit_hook, insert_input_hook},
     configurables::{VMMAP_ALLOCATION_SIZE, FIRSTALLOCATION},
     error_exit, load_dump, 
 };
@@ -43,56 +43,29 @@ pub fn initialize_target()
     // during program execution
     unicorn.mem_map(FIRSTALLOCATION, VMMAP_ALLOCATION_SIZE, Permission::NONE).unwrap();
 
-    // Insert optional target-specific hooks
-    {
-        // This hook catches interrupts to hook syscalls. For eg. Windows or embedded targets, 
-        // you will want to define a different interrupt hook to hook their respective 
-        // context-switch operations
-        insert_linux_syscall_hook(&exec_env, &mut unicorn)?;
-
-        // Hook to insert input into the target
-        let hook_location = unicorn.get_pc().unwrap();
-        insert_input_hook(&exec_env, &mut unicorn, hook_location)?;
-
-        // Hooks to early exit after fuzz-case explored interesting target-state
-        EXIT_ADDRS.iter().for_each(|e| {
-            insert_exit_hook(&mut unicorn, *e).unwrap(); 
-        });
-    }
-
     // Return initialized execution-environment/emulator to caller
     Ok((exec_env, unicorn))
 }
 
-/// Places a hook at `addr` that loads the `exec_env.fuzz_input` into the targets address space
-pub fn insert_input_hook(exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'_, ()>, addr: u64) 
+/// Insert optional target-specific hooks. This is done for every emulator that is created from the
+/// snapshot of the initialized target
+pub fn insert_hooks(exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>)
         -> Result<(), uc_error> {
-    let exec_env_clone = Rc::clone(exec_env);
-    let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
-        let input_buffer_addr: u64 = uc.reg_read(RegisterX86::RSI).unwrap();
-        let mut mutated_data = exec_env_clone.borrow().fuzz_input.clone();
-
-        // From looking at the targets source, we know it reads in at most 100 bytes
-        mutated_data.truncate(100);
-        
-        uc.reg_write(RegisterX86::RAX, mutated_data.len() as u64).unwrap();
-
-        // Mark the memory region as dirtied so our snapshot resets properly reset this memory area
-        exec_env_clone.borrow_mut().mark_dirtied(input_buffer_addr, mutated_data.len());
-        uc.mem_write(input_buffer_addr, &mutated_data)
-            .expect("Failed to write mutated data into target");
-    };
-
-    unicorn.add_code_hook(addr, addr, callback)?;
-    Ok(())
-}
-
-/// Places a hook at `addr` that loads the `exec_env.fuzz_input` into the targets address space
-pub fn insert_exit_hook(unicorn: &mut Unicorn<'_, ()>, addr: u64) -> Result<(), uc_error> {
-    let callback = move |uc: &mut Unicorn<'_, ()>, _address: u64, _size: u32| {
-        uc.emu_stop().unwrap();
-    };
-
-    unicorn.add_code_hook(addr, addr, callback)?;
+    // This hook catches interrupts to hook syscalls. For eg. Windows or embedded targets, 
+    // you will want to define a different interrupt hook to hook their respective 
+    // context-switch operations
+    insert_linux_syscall_hook(exec_env, unicorn)?;
+
+    // Hook to insert input into the target. From looking at the targets source, we know it
+    // reads in at most 100 bytes. The emulator still holds the cpu-context of the snapshot, so the
+    // pc is the one the dump was taken at
+    let hook_location = unicorn.get_pc().unwrap();
+    insert_input_hook(exec_env, unicorn, hook_location, RegisterX86::RSI.into(),
+                      Some(RegisterX86::RAX.into()), Some(100))?;
+
+    // Hooks to early exit after fuzz-case explored interesting target-state
+    EXIT_ADDRS.iter().for_each(|e| {
+        insert_exit_hook(unicorn, *e).unwrap(); 
+    });
     Ok(())
 }
@@ -2,6 +2,8 @@
 
 use crate::{
     execution_state::ExecEnv, targets,
+    mutator::MutatorId,
+    targets::harness_config::{self, HarnessConfig, TargetConfig},
 };
 
 use unicorn_engine::{
@@ -11,6 +13,7 @@ use unicorn_engine::{
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::OnceLock;
 
 const NUM_TARGETS: usize = 1;
 
@@ -22,6 +25,12 @@ pub const TARGETS: [HarnessInit; NUM_TARGETS] = [
         num_threads: 1,
         instr_timeout: 0,
         time_timeout: 0,
+        cmpcov: false,
+        input_to_state: false,
+        deterministic: false,
+        auto_dict: false,
+        mutator: MutatorId::Default as usize,
+        nested_snapshot: None,
     },
 ];
 
@@ -43,8 +52,89 @@ pub const TARGET_INIT_FUNCTIONS:
             targets::target_2::initialize_target,
         ];
 
+/// Function that inserts the target-specific hooks into an emulator
+type HookFn = fn(&Rc<RefCell<ExecEnv>>, &mut Unicorn<'static, ()>) -> Result<(), uc_error>;
+
+/// List of functions used to insert the target-specific hooks (syscalls, allocator, input, ...)
+/// into an emulator that was created from the snapshot of an initialized target. Register them in
+/// the same order as `TARGET_INIT_FUNCTIONS`
+pub const TARGET_HOOK_FUNCTIONS: [HookFn; std::mem::variant_count::<TargetId>()] =
+        [
+            targets::target_1::insert_hooks,
+            targets::target_2::insert_hooks,
+        ];
+
+/// Targets that are actually run by the fuzzer. These are either the compiled-in `TARGETS`, or the
+/// targets described by a harness configuration file passed in via `-c`
+pub static ACTIVE_TARGETS: OnceLock<Vec<HarnessInit>> = OnceLock::new();
+
+/// Target descriptions loaded from a harness configuration file, indexed by target-id
+static TARGET_CONFIGS: OnceLock<Vec<TargetConfig>> = OnceLock::new();
+
+/// Register the targets that should be fuzzed. If no harness configuration was provided, fall back
+/// to the targets registered in `TARGETS`
+pub fn register_targets(config: Option<HarnessConfig>) {
+    let active = match config {
+        Some(config) => {
+            let active = config.targets.iter().enumerate()
+                .map(|(i, t)| t.harness_init(i))
+                .collect();
+            TARGET_CONFIGS.set(config.targets).unwrap();
+            active
+        },
+        None => TARGETS.to_vec(),
+    };
+    ACTIVE_TARGETS.set(active).unwrap();
+}
+
+/// Find the harness description of the target with `target_id`. Targets that have a harness
+/// function in `TARGET_INIT_FUNCTIONS` but were not registered in `TARGETS` are returned with
+/// default settings so they can still be used by the single-input modes (eg. `repro`)
+pub fn find_target(target_id: usize) -> Option<HarnessInit> {
+    if let Some(target) = ACTIVE_TARGETS.get().unwrap().iter().find(|t| t.target_id == target_id) {
+        return Some(*target);
+    }
+
+    if TARGET_CONFIGS.get().is_none() && target_id < TARGET_INIT_FUNCTIONS.len() {
+        return Some(HarnessInit {
+            target_id,
+            num_threads: 1,
+            instr_timeout: 0,
+            time_timeout: 0,
+            cmpcov: false,
+            input_to_state: false,
+            deterministic: false,
+            auto_dict: false,
+            mutator: MutatorId::Default as usize,
+            nested_snapshot: None,
+        });
+    }
+    None
+}
+
+/// Initialize the target with the given `target_id`, either through its registered harness
+/// function, or through the generic harness if it was described in a configuration file. This
+/// only sets up the state of the target, the hooks are inserted by `insert_target_hooks`
+pub fn init_target(target_id: usize) 
+        -> Result<(Rc<RefCell<ExecEnv>>, Unicorn<'static, ()>), uc_error> {
+    match TARGET_CONFIGS.get() {
+        Some(configs) => harness_config::initialize_target(&configs[target_id]),
+        None => TARGET_INIT_FUNCTIONS[target_id](),
+    }
+}
+
+/// Insert the target-specific hooks of the target with the given `target_id` into `unicorn`
+pub fn insert_target_hooks(target_id: usize, exec_env: &Rc<RefCell<ExecEnv>>,
+                           unicorn: &mut Unicorn<'static, ()>) -> Result<(), uc_error> {
+    match TARGET_CONFIGS.get() {
+        Some(configs) => harness_config::insert_target_hooks(&configs[target_id], exec_env,
+                                                             unicorn),
+        None => TARGET_HOOK_FUNCTIONS[target_id](exec_env, unicorn),
+    }
+}
+
 /// Data passed to worker function/harness that describes some run-options
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct HarnessInit {
     /// Target-id used to determine which harness to run
     pub target_id: usize,
@@ -61,5 +151,29 @@ pub struct HarnessInit {
     /// 0 to never timeout
     /// Note: Unicorn does not produce an error-condition for timeouts
     pub time_timeout: u64,
+
+    /// Instrument comparisons to reward partial operand matches with additional coverage. Helps 
+    /// with multi-byte magic-value checks, but slows down execution
+    pub cmpcov: bool,
+
+    /// Log comparison operands of each new seed and use them for input-to-state replacement 
+    /// mutations. Uses the same instrumentation as `cmpcov`
+    pub input_to_state: bool,
+
+    /// Run deterministic mutation stages (walking bit/byte flips, arithmetic, interesting values
+    /// and dictionary insertions) on every corpus entry before it is fuzzed randomly
+    pub deterministic: bool,
+
+    /// Build a dictionary from strings and constants in the target binary and from the operands
+    /// of hooked `strcmp`/`memcmp` calls (see `insert_strcmp_hook`/`insert_memcmp_hook`)
+    pub auto_dict: bool,
+
+    /// Mutator used for this target. This is the index of the mutator in `MUTATOR_INIT_FUNCTIONS`
+    pub mutator: usize,
+
+    /// Optional address at which a nested snapshot is taken for every seed. Cases of the seed are
+    /// then resumed from this point, and only the part of the input that is consumed after it is
+    /// mutated. Useful for multi-stage protocols whose first stages should stay intact
+    pub nested_snapshot: Option<u64>,
 }
 
@@ -0,0 +1,110 @@
+//! Test-case minimizer (`zfuzz tmin -t <target> <file>`). Repeatedly shrinks a crashing input by
+//! removing blocks of decreasing size and normalizing bytes, while re-running it from the snapshot
+//! after every change to make sure it still ends up in the same crash bucket.
+
+use crate::{
+    TargetShared, setup_fuzz_env, create_snapshot, run_case, error_exit,
+    targets::targets::find_target,
+    triage::{crash_bucket, crash_pc, is_crash},
+};
+
+use unicorn_engine::unicorn_const::uc_error;
+
+use std::sync::Arc;
+
+/// Bytes that the normalization pass attempts to replace input bytes with, in order of preference
+const NORMALIZE_BYTES: [u8; 2] = [0x0, b'A'];
+
+/// Maximum number of times all minimization passes are repeated while they still make progress
+const MAX_ROUNDS: usize = 8;
+
+/// Minimize the crashing input in `file` for the target with `target_id` and write the result to
+/// `output`
+pub fn tmin(target_id: usize, file: &str, output: &str) -> Result<(), uc_error> {
+    let harness_init = find_target(target_id).unwrap_or_else(|| {
+        error_exit(&format!("Target {target_id} does not exist"));
+    });
+    let mut data = std::fs::read(file).unwrap_or_else(|_| {
+        error_exit(&format!("Failed to read input file: {file}"));
+    });
+    let orig_len = data.len();
+
+    let snapshot = create_snapshot(&harness_init);
+    let target_shared = Arc::new(TargetShared::default());
+    let (exec_env, mut unicorn) = setup_fuzz_env(&harness_init, &target_shared, &snapshot);
+
+    // Run `input` from the snapshot and return the crash bucket it ends up in, if it crashes
+    let mut execs = 0;
+    let mut run = |input: &[u8]| -> Option<usize> {
+        let mut emu_ref = exec_env.borrow_mut();
+        emu_ref.reset_snapshot(&mut unicorn, &snapshot).unwrap();
+        emu_ref.fuzz_input.clear();
+        emu_ref.fuzz_input.extend_from_slice(input);
+        drop(emu_ref);
+
+        execs += 1;
+        match run_case(&harness_init, &target_shared, &exec_env, &mut unicorn) {
+            Err(err) if is_crash(err) => {
+                let pc = crash_pc(&unicorn, err);
+                Some(crash_bucket(err, pc, &exec_env.borrow().call_stack))
+            },
+            _ => None,
+        }
+    };
+
+    let bucket = run(&data).unwrap_or_else(|| error_exit("The supplied input does not crash"));
+
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = false;
+
+        // Block removal, starting with blocks of 1/16th of the input and halving their size
+        // every pass
+        let mut block_len = (data.len().next_power_of_two() / 16).max(1);
+        loop {
+            let mut pos = 0;
+            while pos < data.len() {
+                let end = (pos + block_len).min(data.len());
+                let mut candidate = data[..pos].to_vec();
+                candidate.extend_from_slice(&data[end..]);
+
+                if run(&candidate) == Some(bucket) {
+                    data = candidate;
+                    changed = true;
+                } else {
+                    pos += block_len;
+                }
+            }
+
+            if block_len == 1 {
+                break;
+            }
+            block_len /= 2;
+        }
+
+        // Byte normalization, replace bytes that are irrelevant to the crash with known values
+        for i in 0..data.len() {
+            for &byte in &NORMALIZE_BYTES {
+                if data[i] == byte {
+                    break;
+                }
+
+                let orig = data[i];
+                data[i] = byte;
+                if run(&data) == Some(bucket) {
+                    changed = true;
+                    break;
+                }
+                data[i] = orig;
+            }
+        }
+
+        if !changed {
+            break;
+        }
+    }
+
+    std::fs::write(output, &data).expect("Failed to write minimized input");
+    println!("Minimized {orig_len} bytes to {} bytes in {execs} executions", data.len());
+    println!("Wrote minimized input to {output}");
+    Ok(())
+}
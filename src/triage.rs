@@ -0,0 +1,317 @@
+//! Crash triage. Crashes are deduplicated based on a hash of the crashing pc, the top frames of
+//! the shadow call-stack maintained by `insert_call_stack_hook` and the kind of fault. This way the
+//! same bug reached through different inputs lands in a single bucket, while different bugs that
+//! happen to fault in the same shared helper (eg. `memcpy`) are kept apart. Every bucket gets its
+//! own directory in `out/<id>/crashes` containing the first input that hit it and a
+//! `metadata.json` file describing the crash, so most crashes can be triaged without re-running
+//! them under a debugger.
+
+use crate::{
+    execution_state::{ExecEnv, SnapshotContext},
+    targets::targets::HarnessInit,
+    arg_setup::OUTPUT_DIR,
+    configurables::CRASH_STACK_DEPTH,
+    run_case, TargetShared,
+};
+
+use capstone::prelude::*;
+use fasthash::{xx::{Hash32, Hash64}, FastHash};
+use serde::Serialize;
+use unicorn_engine::{
+    Unicorn, RegisterX86, RegisterRISCV,
+    unicorn_const::{uc_error, Arch, Mode, MemType, Permission},
+};
+
+use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+/// Number of bytes read at the crashing pc. Large enough to cover the longest x86 instruction
+const INSN_BYTES_LEN: usize = 16;
+
+/// Heap allocation that a faulting address falls into or is closest to
+#[derive(Debug, Serialize)]
+pub struct HeapInfo {
+    /// Base address of the allocation
+    pub base: String,
+
+    /// Size that was requested for the allocation
+    pub size: usize,
+
+    /// Offset of the faulting address from the allocation base. Negative for underflows
+    pub offset: i64,
+
+    /// `allocated` or `freed`
+    pub state: &'static str,
+}
+
+/// Information collected about a unique crash that is saved alongside the crashing input
+#[derive(Debug, Serialize)]
+pub struct CrashInfo {
+    /// Kind of crash (eg. `read`, `double_free`)
+    pub kind: &'static str,
+
+    /// Error that unicorn (or one of our hooks) reported for this crash
+    pub error: String,
+
+    /// Hash of the stack-trace that was used to bucket this crash
+    pub bucket: String,
+
+    /// Address of the faulting instruction
+    pub pc: String,
+
+    /// Address that was accessed if this crash was caused by an invalid memory access
+    pub fault_address: Option<String>,
+
+    /// Type of the invalid memory access
+    pub access_type: Option<String>,
+
+    /// Size of the invalid memory access in bytes
+    pub access_size: Option<usize>,
+
+    /// Heap allocation that the faulting address belongs to or is nearest to
+    pub heap_allocation: Option<HeapInfo>,
+
+    /// Raw bytes at the crashing pc
+    pub instruction_bytes: String,
+
+    /// Disassembly of the crashing instruction, if the bytes at the pc could be decoded
+    pub disassembly: Option<String>,
+
+    /// Number of instructions the fuzz-case executed before crashing
+    pub instructions_executed: u64,
+
+    /// Crashing pc followed by the return addresses on the shadow call-stack, innermost first
+    pub backtrace: Vec<String>,
+
+    /// Register state at the time of the crash
+    pub registers: BTreeMap<&'static str, String>,
+}
+
+/// Short name for the kind of crash `err` represents, used for directory-names and metadata
+pub fn crash_kind(err: uc_error) -> &'static str {
+    match err {
+        uc_error::READ_PROT  | uc_error::READ_UNALIGNED  | uc_error::READ_UNMAPPED  => "read",
+        uc_error::WRITE_PROT | uc_error::WRITE_UNALIGNED | uc_error::WRITE_UNMAPPED => "write",
+        uc_error::FETCH_PROT | uc_error::FETCH_UNALIGNED | uc_error::FETCH_UNMAPPED => "exec",
+        uc_error::NOMEM       => "oom",
+        uc_error::DOUBLE_FREE => "double_free",
+        uc_error::OOB_FREE    => "oob_free",
+        _ => "unknown",
+    }
+}
+
+/// Returns true if `err` is an error that is treated as a crash by the fuzzer
+pub fn is_crash(err: uc_error) -> bool {
+    crash_kind(err) != "unknown"
+}
+
+/// Address of the instruction that caused the crash. For crashes detected by the allocator hooks
+/// the pc isn't properly propagated, so the hooks record the caller in a separate crash-pc instead
+pub fn crash_pc(unicorn: &Unicorn<'static, ()>, err: uc_error) -> u64 {
+    match err {
+        uc_error::NOMEM | uc_error::DOUBLE_FREE | uc_error::OOB_FREE => unicorn.crash_pc(),
+        _ => unicorn.get_pc().unwrap(),
+    }
+}
+
+/// Crashing pc followed by the innermost `depth` return addresses of the shadow call-stack
+fn stack_frames(pc: u64, call_stack: &[u64], depth: usize) -> Vec<u64> {
+    std::iter::once(pc)
+        .chain(call_stack.iter().rev().copied())
+        .take(depth)
+        .collect()
+}
+
+/// Compute the bucket a crash belongs to, based on the kind of crash, the crashing pc and the
+/// top `CRASH_STACK_DEPTH - 1` frames of the call-stack
+pub fn crash_bucket(err: uc_error, pc: u64, call_stack: &[u64]) -> usize {
+    let mut data: Vec<u8> = crash_kind(err).as_bytes().to_vec();
+    for frame in stack_frames(pc, call_stack, CRASH_STACK_DEPTH) {
+        data.extend_from_slice(&frame.to_le_bytes());
+    }
+    Hash64::hash(&data) as usize
+}
+
+/// Read out the general purpose registers of the emulator for the crash-report
+fn dump_registers(unicorn: &Unicorn<'static, ()>) -> BTreeMap<&'static str, String> {
+    let regs: Vec<(&'static str, i32)> = match unicorn.get_arch() {
+        Arch::X86 => vec![
+            ("rax", RegisterX86::RAX.into()), ("rbx", RegisterX86::RBX.into()),
+            ("rcx", RegisterX86::RCX.into()), ("rdx", RegisterX86::RDX.into()),
+            ("rsi", RegisterX86::RSI.into()), ("rdi", RegisterX86::RDI.into()),
+            ("rbp", RegisterX86::RBP.into()), ("rsp", RegisterX86::RSP.into()),
+            ("r8",  RegisterX86::R8.into()),  ("r9",  RegisterX86::R9.into()),
+            ("r10", RegisterX86::R10.into()), ("r11", RegisterX86::R11.into()),
+            ("r12", RegisterX86::R12.into()), ("r13", RegisterX86::R13.into()),
+            ("r14", RegisterX86::R14.into()), ("r15", RegisterX86::R15.into()),
+            ("rip", RegisterX86::RIP.into()), ("eflags", RegisterX86::EFLAGS.into()),
+            ("cs",  RegisterX86::CS.into()),  ("ss",  RegisterX86::SS.into()),
+            ("ds",  RegisterX86::DS.into()),  ("es",  RegisterX86::ES.into()),
+            ("fs",  RegisterX86::FS.into()),  ("gs",  RegisterX86::GS.into()),
+            ("fs_base", RegisterX86::FS_BASE.into()), ("gs_base", RegisterX86::GS_BASE.into()),
+        ],
+        Arch::RISCV => vec![
+            ("ra", RegisterRISCV::RA.into()), ("sp", RegisterRISCV::SP.into()),
+            ("gp", RegisterRISCV::GP.into()), ("tp", RegisterRISCV::TP.into()),
+            ("t0", RegisterRISCV::T0.into()), ("t1", RegisterRISCV::T1.into()),
+            ("t2", RegisterRISCV::T2.into()), ("s0", RegisterRISCV::S0.into()),
+            ("s1", RegisterRISCV::S1.into()), ("a0", RegisterRISCV::A0.into()),
+            ("a1", RegisterRISCV::A1.into()), ("a2", RegisterRISCV::A2.into()),
+            ("a3", RegisterRISCV::A3.into()), ("a4", RegisterRISCV::A4.into()),
+            ("a5", RegisterRISCV::A5.into()), ("a6", RegisterRISCV::A6.into()),
+            ("a7", RegisterRISCV::A7.into()), ("s2", RegisterRISCV::S2.into()),
+            ("s3", RegisterRISCV::S3.into()), ("s4", RegisterRISCV::S4.into()),
+            ("s5", RegisterRISCV::S5.into()), ("s6", RegisterRISCV::S6.into()),
+            ("s7", RegisterRISCV::S7.into()), ("s8", RegisterRISCV::S8.into()),
+            ("s9", RegisterRISCV::S9.into()), ("s10", RegisterRISCV::S10.into()),
+            ("s11", RegisterRISCV::S11.into()), ("t3", RegisterRISCV::T3.into()),
+            ("t4", RegisterRISCV::T4.into()), ("t5", RegisterRISCV::T5.into()),
+            ("t6", RegisterRISCV::T6.into()), ("pc", RegisterRISCV::PC.into()),
+        ],
+        _ => Vec::new(),
+    };
+
+    regs.into_iter()
+        .map(|(name, reg)| (name, format!("{:#x}", unicorn.reg_read(reg).unwrap_or(0))))
+        .collect()
+}
+
+/// Find the heap allocation that `address` falls into. If it is not part of any allocation, the
+/// closest allocation is returned instead. Guard pages inserted by the allocator hooks are
+/// registered as allocations too, so allocations without permissions that were never free'd are
+/// skipped
+fn find_allocation(unicorn: &Unicorn<'static, ()>, exec_env: &ExecEnv, address: u64) 
+        -> Option<HeapInfo> {
+    let regions = unicorn.mem_regions().unwrap_or_default();
+    let is_guard = |base: u64| {
+        !exec_env.freed.contains_key(&base) && regions.iter()
+            .any(|r| base >= r.begin && base <= r.end && r.perms == Permission::NONE)
+    };
+
+    let distance = |base: u64, size: usize| {
+        if address < base {
+            base - address
+        } else {
+            address.saturating_sub(base + size as u64)
+        }
+    };
+
+    exec_env.heap_allocations.iter()
+        .filter(|(&base, _)| !is_guard(base))
+        .min_by_key(|(&base, &size)| distance(base, size))
+        .map(|(&base, &size)| HeapInfo {
+            base:   format!("{base:#x}"),
+            size,
+            offset: address.wrapping_sub(base) as i64,
+            state:  if exec_env.freed.contains_key(&base) { "freed" } else { "allocated" },
+        })
+}
+
+/// Disassemble the instruction at the start of `bytes` using capstone
+fn disassemble(unicorn: &Unicorn<'static, ()>, bytes: &[u8], pc: u64) -> Option<String> {
+    let cs = match (unicorn.get_arch(), unicorn.get_mode()) {
+        (Arch::X86, Mode::MODE_64) => {
+            Capstone::new().x86().mode(arch::x86::ArchMode::Mode64).build()
+        },
+        (Arch::X86, _) => Capstone::new().x86().mode(arch::x86::ArchMode::Mode32).build(),
+        (Arch::RISCV, _) => {
+            Capstone::new().riscv().mode(arch::riscv::ArchMode::RiscV64)
+                .extra_mode([arch::riscv::ArchExtraMode::RiscVC].iter().copied()).build()
+        },
+        _ => return None,
+    }.ok()?;
+
+    let insns = cs.disasm_count(bytes, pc, 1).ok()?;
+    let insn = insns.iter().next()?;
+    Some(format!("{} {}", insn.mnemonic()?, insn.op_str().unwrap_or("")).trim_end().to_string())
+}
+
+/// Run `f` with an instruction-counting hook attached to `unicorn`. Returns the result of `f` and
+/// the number of instructions that were executed while it ran
+pub fn with_instruction_count<T>(unicorn: &mut Unicorn<'static, ()>,
+                                 f: impl FnOnce(&mut Unicorn<'static, ()>) -> T) -> (T, u64) {
+    let count = Rc::new(Cell::new(0u64));
+    let count_clone = Rc::clone(&count);
+
+    let hook = unicorn.add_code_hook(1, 0, move |_uc: &mut Unicorn<'_, ()>, _addr, _size| {
+        count_clone.set(count_clone.get() + 1);
+    }).expect("Failed to insert instruction-counting hook");
+
+    let res = f(unicorn);
+
+    unicorn.remove_hook(hook).expect("Failed to remove instruction-counting hook");
+    (res, count.get())
+}
+
+/// Re-run the current fuzz-input from the snapshot with an instruction-counting hook attached and 
+/// return the number of executed instructions. This is too expensive to do for every fuzz-case, 
+/// so it is only done for unique crashes. The coverage-count of the original run is preserved so 
+/// the caller can still use it
+pub fn count_instructions(harness_init: &HarnessInit, target_shared: &TargetShared, 
+                          exec_env: &Rc<RefCell<ExecEnv>>, unicorn: &mut Unicorn<'static, ()>, 
+                          snapshot: &SnapshotContext) -> u64 {
+    let cov_count = exec_env.borrow().cov_count;
+
+    exec_env.borrow_mut().reset_snapshot(unicorn, snapshot).unwrap();
+    let (_, count) = with_instruction_count(unicorn, |unicorn| {
+        run_case(harness_init, target_shared, exec_env, unicorn)
+    });
+
+    exec_env.borrow_mut().cov_count = cov_count;
+    count
+}
+
+impl CrashInfo {
+    /// Collect information about a crash that just occured in `unicorn`
+    pub fn collect(unicorn: &Unicorn<'static, ()>, exec_env: &ExecEnv, err: uc_error, pc: u64)
+            -> Self {
+        let access_type = exec_env.fault.map(|fault| match fault.mem_type {
+            MemType::READ  | MemType::READ_UNMAPPED  | MemType::READ_PROT  |
+            MemType::READ_AFTER => "read",
+            MemType::WRITE | MemType::WRITE_UNMAPPED | MemType::WRITE_PROT => "write",
+            _ => "fetch",
+        }.to_string());
+
+        let heap_allocation = exec_env.fault
+            .and_then(|fault| find_allocation(unicorn, exec_env, fault.address));
+
+        let insn_bytes = unicorn.mem_read_as_vec(pc, INSN_BYTES_LEN).unwrap_or_default();
+        let disassembly = disassemble(unicorn, &insn_bytes, pc);
+        let instruction_bytes = insn_bytes
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        CrashInfo {
+            kind:          crash_kind(err),
+            error:         format!("{err:?}"),
+            bucket:        format!("{:x}", crash_bucket(err, pc, &exec_env.call_stack)),
+            pc:            format!("{pc:#x}"),
+            fault_address: exec_env.fault.map(|fault| format!("{:#x}", fault.address)),
+            access_type,
+            access_size:   exec_env.fault.map(|fault| fault.size),
+            heap_allocation,
+            instruction_bytes,
+            disassembly,
+            instructions_executed: 0,
+            backtrace:     stack_frames(pc, &exec_env.call_stack, usize::MAX).iter()
+                               .map(|frame| format!("{frame:#x}"))
+                               .collect(),
+            registers:     dump_registers(unicorn),
+        }
+    }
+
+    /// Write the crashing input and this crash-report to `out/<id>/crashes/<kind>_<bucket>/`
+    pub fn save(&self, target_id: usize, input: &[u8]) {
+        let output_dir = OUTPUT_DIR.get().unwrap();
+        let crash_dir  = format!("{output_dir}/{target_id}/crashes/{}_{}", self.kind, self.bucket);
+        std::fs::create_dir_all(&crash_dir).unwrap();
+
+        let h = Hash32::hash(input);
+        std::fs::write(format!("{crash_dir}/{h:x}"), input).unwrap();
+        std::fs::write(format!("{crash_dir}/metadata.json"),
+                       serde_json::to_vec_pretty(self).unwrap()).unwrap();
+    }
+}